@@ -27,8 +27,13 @@ fn post_upgrade() {
 // - "Test-CorruptChunkAtIndex"
 // - "Test-CorruptCertificateAtIndex"
 // - "Test-SwapChunkAtIndexWithNext"
+// Requests to "/whoami" are upgraded to an update call, which responds
+// with the principal of the caller.
 #[query]
 fn http_request(req: HttpRequest) -> HttpResponse {
+    if is_whoami_request(&req) {
+        return HttpResponse::builder().with_upgrade(true).build();
+    }
     let mut response = serve_asset(&req);
     if let Some(index) = chunk_corruption_requested(&req) {
         let current_chunk = current_chunk_index(&response);
@@ -101,6 +106,22 @@ fn http_request(req: HttpRequest) -> HttpResponse {
     response
 }
 
+#[update]
+fn http_request_update(req: HttpRequest) -> HttpResponse {
+    if !is_whoami_request(&req) {
+        ic_cdk::trap(&format!("Unexpected update call for request {:?}", req));
+    }
+    HttpResponse::ok(
+        caller().to_text().into_bytes(),
+        vec![("Content-Type".to_string(), "text/plain".to_string())],
+    )
+    .build()
+}
+
+fn is_whoami_request(req: &HttpRequest) -> bool {
+    req.get_path().is_ok_and(|path| path == "/whoami")
+}
+
 fn current_chunk_index(resp: &HttpResponse) -> usize {
     if let Some(content_range_header_value) = get_header_value(resp.headers(), "Content-Range") {
         get_content_range_begin(&content_range_header_value) / ASSET_CHUNK_SIZE
@@ -0,0 +1,227 @@
+use bytes::{Buf, Bytes, BytesMut};
+use h3::server::RequestResolver;
+use http_body_util::{BodyExt, Full};
+use hyper::{header::HeaderValue, Request, Response};
+use ic_http_gateway_protocol::HttpGatewayResponseBody;
+use quinn::crypto::rustls::{HandshakeData, QuicServerConfig};
+use rustls::ServerConfig;
+use std::{error::Error, future::Future, net::SocketAddr, sync::Arc};
+use tokio::task;
+
+pub static ALPN_H3: &[u8] = b"h3";
+
+type Http3Result<T = ()> = Result<T, Box<dyn Error + Send + Sync>>;
+
+/// The `Alt-Svc` header value advertising the HTTP/3 listener on the given port to HTTP/1.1 and HTTP/2 clients.
+pub fn alt_svc_header_value(port: u16) -> HeaderValue {
+    HeaderValue::from_str(&format!("h3=\":{}\"; ma=86400", port)).unwrap()
+}
+
+/// Binds a QUIC endpoint on the given UDP address, reusing the TLS configuration of the TCP listener.
+pub fn bind(addr: SocketAddr, mut tls_config: ServerConfig) -> Http3Result<quinn::Endpoint> {
+    tls_config.alpn_protocols = vec![ALPN_H3.to_vec()];
+
+    let quic_config = QuicServerConfig::try_from(tls_config)?;
+    let endpoint = quinn::Endpoint::server(
+        quinn::ServerConfig::with_crypto(Arc::new(quic_config)),
+        addr,
+    )?;
+
+    Ok(endpoint)
+}
+
+/// Accepts HTTP/3 connections until the endpoint is closed.
+///
+/// Each request is passed to `handler` along with the SNI hostname of its connection.
+/// Response bodies are streamed frame by frame, so streamed canister responses are
+/// forwarded as they are verified. Must be run within a [`task::LocalSet`].
+pub async fn serve<H, F>(endpoint: quinn::Endpoint, handler: H)
+where
+    H: Fn(Request<Full<Bytes>>, Option<String>) -> F + Clone + 'static,
+    F: Future<Output = Response<HttpGatewayResponseBody>> + 'static,
+{
+    while let Some(incoming) = endpoint.accept().await {
+        let handler = handler.clone();
+
+        task::spawn_local(async move {
+            if let Err(err) = serve_connection(incoming, handler).await {
+                eprintln!("Error serving HTTP/3 connection: {:?}", err);
+            }
+        });
+    }
+}
+
+async fn serve_connection<H, F>(incoming: quinn::Incoming, handler: H) -> Http3Result
+where
+    H: Fn(Request<Full<Bytes>>, Option<String>) -> F + Clone + 'static,
+    F: Future<Output = Response<HttpGatewayResponseBody>> + 'static,
+{
+    let connection = incoming.await?;
+    let sni = connection
+        .handshake_data()
+        .and_then(|handshake_data| handshake_data.downcast::<HandshakeData>().ok())
+        .and_then(|handshake_data| handshake_data.server_name);
+
+    let mut connection = h3::server::Connection::new(h3_quinn::Connection::new(connection)).await?;
+
+    while let Some(resolver) = connection.accept().await? {
+        let handler = handler.clone();
+        let sni = sni.clone();
+
+        task::spawn_local(async move {
+            if let Err(err) = serve_request(resolver, handler, sni).await {
+                eprintln!("Error serving HTTP/3 request: {:?}", err);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn serve_request<H, F>(
+    resolver: RequestResolver<h3_quinn::Connection, Bytes>,
+    handler: H,
+    sni: Option<String>,
+) -> Http3Result
+where
+    H: Fn(Request<Full<Bytes>>, Option<String>) -> F,
+    F: Future<Output = Response<HttpGatewayResponseBody>>,
+{
+    let (request, mut stream) = resolver.resolve_request().await?;
+
+    let mut request_body = BytesMut::new();
+    while let Some(mut chunk) = stream.recv_data().await? {
+        request_body.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+    }
+    let (parts, _) = request.into_parts();
+    let request = Request::from_parts(parts, Full::new(request_body.freeze()));
+
+    let (parts, mut response_body) = handler(request, sni).await.into_parts();
+    stream
+        .send_response(Response::from_parts(parts, ()))
+        .await?;
+
+    while let Some(frame) = response_body.frame().await {
+        match frame?.into_data() {
+            Ok(data) => stream.send_data(data).await?,
+            Err(frame) => {
+                if let Ok(trailers) = frame.into_trailers() {
+                    // sending trailers also finishes the stream
+                    stream.send_trailers(trailers).await?;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    stream.finish().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls::{tests::write_self_signed_cert, CertificateStore};
+    use futures::stream;
+    use http_body_util::StreamBody;
+    use hyper::{body::Frame, StatusCode};
+    use quinn::crypto::rustls::QuicClientConfig;
+    use rustls::pki_types::{pem::PemObject, CertificateDer};
+    use tempfile::TempDir;
+
+    fn client_endpoint(cert: CertificateDer<'static>) -> quinn::Endpoint {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert).unwrap();
+
+        let mut tls_config = rustls::ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::aws_lc_rs::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+        tls_config.alpn_protocols = vec![ALPN_H3.to_vec()];
+
+        let mut endpoint = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(
+            QuicClientConfig::try_from(tls_config).unwrap(),
+        )));
+
+        endpoint
+    }
+
+    async fn streamed_response(
+        request: Request<Full<Bytes>>,
+        sni: Option<String>,
+    ) -> Response<HttpGatewayResponseBody> {
+        let request_body = request.into_body().collect().await.unwrap().to_bytes();
+        let chunks = vec![
+            Ok(Frame::data(Bytes::from(sni.unwrap_or_default()))),
+            Ok(Frame::data(Bytes::from_static(b" "))),
+            Ok(Frame::data(request_body)),
+        ];
+        let body = StreamBody::new(Box::pin(stream::iter(chunks)) as _);
+
+        Response::new(HttpGatewayResponseBody::Left(body))
+    }
+
+    #[tokio::test]
+    async fn should_serve_streamed_responses_over_loopback_udp() {
+        let dir = TempDir::new().unwrap();
+        write_self_signed_cert(dir.path(), "a.example.com");
+        let cert = CertificateDer::from_pem_file(dir.path().join("a.example.com.pem")).unwrap();
+        let certificate_store = CertificateStore::load(dir.path()).unwrap();
+
+        let server = bind(
+            "127.0.0.1:0".parse().unwrap(),
+            certificate_store.server_config().unwrap(),
+        )
+        .unwrap();
+        let server_addr = server.local_addr().unwrap();
+
+        let local = task::LocalSet::new();
+        local.spawn_local(serve(server, streamed_response));
+
+        local
+            .run_until(async move {
+                let connection = client_endpoint(cert)
+                    .connect(server_addr, "a.example.com")
+                    .unwrap()
+                    .await
+                    .unwrap();
+                let (mut driver, mut send_request) =
+                    h3::client::new(h3_quinn::Connection::new(connection))
+                        .await
+                        .unwrap();
+                task::spawn_local(async move {
+                    std::future::poll_fn(|cx| driver.poll_close(cx)).await;
+                });
+
+                let mut stream = send_request
+                    .send_request(Request::post("https://a.example.com/").body(()).unwrap())
+                    .await
+                    .unwrap();
+                stream
+                    .send_data(Bytes::from_static(b"hello"))
+                    .await
+                    .unwrap();
+                stream.finish().await.unwrap();
+
+                let response = stream.recv_response().await.unwrap();
+                let mut response_body = BytesMut::new();
+                while let Some(mut chunk) = stream.recv_data().await.unwrap() {
+                    response_body.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+                }
+
+                assert_eq!(response.status(), StatusCode::OK);
+                assert_eq!(response_body.as_ref(), b"a.example.com hello");
+            })
+            .await;
+    }
+
+    #[test]
+    fn should_advertise_http3_port() {
+        assert_eq!(alt_svc_header_value(3000), "h3=\":3000\"; ma=86400");
+    }
+}
@@ -1,13 +1,33 @@
-use http_body_util::BodyExt;
-use hyper::{body::Incoming, server::conn::http2, service::service_fn, Request, Response};
-use hyper_util::rt::TokioIo;
-use ic_agent::Agent;
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    body::{Body, Incoming},
+    header::{self, HeaderValue},
+    service::service_fn,
+    Request, Response, StatusCode,
+};
+use hyper_util::{rt::TokioIo, server::conn::auto};
+use ic_agent::export::Principal;
 use ic_http_gateway_protocol::{
-    HttpGatewayClient, HttpGatewayRequestArgs, HttpGatewayResponseBody,
+    HttpGatewayClient, HttpGatewayRequestArgs, HttpGatewayResponseBody, RootKey,
 };
 use pocket_ic::PocketIcBuilder;
-use std::{convert::Infallible, net::SocketAddr, path::PathBuf, sync::Arc};
+use resolver::{host_matches_sni, HostnameResolver};
+use std::{
+    convert::Infallible, env, fmt::Debug, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration,
+};
+use tls::CertificateStore;
 use tokio::{fs::File, io::AsyncReadExt, net::TcpListener, task};
+use tokio_rustls::TlsAcceptor;
+
+#[cfg(feature = "http3")]
+mod http3;
+mod resolver;
+mod tls;
+
+static TLS_CERTS_DIR_ENV_VAR: &str = "HTTP_GATEWAY_TLS_CERTS_DIR";
+static DOMAINS_ENV_VAR: &str = "HTTP_GATEWAY_DOMAINS";
+const TLS_CERTS_RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub async fn load_custom_assets_wasm() -> Vec<u8> {
     load_wasm("http_gateway_canister_custom_assets").await
@@ -46,63 +66,224 @@ fn main() {
 
     let url = pic.auto_progress();
 
-    let agent = Agent::builder().with_url(url).build().unwrap();
-    rt.block_on(async {
-        agent.fetch_root_key().await.unwrap();
-    });
+    let http_gateway = Arc::new(rt.block_on(async {
+        HttpGatewayClient::builder()
+            .with_url(url)
+            .with_root_key(RootKey::FetchForLocalDev)
+            .build_and_check_root_key()
+            .await
+            .unwrap()
+    }));
 
-    let http_gateway = HttpGatewayClient::builder()
-        .with_agent(agent)
-        .build()
-        .unwrap();
+    let resolver = Arc::new(load_hostname_resolver(canister_id));
 
-    rt.block_on(async {
+    // the HTTP/3 listener runs on the same thread, since streamed response bodies are not `Send`
+    let local = task::LocalSet::new();
+    local.block_on(&rt, async {
         let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
+
+        // TLS is terminated by the gateway if a certificate directory is configured,
+        // otherwise requests are served over plain TCP.
+        let tls_config = env::var(TLS_CERTS_DIR_ENV_VAR).ok().map(|certs_dir| {
+            let certificate_store = CertificateStore::load(certs_dir).unwrap();
+            certificate_store.watch(TLS_CERTS_RELOAD_INTERVAL);
+            println!(
+                "Loaded TLS certificates: {:?}",
+                certificate_store.hostnames()
+            );
+
+            certificate_store.server_config().unwrap()
+        });
+
+        // HTTP/3 requires TLS, so it is only served if certificates are configured
+        #[cfg(feature = "http3")]
+        let alt_svc = tls_config.clone().map(|tls_config| {
+            let endpoint = http3::bind(addr, tls_config).unwrap();
+            let http_gateway = Arc::clone(&http_gateway);
+            let resolver = Arc::clone(&resolver);
+
+            task::spawn_local(http3::serve(endpoint, move |req, sni| {
+                let http_gateway = Arc::clone(&http_gateway);
+                let resolver = Arc::clone(&resolver);
+
+                async move { handle_request(req, &http_gateway, &resolver, sni.as_deref()).await }
+            }));
+            println!("Listening for HTTP/3 on: {}", addr);
+
+            http3::alt_svc_header_value(addr.port())
+        });
+        #[cfg(not(feature = "http3"))]
+        let alt_svc = None;
+
+        let tls_acceptor = tls_config.map(|tls_config| TlsAcceptor::from(Arc::new(tls_config)));
         let listener = TcpListener::bind(addr).await.unwrap();
 
         println!("Listening on: {}", addr);
 
         loop {
             let (stream, _) = listener.accept().await.unwrap();
-            let io = TokioIo::new(stream);
 
-            let http_gateway_clone = Arc::new(http_gateway.clone());
+            let http_gateway = Arc::clone(&http_gateway);
+            let resolver = Arc::clone(&resolver);
+            let tls_acceptor = tls_acceptor.clone();
+            let alt_svc = alt_svc.clone();
 
-            let service = service_fn(move |req: Request<Incoming>| {
-                let http_gateway_clone = Arc::clone(&http_gateway_clone);
-
-                async move {
-                    let canister_request = Request::builder().uri(req.uri()).method(req.method());
-                    let collected_req = req.collect().await.unwrap().to_bytes();
-                    let canister_request = canister_request.body(collected_req).unwrap();
+            // every connection is handled in its own task, so that a slow client cannot block the others
+            task::spawn_local(async move {
+                match tls_acceptor {
+                    Some(tls_acceptor) => {
+                        let stream = match tokio::time::timeout(
+                            TLS_HANDSHAKE_TIMEOUT,
+                            tls_acceptor.accept(stream),
+                        )
+                        .await
+                        {
+                            Ok(Ok(stream)) => stream,
+                            Ok(Err(err)) => {
+                                eprintln!("Error during TLS handshake: {:?}", err);
+                                return;
+                            }
+                            Err(_) => {
+                                eprintln!("TLS handshake timed out");
+                                return;
+                            }
+                        };
+                        let sni = stream.get_ref().1.server_name().map(str::to_string);
 
-                    let gateway_response = http_gateway_clone
-                        .request(HttpGatewayRequestArgs {
-                            canister_id,
-                            canister_request,
-                        })
-                        .send()
+                        serve_connection(
+                            TokioIo::new(stream),
+                            http_gateway,
+                            resolver,
+                            sni,
+                            alt_svc,
+                        )
                         .await;
-
-                    Ok::<Response<HttpGatewayResponseBody>, Infallible>(
-                        gateway_response.canister_response,
-                    )
+                    }
+                    None => {
+                        serve_connection(TokioIo::new(stream), http_gateway, resolver, None, None)
+                            .await;
+                    }
                 }
             });
+        }
+    });
+}
 
-            let local = task::LocalSet::new();
-            local
-                .run_until(async move {
-                    if let Err(err) = http2::Builder::new(LocalExec)
-                        .serve_connection(io, service)
-                        .await
-                    {
-                        eprintln!("Error serving connection: {:?}", err);
-                    }
-                })
-                .await;
+/// Custom domains are configured as a comma-separated list of `<domain>=<canister_id>` pairs,
+/// all other hostnames are served by the example canister.
+fn load_hostname_resolver(canister_id: Principal) -> HostnameResolver {
+    let mut resolver = HostnameResolver::new(Some(canister_id));
+
+    if let Ok(domains) = env::var(DOMAINS_ENV_VAR) {
+        for domain in domains.split(',').filter(|domain| !domain.is_empty()) {
+            let (domain, domain_canister_id) = domain
+                .split_once('=')
+                .unwrap_or_else(|| panic!("invalid domain mapping {:?}", domain));
+
+            resolver = resolver.with_domain(
+                domain.trim(),
+                Principal::from_text(domain_canister_id.trim()).unwrap(),
+            );
+        }
+    }
+
+    resolver
+}
+
+async fn serve_connection<I>(
+    io: I,
+    http_gateway: Arc<HttpGatewayClient>,
+    resolver: Arc<HostnameResolver>,
+    sni: Option<String>,
+    alt_svc: Option<HeaderValue>,
+) where
+    I: hyper::rt::Read + hyper::rt::Write + Unpin + 'static,
+{
+    let service = service_fn(move |req: Request<Incoming>| {
+        let http_gateway = Arc::clone(&http_gateway);
+        let resolver = Arc::clone(&resolver);
+        let sni = sni.clone();
+        let alt_svc = alt_svc.clone();
+
+        async move {
+            let mut response = handle_request(req, &http_gateway, &resolver, sni.as_deref()).await;
+            if let Some(alt_svc) = alt_svc {
+                response.headers_mut().insert(header::ALT_SVC, alt_svc);
+            }
+
+            Ok::<Response<HttpGatewayResponseBody>, Infallible>(response)
         }
     });
+
+    let local = task::LocalSet::new();
+    local
+        .run_until(async move {
+            // HTTP/1.1 and HTTP/2 are both supported, TLS connections negotiate the protocol via ALPN
+            if let Err(err) = auto::Builder::new(LocalExec)
+                .serve_connection(io, service)
+                .await
+            {
+                eprintln!("Error serving connection: {:?}", err);
+            }
+        })
+        .await;
+}
+
+async fn handle_request<B>(
+    req: Request<B>,
+    http_gateway: &HttpGatewayClient,
+    resolver: &HostnameResolver,
+    sni: Option<&str>,
+) -> Response<HttpGatewayResponseBody>
+where
+    B: Body,
+    B::Error: Debug,
+{
+    // HTTP/2 and HTTP/3 requests carry the host in the `:authority` pseudo-header, HTTP/1.1 in the `Host` header
+    let host = req
+        .uri()
+        .authority()
+        .map(|authority| authority.as_str().to_string())
+        .or_else(|| {
+            req.headers()
+                .get(header::HOST)
+                .and_then(|host| host.to_str().ok())
+                .map(str::to_string)
+        })
+        .unwrap_or_default();
+
+    if let Some(sni) = sni {
+        if !host_matches_sni(resolver, &host, sni) {
+            return create_err_response(
+                StatusCode::MISDIRECTED_REQUEST,
+                "Host does not match the TLS server name",
+            );
+        }
+    }
+
+    let Some(canister_id) = resolver.resolve(&host) else {
+        return create_err_response(StatusCode::NOT_FOUND, "Unknown domain");
+    };
+
+    let canister_request = Request::builder().uri(req.uri()).method(req.method());
+    let collected_req = req.collect().await.unwrap().to_bytes();
+    let canister_request = canister_request.body(collected_req).unwrap();
+
+    http_gateway
+        .request(HttpGatewayRequestArgs {
+            canister_id,
+            canister_request,
+        })
+        .send()
+        .await
+        .canister_response
+}
+
+fn create_err_response(status: StatusCode, msg: &'static str) -> Response<HttpGatewayResponseBody> {
+    let mut response = Response::new(HttpGatewayResponseBody::Right(Full::from(msg)));
+    *response.status_mut() = status;
+
+    response
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -0,0 +1,108 @@
+use crate::tls::strip_port;
+use ic_agent::export::Principal;
+use std::collections::HashMap;
+
+/// Resolves the hostname of an incoming request to the canister that serves it.
+#[derive(Debug, Clone, Default)]
+pub struct HostnameResolver {
+    domains: HashMap<String, Principal>,
+    default_canister_id: Option<Principal>,
+}
+
+impl HostnameResolver {
+    pub fn new(default_canister_id: Option<Principal>) -> Self {
+        Self {
+            domains: HashMap::new(),
+            default_canister_id,
+        }
+    }
+
+    /// Maps a custom domain to a canister.
+    pub fn with_domain(mut self, domain: &str, canister_id: Principal) -> Self {
+        self.domains.insert(normalize_hostname(domain), canister_id);
+
+        self
+    }
+
+    /// Resolves a hostname, optionally including a port, to a canister id.
+    ///
+    /// Custom domains take precedence, followed by `<canister_id>.<domain>` subdomains.
+    /// The default canister is used if neither matches.
+    pub fn resolve(&self, host: &str) -> Option<Principal> {
+        let hostname = normalize_hostname(host);
+
+        if let Some(canister_id) = self.domains.get(&hostname) {
+            return Some(*canister_id);
+        }
+
+        hostname
+            .split_once('.')
+            .and_then(|(subdomain, _)| Principal::from_text(subdomain).ok())
+            .or(self.default_canister_id)
+    }
+}
+
+/// Checks that the `Host` of a request matches the SNI hostname the TLS connection was established for.
+///
+/// Without this check, a client could establish a connection for one domain
+/// and then use it to send requests to a different domain.
+pub fn host_matches_sni(resolver: &HostnameResolver, host: &str, sni: &str) -> bool {
+    let host = normalize_hostname(host);
+    let sni = normalize_hostname(sni);
+
+    host == sni && resolver.resolve(&host).is_some()
+}
+
+fn normalize_hostname(host: &str) -> String {
+    strip_port(host).trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CANISTER_ID: &str = "qoctq-giaaa-aaaaa-aaaea-cai";
+    const OTHER_CANISTER_ID: &str = "rrkah-fqaaa-aaaaa-aaaaq-cai";
+
+    #[test]
+    fn should_resolve_hostnames() {
+        let canister_id = Principal::from_text(CANISTER_ID).unwrap();
+        let other_canister_id = Principal::from_text(OTHER_CANISTER_ID).unwrap();
+        let resolver = HostnameResolver::new(None).with_domain("Example.com", canister_id);
+
+        assert_eq!(resolver.resolve("example.com"), Some(canister_id));
+        assert_eq!(resolver.resolve("example.com:443"), Some(canister_id));
+        assert_eq!(
+            resolver.resolve(&format!("{OTHER_CANISTER_ID}.localhost:3000")),
+            Some(other_canister_id)
+        );
+        assert_eq!(resolver.resolve("unknown.com"), None);
+
+        let resolver = HostnameResolver::new(Some(other_canister_id));
+        assert_eq!(resolver.resolve("unknown.com"), Some(other_canister_id));
+    }
+
+    #[test]
+    fn should_reject_sni_host_mismatch() {
+        let canister_id = Principal::from_text(CANISTER_ID).unwrap();
+        let resolver = HostnameResolver::new(None)
+            .with_domain("a.example.com", canister_id)
+            .with_domain("b.example.com", canister_id);
+
+        assert!(host_matches_sni(
+            &resolver,
+            "a.example.com:443",
+            "A.example.com"
+        ));
+        assert!(!host_matches_sni(
+            &resolver,
+            "b.example.com",
+            "a.example.com"
+        ));
+        assert!(!host_matches_sni(
+            &resolver,
+            "c.example.com",
+            "c.example.com"
+        ));
+    }
+}
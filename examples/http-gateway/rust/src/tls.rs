@@ -0,0 +1,342 @@
+use rustls::{
+    crypto::CryptoProvider,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    fs,
+    hash::{DefaultHasher, Hash, Hasher},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+static CERTIFICATE_FILE_EXTENSION: &str = "pem";
+static PRIVATE_KEY_FILE_EXTENSION: &str = "key";
+
+pub static ALPN_H2: &[u8] = b"h2";
+pub static ALPN_HTTP1_1: &[u8] = b"http/1.1";
+
+pub type TlsResult<T = ()> = Result<T, TlsError>;
+
+#[derive(Debug)]
+pub enum TlsError {
+    Io(PathBuf, std::io::Error),
+    Pem(PathBuf, rustls::pki_types::pem::Error),
+    Rustls(PathBuf, rustls::Error),
+    MissingPrivateKey(PathBuf),
+    NoCertificates(PathBuf),
+}
+
+impl std::fmt::Display for TlsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TlsError::Io(path, e) => write!(f, "failed to read {:?}: {}", path, e),
+            TlsError::Pem(path, e) => write!(f, "failed to parse PEM file {:?}: {}", path, e),
+            TlsError::Rustls(path, e) => write!(f, "invalid key pair {:?}: {}", path, e),
+            TlsError::MissingPrivateKey(path) => {
+                write!(f, "no private key found for certificate {:?}", path)
+            }
+            TlsError::NoCertificates(path) => {
+                write!(f, "no certificates found in directory {:?}", path)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+/// Certificates loaded from a directory, selected by the SNI hostname of the client.
+///
+/// Each certificate chain is read from `<hostname>.pem` and its private key from `<hostname>.key`.
+/// Wildcard certificates use the `*.` prefix in the file name, e.g. `*.example.com.pem`.
+pub struct CertificateStore {
+    certs_dir: PathBuf,
+    provider: Arc<CryptoProvider>,
+    certificates: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+    fingerprint: RwLock<u64>,
+}
+
+impl Debug for CertificateStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CertificateStore")
+            .field("certs_dir", &self.certs_dir)
+            .field("hostnames", &self.hostnames())
+            .finish()
+    }
+}
+
+impl CertificateStore {
+    pub fn load(certs_dir: impl Into<PathBuf>) -> TlsResult<Arc<Self>> {
+        let store = Self {
+            certs_dir: certs_dir.into(),
+            provider: Arc::new(rustls::crypto::aws_lc_rs::default_provider()),
+            certificates: RwLock::new(HashMap::new()),
+            fingerprint: RwLock::new(0),
+        };
+        store.reload()?;
+
+        Ok(Arc::new(store))
+    }
+
+    pub fn hostnames(&self) -> Vec<String> {
+        let mut hostnames = self
+            .certificates
+            .read()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        hostnames.sort();
+
+        hostnames
+    }
+
+    /// Reloads all certificates from disk.
+    /// If loading fails, the previously loaded certificates are kept.
+    pub fn reload(&self) -> TlsResult {
+        let certificates = load_certificates(&self.certs_dir, &self.provider)?;
+        if certificates.is_empty() {
+            return Err(TlsError::NoCertificates(self.certs_dir.clone()));
+        }
+
+        *self.certificates.write().unwrap() = certificates;
+        *self.fingerprint.write().unwrap() = fingerprint(&self.certs_dir)?;
+
+        Ok(())
+    }
+
+    /// Reloads all certificates if any file in the certificate directory was added, removed or changed
+    /// since the last load. Returns `true` if the certificates were reloaded.
+    pub fn reload_if_changed(&self) -> TlsResult<bool> {
+        if fingerprint(&self.certs_dir)? == *self.fingerprint.read().unwrap() {
+            return Ok(false);
+        }
+
+        self.reload()?;
+
+        Ok(true)
+    }
+
+    /// Periodically checks the certificate directory for changes and hot-reloads the certificates.
+    pub fn watch(self: &Arc<Self>, interval: Duration) -> tokio::task::JoinHandle<()> {
+        let store = Arc::clone(self);
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+
+                match store.reload_if_changed() {
+                    Ok(true) => println!("Reloaded TLS certificates: {:?}", store.hostnames()),
+                    Ok(false) => {}
+                    Err(e) => eprintln!("Failed to reload TLS certificates: {}", e),
+                }
+            }
+        })
+    }
+
+    pub fn server_config(self: &Arc<Self>) -> TlsResult<ServerConfig> {
+        let mut config = ServerConfig::builder_with_provider(Arc::clone(&self.provider))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| TlsError::Rustls(self.certs_dir.clone(), e))?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::clone(self) as Arc<dyn ResolvesServerCert>);
+        config.alpn_protocols = vec![ALPN_H2.to_vec(), ALPN_HTTP1_1.to_vec()];
+
+        Ok(config)
+    }
+
+    fn find(&self, hostname: &str) -> Option<Arc<CertifiedKey>> {
+        let hostname = hostname.trim_end_matches('.').to_ascii_lowercase();
+        let certificates = self.certificates.read().unwrap();
+
+        if let Some(cert) = certificates.get(&hostname) {
+            return Some(Arc::clone(cert));
+        }
+
+        let (_, parent) = hostname.split_once('.')?;
+        certificates.get(&format!("*.{}", parent)).cloned()
+    }
+}
+
+impl ResolvesServerCert for CertificateStore {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        // clients that do not send SNI cannot be served, since every domain has its own certificate
+        self.find(client_hello.server_name()?)
+    }
+}
+
+fn load_certificates(
+    certs_dir: &Path,
+    provider: &CryptoProvider,
+) -> TlsResult<HashMap<String, Arc<CertifiedKey>>> {
+    let mut certificates = HashMap::new();
+
+    for entry in fs::read_dir(certs_dir).map_err(|e| TlsError::Io(certs_dir.to_path_buf(), e))? {
+        let cert_path = entry
+            .map_err(|e| TlsError::Io(certs_dir.to_path_buf(), e))?
+            .path();
+        if cert_path.extension().and_then(|ext| ext.to_str()) != Some(CERTIFICATE_FILE_EXTENSION) {
+            continue;
+        }
+        let Some(hostname) = cert_path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+
+        let key_path = cert_path.with_extension(PRIVATE_KEY_FILE_EXTENSION);
+        if !key_path.exists() {
+            return Err(TlsError::MissingPrivateKey(cert_path));
+        }
+
+        let cert_chain = CertificateDer::pem_file_iter(&cert_path)
+            .map_err(|e| TlsError::Pem(cert_path.clone(), e))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| TlsError::Pem(cert_path.clone(), e))?;
+        let key =
+            PrivateKeyDer::from_pem_file(&key_path).map_err(|e| TlsError::Pem(key_path, e))?;
+        let certified_key = CertifiedKey::from_der(cert_chain, key, provider)
+            .map_err(|e| TlsError::Rustls(cert_path.clone(), e))?;
+
+        certificates.insert(hostname.to_ascii_lowercase(), Arc::new(certified_key));
+    }
+
+    Ok(certificates)
+}
+
+/// Hashes the names and modification times of the files in the certificate directory,
+/// so that removed files are noticed as well as added and modified ones.
+fn fingerprint(certs_dir: &Path) -> TlsResult<u64> {
+    let mut files = vec![];
+
+    for entry in fs::read_dir(certs_dir).map_err(|e| TlsError::Io(certs_dir.to_path_buf(), e))? {
+        let file = entry
+            .and_then(|entry| Ok((entry.file_name(), entry.metadata()?.modified()?)))
+            .map_err(|e| TlsError::Io(certs_dir.to_path_buf(), e))?;
+
+        files.push(file);
+    }
+    files.sort();
+
+    let mut hasher = DefaultHasher::new();
+    files.hash(&mut hasher);
+
+    Ok(hasher.finish())
+}
+
+/// Strips the port from a `Host` header value or URI authority.
+pub fn strip_port(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        // IPv6 literals contain colons, so a port can only follow a bracketed literal
+        // or a hostname without any other colon
+        Some((hostname, port))
+            if (hostname.ends_with(']') || !hostname.contains(':'))
+                && port.chars().all(|c| c.is_ascii_digit()) =>
+        {
+            hostname
+        }
+        _ => host,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    pub(crate) fn write_self_signed_cert(dir: &Path, hostname: &str) {
+        let rcgen::CertifiedKey { cert, key_pair } =
+            rcgen::generate_simple_self_signed(vec![hostname.to_string()]).unwrap();
+
+        fs::write(dir.join(format!("{hostname}.pem")), cert.pem()).unwrap();
+        fs::write(
+            dir.join(format!("{hostname}.key")),
+            key_pair.serialize_pem(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn should_select_certificate_by_hostname() {
+        let dir = TempDir::new().unwrap();
+        write_self_signed_cert(dir.path(), "a.example.com");
+        write_self_signed_cert(dir.path(), "*.example.org");
+
+        let store = CertificateStore::load(dir.path()).unwrap();
+
+        assert_eq!(store.hostnames(), vec!["*.example.org", "a.example.com"]);
+        assert!(store.find("a.example.com").is_some());
+        assert!(store.find("A.Example.Com.").is_some());
+        assert!(store.find("foo.example.org").is_some());
+        assert!(store.find("b.example.com").is_none());
+        assert!(store.find("foo.bar.example.org").is_none());
+    }
+
+    #[test]
+    fn should_reload_changed_certificates() {
+        let dir = TempDir::new().unwrap();
+        write_self_signed_cert(dir.path(), "a.example.com");
+
+        let store = CertificateStore::load(dir.path()).unwrap();
+        assert!(!store.reload_if_changed().unwrap());
+
+        // make sure the modification time of the directory entries changes
+        std::thread::sleep(Duration::from_millis(10));
+        write_self_signed_cert(dir.path(), "b.example.com");
+
+        assert!(store.reload_if_changed().unwrap());
+        assert_eq!(store.hostnames(), vec!["a.example.com", "b.example.com"]);
+    }
+
+    #[test]
+    fn should_reload_removed_certificates() {
+        let dir = TempDir::new().unwrap();
+        write_self_signed_cert(dir.path(), "a.example.com");
+        write_self_signed_cert(dir.path(), "b.example.com");
+
+        let store = CertificateStore::load(dir.path()).unwrap();
+        fs::remove_file(dir.path().join("b.example.com.pem")).unwrap();
+        fs::remove_file(dir.path().join("b.example.com.key")).unwrap();
+
+        assert!(store.reload_if_changed().unwrap());
+        assert_eq!(store.hostnames(), vec!["a.example.com"]);
+    }
+
+    #[test]
+    fn should_keep_certificates_when_reload_fails() {
+        let dir = TempDir::new().unwrap();
+        write_self_signed_cert(dir.path(), "a.example.com");
+
+        let store = CertificateStore::load(dir.path()).unwrap();
+        fs::write(dir.path().join("b.example.com.pem"), "not a certificate").unwrap();
+
+        assert!(store.reload().is_err());
+        assert_eq!(store.hostnames(), vec!["a.example.com"]);
+    }
+
+    #[test]
+    fn should_fail_without_private_key() {
+        let dir = TempDir::new().unwrap();
+        write_self_signed_cert(dir.path(), "a.example.com");
+        fs::remove_file(dir.path().join("a.example.com.key")).unwrap();
+
+        assert!(matches!(
+            CertificateStore::load(dir.path()),
+            Err(TlsError::MissingPrivateKey(_))
+        ));
+    }
+
+    #[test]
+    fn should_strip_port() {
+        assert_eq!(strip_port("example.com:443"), "example.com");
+        assert_eq!(strip_port("example.com"), "example.com");
+        assert_eq!(strip_port("[::1]:8080"), "[::1]");
+        assert_eq!(strip_port("[::1]"), "[::1]");
+        assert_eq!(strip_port("::1"), "::1");
+        assert_eq!(strip_port("fe80::1:443"), "fe80::1:443");
+    }
+}
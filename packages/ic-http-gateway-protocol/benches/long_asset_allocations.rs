@@ -0,0 +1,172 @@
+//! Counts the heap allocations made by the gateway while it streams and verifies long assets.
+//!
+//! Run with `cargo bench --bench long_asset_allocations`.
+//! Like the integration tests, this requires PocketIC and the custom assets canister to be built.
+
+use bytes::Bytes;
+use http::Request;
+use http_body_util::BodyExt;
+use ic_http_gateway_protocol::{HttpGatewayClient, HttpGatewayRequestArgs, RootKey};
+use pocket_ic::PocketIcBuilder;
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+#[path = "../tests/utils/mod.rs"]
+mod utils;
+
+const ITERATIONS: usize = 5;
+
+const ASSETS: [(&str, usize); 4] = [
+    ("long_asset_one_chunk", 1),
+    ("long_asset_two_chunks", 2),
+    ("long_asset_six_chunks", 6),
+    ("long_asset_ten_chunks", 10),
+];
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+static LIVE_BYTES: AtomicUsize = AtomicUsize::new(0);
+static PEAK_LIVE_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+struct CountingAllocator;
+
+impl CountingAllocator {
+    fn record_allocation(size: usize) {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(size, Ordering::Relaxed);
+        let live_bytes = LIVE_BYTES.fetch_add(size, Ordering::Relaxed) + size;
+        PEAK_LIVE_BYTES.fetch_max(live_bytes, Ordering::Relaxed);
+    }
+
+    fn record_deallocation(size: usize) {
+        LIVE_BYTES.fetch_sub(size, Ordering::Relaxed);
+    }
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        Self::record_allocation(layout.size());
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        Self::record_allocation(layout.size());
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        Self::record_deallocation(layout.size());
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        Self::record_deallocation(layout.size());
+        Self::record_allocation(new_size);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+struct AllocationStats {
+    allocations: usize,
+    allocated_bytes: usize,
+    peak_live_bytes: usize,
+}
+
+fn reset_allocation_stats() {
+    ALLOCATIONS.store(0, Ordering::Relaxed);
+    ALLOCATED_BYTES.store(0, Ordering::Relaxed);
+    PEAK_LIVE_BYTES.store(LIVE_BYTES.load(Ordering::Relaxed), Ordering::Relaxed);
+}
+
+fn allocation_stats(baseline_live_bytes: usize) -> AllocationStats {
+    AllocationStats {
+        allocations: ALLOCATIONS.load(Ordering::Relaxed),
+        allocated_bytes: ALLOCATED_BYTES.load(Ordering::Relaxed),
+        peak_live_bytes: PEAK_LIVE_BYTES
+            .load(Ordering::Relaxed)
+            .saturating_sub(baseline_live_bytes),
+    }
+}
+
+async fn fetch_asset(
+    http_gateway: &HttpGatewayClient,
+    canister_id: candid::Principal,
+    asset_name: &str,
+) -> usize {
+    let response = http_gateway
+        .request(HttpGatewayRequestArgs {
+            canister_id,
+            canister_request: Request::builder()
+                .uri(format!("/{asset_name}"))
+                .body(Bytes::new())
+                .unwrap(),
+        })
+        .send()
+        .await;
+
+    response
+        .canister_response
+        .into_body()
+        .collect()
+        .await
+        .unwrap()
+        .to_bytes()
+        .len()
+}
+
+fn main() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let wasm_bytes = rt.block_on(async { utils::load_custom_assets_wasm().await });
+
+    let pic = PocketIcBuilder::new()
+        .with_nns_subnet()
+        .with_application_subnet()
+        .build();
+
+    let canister_id = pic.create_canister();
+    pic.add_cycles(canister_id, 2_000_000_000_000_000);
+    pic.install_canister(canister_id, wasm_bytes, vec![], None);
+
+    let url = pic.auto_progress();
+
+    let http_gateway = rt.block_on(async {
+        HttpGatewayClient::builder()
+            .with_url(url)
+            .with_root_key(RootKey::FetchForLocalDev)
+            .build_and_check_root_key()
+            .await
+            .unwrap()
+    });
+
+    println!(
+        "{:<24} {:>8} {:>12} {:>16} {:>20} {:>16}",
+        "asset", "chunks", "body bytes", "allocations", "allocated bytes", "peak bytes"
+    );
+    for (asset_name, chunk_count) in ASSETS {
+        // warm up the connection pool and the verification cache
+        rt.block_on(fetch_asset(&http_gateway, canister_id, asset_name));
+
+        let baseline_live_bytes = LIVE_BYTES.load(Ordering::Relaxed);
+        reset_allocation_stats();
+        let mut body_length = 0;
+        for _ in 0..ITERATIONS {
+            body_length = rt.block_on(fetch_asset(&http_gateway, canister_id, asset_name));
+        }
+        let stats = allocation_stats(baseline_live_bytes);
+
+        println!(
+            "{:<24} {:>8} {:>12} {:>16} {:>20} {:>16}",
+            asset_name,
+            chunk_count,
+            body_length,
+            stats.allocations / ITERATIONS,
+            stats.allocated_bytes / ITERATIONS,
+            stats.peak_live_bytes,
+        );
+    }
+}
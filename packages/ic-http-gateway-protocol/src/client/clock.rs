@@ -0,0 +1,16 @@
+use std::{fmt::Debug, time::SystemTime};
+
+/// A source of the current time, used to check the freshness of certificates.
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// The system clock of the host.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
@@ -0,0 +1,607 @@
+use crate::{
+    CanisterRequest, Clock, HttpGatewayError, HttpGatewayResponse, HttpGatewayResponseBody,
+    HttpGatewayResponseMetadata, HttpGatewayResult,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use candid::Principal;
+use http::{header, HeaderValue, Response, StatusCode};
+use http_body_util::Full;
+use ic_agent::{
+    identity::{DelegatedIdentity, Delegation, DelegationError, SignedDelegation},
+    Identity,
+};
+use serde::Deserialize;
+use std::{
+    collections::HashSet,
+    fmt::{Debug, Formatter},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::UNIX_EPOCH,
+};
+
+/// The scheme of `Authorization` headers that carry a delegation chain.
+const AUTHORIZATION_SCHEME: &str = "Delegation";
+
+/// The header in which browsers tell where a request was initiated, relative to its target.
+const SEC_FETCH_SITE: &str = "sec-fetch-site";
+
+/// The DER encoded algorithm identifier of canister signature public keys, as used by Internet Identity.
+const CANISTER_SIGNATURE_ALGORITHM: [u8; 14] = [
+    0x30, 0x0c, 0x06, 0x0a, 0x2b, 0x06, 0x01, 0x04, 0x01, 0x83, 0xb8, 0x43, 0x01, 0x02,
+];
+
+/// Where a [DelegationAuthenticator] reads the delegation chain of a request from.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum DelegationSource {
+    /// An `Authorization: Delegation <chain>` header.
+    #[default]
+    AuthorizationHeader,
+
+    /// The cookie with the given name.
+    ///
+    /// Browsers send cookies with cross-site requests too, so to protect against cross-site request forgery,
+    /// the cookie is only used for requests from the same origin or from an allowed origin,
+    /// see [DelegationAuthenticator::with_allowed_origins]. It is removed from other requests, which are
+    /// sent with the identity of the agent.
+    Cookie(String),
+}
+
+/// Calls canisters on behalf of the users that delegated to the gateway's session key.
+///
+/// Requests carry a delegation chain from the user's key to the session key, in the JSON format of
+/// agent-js' `DelegationChain.toJSON()`, encoded with unpadded URL-safe base64. The chain is validated
+/// and removed from the request, which is then sent with a per-request [DelegatedIdentity], so the
+/// canister sees the user's principal as the caller. Requests without a delegation chain are sent
+/// with the identity of the agent.
+///
+/// Chains starting at a canister signature key, like those issued by Internet Identity, cannot be
+/// verified by the gateway. The signatures of the rest of the chain are verified, and the canister
+/// signature is left to the Internet Computer, which rejects calls with invalid delegations.
+pub struct DelegationAuthenticator {
+    session_identity: Arc<dyn Identity>,
+    source: DelegationSource,
+    allowed_origins: HashSet<String>,
+    delegated_requests: AtomicU64,
+    rejected_delegations: AtomicU64,
+}
+
+impl Debug for DelegationAuthenticator {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DelegationAuthenticator")
+            .field("session_principal", &self.session_identity.sender().ok())
+            .field("source", &self.source)
+            .field("allowed_origins", &self.allowed_origins)
+            .field("delegated_requests", &self.delegated_requests())
+            .field("rejected_delegations", &self.rejected_delegations())
+            .finish()
+    }
+}
+
+impl DelegationAuthenticator {
+    /// `session_identity` holds the session key that users delegate to.
+    pub fn new(session_identity: Arc<dyn Identity>) -> Self {
+        Self {
+            session_identity,
+            source: DelegationSource::default(),
+            allowed_origins: HashSet::new(),
+            delegated_requests: AtomicU64::new(0),
+            rejected_delegations: AtomicU64::new(0),
+        }
+    }
+
+    /// Where delegation chains are read from, defaults to the `Authorization` header.
+    pub fn with_source(mut self, source: DelegationSource) -> Self {
+        self.source = source;
+
+        self
+    }
+
+    /// The origins, e.g. `https://app.example.com`, whose cross-origin requests may be authenticated
+    /// with a [DelegationSource::Cookie], in addition to same-origin requests.
+    pub fn with_allowed_origins(
+        mut self,
+        allowed_origins: impl IntoIterator<Item = String>,
+    ) -> Self {
+        self.allowed_origins = allowed_origins
+            .into_iter()
+            .map(|origin| origin.to_ascii_lowercase())
+            .collect();
+
+        self
+    }
+
+    /// The DER encoded session public key that users delegate to.
+    pub fn session_public_key(&self) -> Option<Vec<u8>> {
+        self.session_identity.public_key()
+    }
+
+    /// The number of requests that were sent on behalf of a user.
+    pub fn delegated_requests(&self) -> u64 {
+        self.delegated_requests.load(Ordering::Relaxed)
+    }
+
+    /// The number of requests that were refused because their delegation chain was invalid.
+    pub fn rejected_delegations(&self) -> u64 {
+        self.rejected_delegations.load(Ordering::Relaxed)
+    }
+
+    /// Removes the delegation chain from the request and validates it for calls to the canister.
+    /// Returns `None` if the request does not carry a delegation chain.
+    pub(crate) fn authenticate(
+        &self,
+        canister_id: Principal,
+        request: &mut CanisterRequest,
+        clock: &Arc<dyn Clock>,
+    ) -> HttpGatewayResult<Option<DelegatedIdentity>> {
+        let Some(credential) = self.take_credential(request) else {
+            return Ok(None);
+        };
+
+        let delegated_identity = self.validate(&credential, canister_id, clock);
+        match delegated_identity {
+            Ok(_) => self.delegated_requests.fetch_add(1, Ordering::Relaxed),
+            Err(_) => self.rejected_delegations.fetch_add(1, Ordering::Relaxed),
+        };
+
+        delegated_identity
+            .map(Some)
+            .map_err(|reason| HttpGatewayError::InvalidDelegation { reason })
+    }
+
+    fn take_credential(&self, request: &mut CanisterRequest) -> Option<String> {
+        match &self.source {
+            DelegationSource::AuthorizationHeader => {
+                let credential = request
+                    .headers()
+                    .get(header::AUTHORIZATION)?
+                    .to_str()
+                    .ok()?
+                    .split_once(' ')
+                    .filter(|(scheme, _)| scheme.eq_ignore_ascii_case(AUTHORIZATION_SCHEME))?
+                    .1
+                    .trim()
+                    .to_string();
+                request.headers_mut().remove(header::AUTHORIZATION);
+
+                Some(credential)
+            }
+            DelegationSource::Cookie(name) => {
+                let mut credential = None;
+                let mut cookies = Vec::new();
+                for value in request.headers().get_all(header::COOKIE) {
+                    for cookie in value.to_str().ok()?.split(';').map(str::trim) {
+                        match cookie.split_once('=') {
+                            Some((cookie_name, value)) if cookie_name == name => {
+                                credential = Some(value.trim_matches('"').to_string());
+                            }
+                            _ if cookie.is_empty() => {}
+                            _ => cookies.push(cookie),
+                        }
+                    }
+                }
+                let credential = credential?;
+                let is_allowed_origin = self.is_allowed_origin(request);
+
+                // the remaining cookies are forwarded to the canister in a single header
+                let cookies = HeaderValue::from_str(&cookies.join("; ")).ok();
+                let headers = request.headers_mut();
+                headers.remove(header::COOKIE);
+                if let Some(cookies) = cookies.filter(|cookies| !cookies.is_empty()) {
+                    headers.insert(header::COOKIE, cookies);
+                }
+
+                is_allowed_origin.then_some(credential)
+            }
+        }
+    }
+
+    /// Whether the request comes from the same origin or from an allowed origin, according to
+    /// its `Sec-Fetch-Site` and `Origin` headers. Requests without either header are not trusted.
+    fn is_allowed_origin(&self, request: &CanisterRequest) -> bool {
+        let headers = request.headers();
+        let origin = headers
+            .get(header::ORIGIN)
+            .and_then(|origin| origin.to_str().ok())
+            .map(str::to_ascii_lowercase);
+        if origin
+            .as_ref()
+            .is_some_and(|origin| self.allowed_origins.contains(origin))
+        {
+            return true;
+        }
+
+        if let Some(fetch_site) = headers.get(SEC_FETCH_SITE) {
+            // `none` is sent for navigations that the user started, e.g. from the address bar
+            return fetch_site == "same-origin" || fetch_site == "none";
+        }
+
+        let host = request
+            .uri()
+            .authority()
+            .map(|authority| authority.as_str())
+            .or_else(|| headers.get(header::HOST)?.to_str().ok());
+        match (origin, host) {
+            (Some(origin), Some(host)) => origin
+                .split_once("://")
+                .is_some_and(|(_, origin_host)| origin_host.eq_ignore_ascii_case(host)),
+            _ => false,
+        }
+    }
+
+    fn validate(
+        &self,
+        credential: &str,
+        canister_id: Principal,
+        clock: &Arc<dyn Clock>,
+    ) -> Result<DelegatedIdentity, String> {
+        let (from_key, chain) = parse_delegation_chain(credential)?;
+        if chain.is_empty() {
+            return Err("the delegation chain is empty".to_string());
+        }
+
+        let now_ns = clock
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| e.to_string())?
+            .as_nanos();
+        for signed_delegation in &chain {
+            let delegation = &signed_delegation.delegation;
+            if u128::from(delegation.expiration) <= now_ns {
+                return Err("the delegation chain has expired".to_string());
+            }
+            if delegation
+                .targets
+                .as_ref()
+                .is_some_and(|targets| !targets.contains(&canister_id))
+            {
+                return Err(format!(
+                    "the delegation chain does not target canister {canister_id}"
+                ));
+            }
+        }
+
+        match DelegatedIdentity::new(from_key.clone(), self.session_identity(), chain.clone()) {
+            Ok(delegated_identity) => Ok(delegated_identity),
+            Err(DelegationError::UnknownAlgorithm)
+                if from_key.get(2..16) == Some(&CANISTER_SIGNATURE_ALGORITHM[..]) =>
+            {
+                DelegatedIdentity::new(
+                    chain[0].delegation.pubkey.clone(),
+                    self.session_identity(),
+                    chain[1..].to_vec(),
+                )
+                .map_err(|e| e.to_string())?;
+
+                Ok(DelegatedIdentity::new_unchecked(
+                    from_key,
+                    self.session_identity(),
+                    chain,
+                ))
+            }
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    fn session_identity(&self) -> Box<dyn Identity> {
+        Box::new(self.session_identity.clone())
+    }
+}
+
+/// Creates the `401 Unauthorized` response to a request with an invalid delegation chain.
+pub(crate) fn create_invalid_delegation_response(error: HttpGatewayError) -> HttpGatewayResponse {
+    let mut canister_response = Response::new(HttpGatewayResponseBody::Right(Full::from(
+        error.to_string().into_bytes(),
+    )));
+    *canister_response.status_mut() = StatusCode::UNAUTHORIZED;
+    canister_response.headers_mut().insert(
+        header::WWW_AUTHENTICATE,
+        HeaderValue::from_static(AUTHORIZATION_SCHEME),
+    );
+
+    HttpGatewayResponse {
+        canister_response,
+        metadata: HttpGatewayResponseMetadata {
+            internal_error: Some(error),
+            ..Default::default()
+        },
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DelegationChainJson {
+    delegations: Vec<SignedDelegationJson>,
+    public_key: String,
+}
+
+#[derive(Deserialize)]
+struct SignedDelegationJson {
+    delegation: DelegationJson,
+    signature: String,
+}
+
+#[derive(Deserialize)]
+struct DelegationJson {
+    pubkey: String,
+    expiration: String,
+    targets: Option<Vec<String>>,
+}
+
+fn parse_delegation_chain(credential: &str) -> Result<(Vec<u8>, Vec<SignedDelegation>), String> {
+    let json = URL_SAFE_NO_PAD
+        .decode(credential.trim_end_matches('='))
+        .map_err(|e| format!("the delegation chain is not valid base64: {e}"))?;
+    let chain: DelegationChainJson = serde_json::from_slice(&json)
+        .map_err(|e| format!("the delegation chain is not valid JSON: {e}"))?;
+
+    let decode_hex = |field: &str, value: &str| {
+        hex::decode(value).map_err(|e| format!(r#"invalid "{field}" in the delegation chain: {e}"#))
+    };
+    let delegations = chain
+        .delegations
+        .into_iter()
+        .map(|signed_delegation| {
+            let delegation = signed_delegation.delegation;
+            Ok(SignedDelegation {
+                delegation: Delegation {
+                    pubkey: decode_hex("pubkey", &delegation.pubkey)?,
+                    expiration: u64::from_str_radix(&delegation.expiration, 16).map_err(|e| {
+                        format!(r#"invalid "expiration" in the delegation chain: {e}"#)
+                    })?,
+                    targets: delegation
+                        .targets
+                        .map(|targets| {
+                            targets
+                                .iter()
+                                .map(|target| {
+                                    decode_hex("targets", target)
+                                        .map(|target| Principal::from_slice(&target))
+                                })
+                                .collect::<Result<Vec<_>, _>>()
+                        })
+                        .transpose()?,
+                },
+                signature: decode_hex("signature", &signed_delegation.signature)?,
+            })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    Ok((decode_hex("publicKey", &chain.public_key)?, delegations))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use http::Request;
+    use ic_agent::identity::BasicIdentity;
+    use serde_json::json;
+    use std::{
+        sync::Mutex,
+        time::{Duration, SystemTime},
+    };
+
+    #[derive(Debug)]
+    struct FixedClock(Mutex<SystemTime>);
+
+    impl Clock for FixedClock {
+        fn now(&self) -> SystemTime {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    fn clock() -> Arc<dyn Clock> {
+        Arc::new(FixedClock(Mutex::new(
+            UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        )))
+    }
+
+    fn canister_id(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    fn credential(
+        user: &BasicIdentity,
+        session: &BasicIdentity,
+        expiration: Duration,
+        targets: Option<Vec<Principal>>,
+    ) -> String {
+        let delegation = Delegation {
+            pubkey: session.public_key().unwrap(),
+            expiration: expiration.as_nanos() as u64,
+            targets: targets.clone(),
+        };
+        let signature = user.sign_delegation(&delegation).unwrap();
+        let mut delegation_json = json!({
+            "pubkey": hex::encode(&delegation.pubkey),
+            "expiration": format!("{:x}", delegation.expiration),
+        });
+        if let Some(targets) = targets {
+            delegation_json["targets"] = targets
+                .iter()
+                .map(|target| hex::encode(target.as_slice()))
+                .collect();
+        }
+        let chain = json!({
+            "delegations": [{
+                "delegation": delegation_json,
+                "signature": hex::encode(signature.signature.unwrap()),
+            }],
+            "publicKey": hex::encode(user.public_key().unwrap()),
+        });
+
+        URL_SAFE_NO_PAD.encode(chain.to_string())
+    }
+
+    fn request(header_name: header::HeaderName, header_value: &str) -> CanisterRequest {
+        Request::builder()
+            .uri("/whoami")
+            .header(header_name, header_value)
+            .body(Bytes::new())
+            .unwrap()
+    }
+
+    #[test]
+    fn should_authenticate_delegations_from_the_authorization_header() {
+        let user = BasicIdentity::from_raw_key(&[1; 32]);
+        let session = BasicIdentity::from_raw_key(&[2; 32]);
+        let authenticator = DelegationAuthenticator::new(Arc::new(session));
+        let credential = credential(
+            &user,
+            &BasicIdentity::from_raw_key(&[2; 32]),
+            Duration::from_secs(1_700_000_060),
+            Some(vec![canister_id(1)]),
+        );
+        let mut request = request(header::AUTHORIZATION, &format!("Delegation {credential}"));
+
+        let delegated_identity = authenticator
+            .authenticate(canister_id(1), &mut request, &clock())
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(delegated_identity.sender(), user.sender());
+        assert!(!request.headers().contains_key(header::AUTHORIZATION));
+        assert_eq!(authenticator.delegated_requests(), 1);
+    }
+
+    #[test]
+    fn should_take_delegations_from_a_cookie_and_keep_other_cookies() {
+        let user = BasicIdentity::from_raw_key(&[1; 32]);
+        let authenticator =
+            DelegationAuthenticator::new(Arc::new(BasicIdentity::from_raw_key(&[2; 32])))
+                .with_source(DelegationSource::Cookie("ic-delegation".to_string()));
+        let credential = credential(
+            &user,
+            &BasicIdentity::from_raw_key(&[2; 32]),
+            Duration::from_secs(1_700_000_060),
+            None,
+        );
+        let mut request = request(
+            header::COOKIE,
+            &format!("theme=dark; ic-delegation={credential}; lang=en"),
+        );
+        request
+            .headers_mut()
+            .insert(SEC_FETCH_SITE, HeaderValue::from_static("same-origin"));
+
+        let delegated_identity = authenticator
+            .authenticate(canister_id(1), &mut request, &clock())
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(delegated_identity.sender(), user.sender());
+        assert_eq!(request.headers()[header::COOKIE], "theme=dark; lang=en");
+    }
+
+    #[test]
+    fn should_only_take_delegations_from_cookies_of_allowed_origins() {
+        let user = BasicIdentity::from_raw_key(&[1; 32]);
+        let authenticator =
+            DelegationAuthenticator::new(Arc::new(BasicIdentity::from_raw_key(&[2; 32])))
+                .with_source(DelegationSource::Cookie("ic-delegation".to_string()))
+                .with_allowed_origins(["https://App.example.com".to_string()]);
+        let credential = credential(
+            &user,
+            &BasicIdentity::from_raw_key(&[2; 32]),
+            Duration::from_secs(1_700_000_060),
+            None,
+        );
+
+        for (headers, is_allowed) in [
+            (vec![(SEC_FETCH_SITE, "same-origin")], true),
+            (vec![(SEC_FETCH_SITE, "none")], true),
+            (vec![(SEC_FETCH_SITE, "same-site")], false),
+            (vec![(SEC_FETCH_SITE, "cross-site")], false),
+            (
+                vec![
+                    (SEC_FETCH_SITE, "cross-site"),
+                    ("origin", "https://app.example.com"),
+                ],
+                true,
+            ),
+            (
+                vec![
+                    ("origin", "https://canister.example.com"),
+                    ("host", "canister.example.com"),
+                ],
+                true,
+            ),
+            (
+                vec![
+                    ("origin", "https://attacker.example"),
+                    ("host", "canister.example.com"),
+                ],
+                false,
+            ),
+            (vec![("host", "canister.example.com")], false),
+        ] {
+            let mut request = request(header::COOKIE, &format!("ic-delegation={credential}"));
+            for (name, value) in &headers {
+                request
+                    .headers_mut()
+                    .insert(*name, HeaderValue::from_static(value));
+            }
+
+            let delegated_identity = authenticator
+                .authenticate(canister_id(1), &mut request, &clock())
+                .unwrap();
+
+            assert_eq!(delegated_identity.is_some(), is_allowed, "{headers:?}");
+            assert!(!request.headers().contains_key(header::COOKIE));
+        }
+        assert_eq!(authenticator.delegated_requests(), 4);
+        assert_eq!(authenticator.rejected_delegations(), 0);
+    }
+
+    #[test]
+    fn should_ignore_requests_without_a_delegation() {
+        let authenticator =
+            DelegationAuthenticator::new(Arc::new(BasicIdentity::from_raw_key(&[2; 32])));
+        let mut request = request(header::AUTHORIZATION, "Bearer token");
+
+        assert!(matches!(
+            authenticator.authenticate(canister_id(1), &mut request, &clock()),
+            Ok(None)
+        ));
+        assert_eq!(request.headers()[header::AUTHORIZATION], "Bearer token");
+    }
+
+    #[test]
+    fn should_reject_invalid_delegations() {
+        let user = BasicIdentity::from_raw_key(&[1; 32]);
+        let session = BasicIdentity::from_raw_key(&[2; 32]);
+        let authenticator =
+            DelegationAuthenticator::new(Arc::new(BasicIdentity::from_raw_key(&[2; 32])));
+        let credentials = [
+            // expired
+            credential(&user, &session, Duration::from_secs(1_699_999_999), None),
+            // targets another canister
+            credential(
+                &user,
+                &session,
+                Duration::from_secs(1_700_000_060),
+                Some(vec![canister_id(2)]),
+            ),
+            // delegates to another key
+            credential(
+                &user,
+                &BasicIdentity::from_raw_key(&[3; 32]),
+                Duration::from_secs(1_700_000_060),
+                None,
+            ),
+            "not a delegation chain".to_string(),
+        ];
+
+        for credential in &credentials {
+            let mut request = request(header::AUTHORIZATION, &format!("Delegation {credential}"));
+
+            assert!(matches!(
+                authenticator.authenticate(canister_id(1), &mut request, &clock()),
+                Err(HttpGatewayError::InvalidDelegation { .. })
+            ));
+        }
+        assert_eq!(authenticator.rejected_delegations(), 4);
+    }
+}
@@ -0,0 +1,550 @@
+use crate::{
+    CanisterRequest, CanisterResponse, HttpGatewayError, HttpGatewayResponse,
+    HttpGatewayResponseBody, HttpGatewayResponseMetadata, HttpGatewayResult,
+};
+use bytes::Bytes;
+use candid::Principal;
+use http::{header, HeaderValue, Response, StatusCode};
+use http_body_util::Full;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Formatter},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, SystemTime},
+};
+use tokio::task::JoinHandle;
+
+pub(crate) const DEFAULT_DENYLIST_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The response to requests that match the denylist.
+#[derive(Debug, Clone, Default)]
+pub enum DenylistResponse {
+    /// Respond with `451 Unavailable For Legal Reasons`.
+    #[default]
+    UnavailableForLegalReasons,
+
+    /// Respond with `404 Not Found` and a custom page.
+    NotFound {
+        content_type: HeaderValue,
+        page: Bytes,
+    },
+}
+
+impl DenylistResponse {
+    fn create_response(&self) -> CanisterResponse {
+        let (status_code, content_type, body) = match self {
+            DenylistResponse::UnavailableForLegalReasons => (
+                StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS,
+                HeaderValue::from_static("text/plain; charset=utf-8"),
+                Bytes::from_static(b"Unavailable For Legal Reasons"),
+            ),
+            DenylistResponse::NotFound { content_type, page } => {
+                (StatusCode::NOT_FOUND, content_type.clone(), page.clone())
+            }
+        };
+
+        let mut response = Response::new(HttpGatewayResponseBody::Right(Full::new(body)));
+        *response.status_mut() = status_code;
+        response
+            .headers_mut()
+            .insert(header::CONTENT_TYPE, content_type);
+
+        response
+    }
+}
+
+/// The contents of a denylist file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DenylistFile {
+    #[serde(default)]
+    canisters: Vec<DenylistFileEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DenylistFileEntry {
+    id: String,
+
+    /// If empty, every path of the canister is denied.
+    #[serde(default)]
+    paths: Vec<String>,
+}
+
+/// The denied paths of each canister, `None` if every path is denied.
+type DenylistRules = HashMap<Principal, Option<Vec<String>>>;
+
+/// A list of canisters, or specific paths of canisters, that the gateway refuses to serve,
+/// e.g. because of legal takedowns.
+///
+/// The denylist is loaded from a JSON file, or from a TOML file if the file has a `.toml` extension:
+///
+/// ```toml
+/// [[canisters]]
+/// id = "qoctq-giaaa-aaaaa-aaaea-cai"
+///
+/// [[canisters]]
+/// id = "rdmx6-jaaaa-aaaaa-aaadq-cai"
+/// paths = ["/takedown.html", "/videos/*"]
+/// ```
+///
+/// Paths are matched exactly, or by prefix if they end with `*`. The query string is ignored.
+/// Request paths are percent-decoded and normalized before they are matched, as canisters
+/// decode them before serving, so that `/takedown%2Ehtml`, `//takedown.html` or
+/// `/x/../takedown.html` are denied as `/takedown.html`.
+/// Once the gateway is built, the file is polled for changes and reloaded.
+/// If a changed file fails to load, the previous denylist is kept.
+pub struct Denylist {
+    path: PathBuf,
+    poll_interval: Duration,
+    response: DenylistResponse,
+    rules: RwLock<DenylistRules>,
+    modified_at: Mutex<Option<SystemTime>>,
+    watching: AtomicBool,
+    hits: AtomicU64,
+    reloads: AtomicU64,
+    reload_failures: AtomicU64,
+}
+
+impl Debug for Denylist {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Denylist")
+            .field("path", &self.path)
+            .field("poll_interval", &self.poll_interval)
+            .field("response", &self.response)
+            .field("len", &self.len())
+            .field("hits", &self.hits())
+            .field("reloads", &self.reloads())
+            .field("reload_failures", &self.reload_failures())
+            .finish()
+    }
+}
+
+impl Denylist {
+    /// Loads the denylist from a JSON or TOML file.
+    pub fn load(path: impl Into<PathBuf>) -> HttpGatewayResult<Self> {
+        let path = path.into();
+        let modified_at = std::fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        let rules = std::fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|contents| parse_denylist(&path, &contents))
+            .map_err(|reason| HttpGatewayError::DenylistLoadingError {
+                path: path.display().to_string(),
+                reason,
+            })?;
+
+        Ok(Self {
+            path,
+            poll_interval: DEFAULT_DENYLIST_POLL_INTERVAL,
+            response: DenylistResponse::default(),
+            rules: RwLock::new(rules),
+            modified_at: Mutex::new(modified_at),
+            watching: AtomicBool::new(false),
+            hits: AtomicU64::new(0),
+            reloads: AtomicU64::new(0),
+            reload_failures: AtomicU64::new(0),
+        })
+    }
+
+    /// The response to requests that match the denylist, defaults to `451 Unavailable For Legal Reasons`.
+    pub fn with_response(mut self, response: DenylistResponse) -> Self {
+        self.response = response;
+
+        self
+    }
+
+    /// How often the file is checked for changes, defaults to 10 seconds.
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+
+        self
+    }
+
+    /// The number of canisters with denied paths.
+    pub fn len(&self) -> usize {
+        self.rules.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of requests that were denied.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// The number of times the file was reloaded after it changed.
+    pub fn reloads(&self) -> u64 {
+        self.reloads.load(Ordering::Relaxed)
+    }
+
+    /// The number of times the changed file could not be reloaded.
+    pub fn reload_failures(&self) -> u64 {
+        self.reload_failures.load(Ordering::Relaxed)
+    }
+
+    /// Whether the path of the canister is denied.
+    pub fn is_denied(&self, canister_id: &Principal, path: &str) -> bool {
+        match self.rules.read().unwrap().get(canister_id) {
+            Some(None) => true,
+            Some(Some(patterns)) => {
+                let path = normalize_path(path);
+                patterns.iter().any(|pattern| matches_path(pattern, &path))
+            }
+            None => false,
+        }
+    }
+
+    /// Reloads the file if it changed since it was last loaded.
+    /// Returns whether the denylist was reloaded.
+    pub async fn reload(&self) -> HttpGatewayResult<bool> {
+        let loading_error = |reason: String| HttpGatewayError::DenylistLoadingError {
+            path: self.path.display().to_string(),
+            reason,
+        };
+
+        let modified_at = tokio::fs::metadata(&self.path)
+            .await
+            .and_then(|metadata| metadata.modified())
+            .map_err(|e| loading_error(e.to_string()))?;
+        if *self.modified_at.lock().unwrap() == Some(modified_at) {
+            return Ok(false);
+        }
+
+        let rules = tokio::fs::read_to_string(&self.path)
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|contents| parse_denylist(&self.path, &contents))
+            .map_err(loading_error)?;
+
+        *self.rules.write().unwrap() = rules;
+        *self.modified_at.lock().unwrap() = Some(modified_at);
+        self.reloads.fetch_add(1, Ordering::Relaxed);
+
+        Ok(true)
+    }
+
+    /// Polls the file for changes until the denylist is dropped.
+    /// Only the first call starts polling, later calls return `None`.
+    /// Must be called within a tokio runtime.
+    pub fn watch(self: &Arc<Self>) -> Option<JoinHandle<()>> {
+        if self.watching.swap(true, Ordering::Relaxed) {
+            return None;
+        }
+
+        let denylist = Arc::downgrade(self);
+        let poll_interval = self.poll_interval;
+
+        Some(tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(poll_interval).await;
+
+                let Some(denylist) = denylist.upgrade() else {
+                    return;
+                };
+                if denylist.reload().await.is_err() {
+                    denylist.reload_failures.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }))
+    }
+
+    /// Returns the denylist response if the request is denied.
+    pub(crate) fn check(
+        &self,
+        canister_id: &Principal,
+        request: &CanisterRequest,
+    ) -> Option<HttpGatewayResponse> {
+        if !self.is_denied(canister_id, request.uri().path()) {
+            return None;
+        }
+
+        self.hits.fetch_add(1, Ordering::Relaxed);
+
+        Some(HttpGatewayResponse {
+            canister_response: self.response.create_response(),
+            metadata: HttpGatewayResponseMetadata {
+                denylisted: true,
+                ..Default::default()
+            },
+        })
+    }
+}
+
+fn parse_denylist(path: &Path, contents: &str) -> Result<DenylistRules, String> {
+    let denylist_file: DenylistFile =
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => toml::from_str(contents).map_err(|e| e.to_string())?,
+            _ => serde_json::from_str(contents).map_err(|e| e.to_string())?,
+        };
+
+    let mut rules = DenylistRules::new();
+    for entry in denylist_file.canisters {
+        let canister_id = Principal::from_text(&entry.id)
+            .map_err(|e| format!(r#"invalid canister id "{}": {}"#, entry.id, e))?;
+        if let Some(pattern) = entry.paths.iter().find(|path| !path.starts_with('/')) {
+            return Err(format!(
+                r#"invalid path "{}": must start with "/""#,
+                pattern
+            ));
+        }
+
+        let paths = rules.entry(canister_id).or_insert_with(|| Some(vec![]));
+        match (paths, entry.paths.is_empty()) {
+            (paths, true) => *paths = None,
+            (Some(paths), false) => paths.extend(entry.paths),
+            (None, false) => {}
+        }
+    }
+
+    Ok(rules)
+}
+
+/// Percent-decodes the path, collapses duplicate slashes and resolves `.` and `..` segments.
+pub(crate) fn normalize_path(path: &str) -> String {
+    let decoded = urlencoding::decode_binary(path.as_bytes());
+    let decoded = String::from_utf8_lossy(&decoded);
+
+    let mut segments = Vec::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+
+    let mut normalized = format!("/{}", segments.join("/"));
+    let is_directory =
+        decoded.ends_with('/') || decoded.ends_with("/.") || decoded.ends_with("/..");
+    if is_directory && !segments.is_empty() {
+        normalized.push('/');
+    }
+
+    normalized
+}
+
+fn matches_path(pattern: &str, path: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => path.starts_with(prefix),
+        None => path == pattern,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::assert_matches;
+    use http::Request;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    const DENIED_CANISTER_ID: &str = "qoctq-giaaa-aaaaa-aaaea-cai";
+    const PARTIALLY_DENIED_CANISTER_ID: &str = "rdmx6-jaaaa-aaaaa-aaadq-cai";
+
+    fn write_denylist(suffix: &str, contents: &str) -> NamedTempFile {
+        let mut file = tempfile::Builder::new().suffix(suffix).tempfile().unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+
+        file
+    }
+
+    fn canister_id(text: &str) -> Principal {
+        Principal::from_text(text).unwrap()
+    }
+
+    #[test]
+    fn should_load_json_and_toml_denylists() {
+        let json_file = write_denylist(
+            ".json",
+            &format!(
+                r#"{{"canisters": [{{"id": "{}"}}, {{"id": "{}", "paths": ["/takedown.html", "/videos/*"]}}]}}"#,
+                DENIED_CANISTER_ID, PARTIALLY_DENIED_CANISTER_ID
+            ),
+        );
+        let toml_file = write_denylist(
+            ".toml",
+            &format!(
+                "[[canisters]]\nid = \"{}\"\n\n[[canisters]]\nid = \"{}\"\npaths = [\"/takedown.html\", \"/videos/*\"]\n",
+                DENIED_CANISTER_ID, PARTIALLY_DENIED_CANISTER_ID
+            ),
+        );
+
+        for file in [json_file, toml_file] {
+            let denylist = Denylist::load(file.path()).unwrap();
+
+            assert_eq!(denylist.len(), 2);
+            assert!(denylist.is_denied(&canister_id(DENIED_CANISTER_ID), "/"));
+            assert!(
+                denylist.is_denied(&canister_id(PARTIALLY_DENIED_CANISTER_ID), "/takedown.html")
+            );
+            assert!(denylist.is_denied(
+                &canister_id(PARTIALLY_DENIED_CANISTER_ID),
+                "/videos/cat.mp4"
+            ));
+            assert!(!denylist.is_denied(
+                &canister_id(PARTIALLY_DENIED_CANISTER_ID),
+                "/takedown.html.bak"
+            ));
+            assert!(!denylist.is_denied(&canister_id(PARTIALLY_DENIED_CANISTER_ID), "/"));
+            assert!(!denylist.is_denied(&Principal::anonymous(), "/"));
+        }
+    }
+
+    #[test]
+    fn should_fail_to_load_invalid_denylists() {
+        for contents in [
+            r#"{"canisters": [{"id": "not a principal"}]}"#,
+            r#"{"canisters": [{"id": "qoctq-giaaa-aaaaa-aaaea-cai", "paths": ["videos"]}]}"#,
+            r#"{"canister": []}"#,
+        ] {
+            let file = write_denylist(".json", contents);
+
+            assert_matches!(
+                Denylist::load(file.path()),
+                Err(HttpGatewayError::DenylistLoadingError { .. })
+            );
+        }
+    }
+
+    #[test]
+    fn should_respond_to_denied_requests() {
+        let file = write_denylist(
+            ".json",
+            &format!(
+                r#"{{"canisters": [{{"id": "{}", "paths": ["/takedown.html"]}}]}}"#,
+                PARTIALLY_DENIED_CANISTER_ID
+            ),
+        );
+        let denylist =
+            Denylist::load(file.path())
+                .unwrap()
+                .with_response(DenylistResponse::NotFound {
+                    content_type: HeaderValue::from_static("text/html"),
+                    page: Bytes::from_static(b"<h1>Not Found</h1>"),
+                });
+        let canister_id = canister_id(PARTIALLY_DENIED_CANISTER_ID);
+
+        let request = Request::get("/takedown.html?v=1")
+            .body(Bytes::new())
+            .unwrap();
+        let response = denylist.check(&canister_id, &request).unwrap();
+        assert_eq!(response.canister_response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            response
+                .canister_response
+                .headers()
+                .get(header::CONTENT_TYPE),
+            Some(&HeaderValue::from_static("text/html"))
+        );
+        assert!(response.metadata.denylisted);
+
+        let request = Request::get("/index.html").body(Bytes::new()).unwrap();
+        assert!(denylist.check(&canister_id, &request).is_none());
+        assert_eq!(denylist.hits(), 1);
+    }
+
+    #[test]
+    fn should_deny_encoded_and_unnormalized_paths() {
+        let file = write_denylist(
+            ".json",
+            &format!(
+                r#"{{"canisters": [{{"id": "{}", "paths": ["/takedown.html", "/videos/*"]}}]}}"#,
+                PARTIALLY_DENIED_CANISTER_ID
+            ),
+        );
+        let denylist = Denylist::load(file.path()).unwrap();
+        let canister_id = canister_id(PARTIALLY_DENIED_CANISTER_ID);
+
+        for path in [
+            "/takedown%2Ehtml",
+            "/%74akedown.html",
+            "//takedown.html",
+            "/x/../takedown.html",
+            "/./takedown.html",
+            "/x/%2E%2E/takedown.html",
+            "/x%2F..%2Ftakedown.html",
+            "/../../takedown.html",
+            "//videos//cat.mp4",
+            "/videos/./cat.mp4",
+            "/%76ideos/cat.mp4",
+        ] {
+            let request = Request::get(path).body(Bytes::new()).unwrap();
+
+            assert!(
+                denylist.check(&canister_id, &request).is_some(),
+                "{path} is not denied"
+            );
+        }
+
+        for path in [
+            "/takedown.html/../index.html",
+            "/takedown%252Ehtml",
+            "/videos",
+        ] {
+            assert!(!denylist.is_denied(&canister_id, path), "{path} is denied");
+        }
+    }
+
+    #[test]
+    fn should_normalize_paths() {
+        assert_eq!(normalize_path("/"), "/");
+        assert_eq!(normalize_path(""), "/");
+        assert_eq!(normalize_path("/a//b/"), "/a/b/");
+        assert_eq!(normalize_path("/a/b/.."), "/a/");
+        assert_eq!(normalize_path("/a/./b"), "/a/b");
+        assert_eq!(normalize_path("/.."), "/");
+        assert_eq!(normalize_path("/a%20b"), "/a b");
+        assert_eq!(normalize_path("/%ff"), "/\u{fffd}");
+    }
+
+    #[tokio::test]
+    async fn should_reload_changed_denylists() {
+        let file = write_denylist(".json", r#"{"canisters": []}"#);
+        let denylist = Denylist::load(file.path()).unwrap();
+        let modified_at = std::fs::metadata(file.path()).unwrap().modified().unwrap();
+        assert!(!denylist.reload().await.unwrap());
+
+        rewrite_denylist(
+            file.path(),
+            &format!(r#"{{"canisters": [{{"id": "{}"}}]}}"#, DENIED_CANISTER_ID),
+            modified_at + Duration::from_secs(1),
+        );
+        assert!(denylist.reload().await.unwrap());
+        assert!(denylist.is_denied(&canister_id(DENIED_CANISTER_ID), "/"));
+        assert_eq!(denylist.reloads(), 1);
+
+        // invalid changes keep the previous denylist
+        rewrite_denylist(
+            file.path(),
+            "not json",
+            modified_at + Duration::from_secs(2),
+        );
+        assert_matches!(
+            denylist.reload().await,
+            Err(HttpGatewayError::DenylistLoadingError { .. })
+        );
+        assert!(denylist.is_denied(&canister_id(DENIED_CANISTER_ID), "/"));
+    }
+
+    // sets the modification time explicitly, since file systems may have a coarse resolution
+    fn rewrite_denylist(path: &Path, contents: &str, modified_at: SystemTime) {
+        std::fs::write(path, contents).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(modified_at)
+            .unwrap();
+    }
+}
@@ -1,32 +1,147 @@
 use crate::{
-    HttpGatewayClientBuilder, HttpGatewayRequestArgs, HttpGatewayRequestBuilder,
-    HttpGatewayRequestBuilderArgs,
+    DelegationAuthenticator, Denylist, HttpGatewayClientBuilder, HttpGatewayMetrics,
+    HttpGatewayRequestArgs, HttpGatewayRequestBuilder, HttpGatewayRequestBuilderArgs,
+    HttpInterfaceResolver, RateLimiter, RequestCoalescer, ResponseCache, ResponseStreamingOptions,
+    ResponseVerificationOptions, UpdateCallPolicy,
 };
 use ic_agent::Agent;
+use std::{sync::Arc, time::Duration};
 
 #[derive(Clone)]
 pub struct HttpGatewayClientArgs {
     pub agent: Agent,
+    pub response_verification_options: ResponseVerificationOptions,
+    pub response_streaming_options: ResponseStreamingOptions,
+    pub request_coalescer: Option<Arc<RequestCoalescer>>,
+    pub response_cache: Option<Arc<ResponseCache>>,
+    pub denylist: Option<Arc<Denylist>>,
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    pub update_call_policy: Option<Arc<UpdateCallPolicy>>,
+    pub delegation_authenticator: Option<Arc<DelegationAuthenticator>>,
+    pub http_interface_resolver: Option<Arc<HttpInterfaceResolver>>,
 }
 
 #[derive(Clone)]
 pub struct HttpGatewayClient {
     agent: Agent,
+    response_verification_options: ResponseVerificationOptions,
+    response_streaming_options: ResponseStreamingOptions,
+    request_coalescer: Option<Arc<RequestCoalescer>>,
+    response_cache: Option<Arc<ResponseCache>>,
+    denylist: Option<Arc<Denylist>>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    update_call_policy: Option<Arc<UpdateCallPolicy>>,
+    delegation_authenticator: Option<Arc<DelegationAuthenticator>>,
+    http_interface_resolver: Option<Arc<HttpInterfaceResolver>>,
 }
 
 impl<'a> HttpGatewayClient {
     pub fn new(args: HttpGatewayClientArgs) -> Self {
-        Self { agent: args.agent }
+        Self {
+            agent: args.agent,
+            response_verification_options: args.response_verification_options,
+            response_streaming_options: args.response_streaming_options,
+            request_coalescer: args.request_coalescer,
+            response_cache: args.response_cache,
+            denylist: args.denylist,
+            rate_limiter: args.rate_limiter,
+            update_call_policy: args.update_call_policy,
+            delegation_authenticator: args.delegation_authenticator,
+            http_interface_resolver: args.http_interface_resolver,
+        }
     }
 
     pub fn builder() -> HttpGatewayClientBuilder {
         Default::default()
     }
 
+    /// Returns a snapshot of the client's metrics.
+    pub fn metrics(&self) -> HttpGatewayMetrics {
+        let verification_pool = self
+            .response_verification_options
+            .verification_pool
+            .as_ref();
+        let verification_cache = self
+            .response_verification_options
+            .verification_cache
+            .as_ref();
+
+        HttpGatewayMetrics {
+            verification_queue_length: verification_pool.map_or(0, |pool| pool.queued()),
+            verifications: verification_pool.map_or(0, |pool| pool.verifications()),
+            verification_queue_time: verification_pool
+                .map_or(Duration::ZERO, |pool| pool.total_queue_time()),
+            max_verification_queue_time: verification_pool
+                .map_or(Duration::ZERO, |pool| pool.max_queue_time()),
+            verification_cache_hits: verification_cache.map_or(0, |cache| cache.hits()),
+            verification_cache_misses: verification_cache.map_or(0, |cache| cache.misses()),
+            verification_cache_size: verification_cache.map_or(0, |cache| cache.len()),
+            coalesced_requests: self
+                .request_coalescer
+                .as_ref()
+                .map_or(0, |coalescer| coalescer.coalesced_requests()),
+            response_cache_hits: self.response_cache.as_ref().map_or(0, |cache| cache.hits()),
+            stale_response_cache_hits: self
+                .response_cache
+                .as_ref()
+                .map_or(0, |cache| cache.stale_hits()),
+            denylisted_requests: self.denylist.as_ref().map_or(0, |denylist| denylist.hits()),
+            denylist_reload_failures: self
+                .denylist
+                .as_ref()
+                .map_or(0, |denylist| denylist.reload_failures()),
+            rate_limited_queries: self
+                .rate_limiter
+                .as_ref()
+                .map_or(0, |rate_limiter| rate_limiter.limited_queries()),
+            rate_limited_update_calls: self
+                .rate_limiter
+                .as_ref()
+                .map_or(0, |rate_limiter| rate_limiter.limited_update_calls()),
+            refused_upgrades: self
+                .update_call_policy
+                .as_ref()
+                .map_or(0, |update_call_policy| {
+                    update_call_policy.refused_upgrades()
+                }),
+            update_fallbacks: self
+                .update_call_policy
+                .as_ref()
+                .map_or(0, |update_call_policy| {
+                    update_call_policy.update_fallbacks()
+                }),
+            delegated_requests: self
+                .delegation_authenticator
+                .as_ref()
+                .map_or(0, |delegation_authenticator| {
+                    delegation_authenticator.delegated_requests()
+                }),
+            rejected_delegations: self
+                .delegation_authenticator
+                .as_ref()
+                .map_or(0, |delegation_authenticator| {
+                    delegation_authenticator.rejected_delegations()
+                }),
+            rejected_verification_versions: self
+                .response_verification_options
+                .verification_version_policy
+                .rejected_responses(),
+        }
+    }
+
     pub fn request(&'a self, args: HttpGatewayRequestArgs) -> HttpGatewayRequestBuilder<'a> {
         HttpGatewayRequestBuilder::new(HttpGatewayRequestBuilderArgs {
             request_args: args,
             agent: &self.agent,
+            response_verification_options: &self.response_verification_options,
+            response_streaming_options: &self.response_streaming_options,
+            request_coalescer: self.request_coalescer.as_deref(),
+            response_cache: self.response_cache.as_ref(),
+            denylist: self.denylist.as_deref(),
+            rate_limiter: self.rate_limiter.as_deref(),
+            update_call_policy: self.update_call_policy.as_ref(),
+            delegation_authenticator: self.delegation_authenticator.as_deref(),
+            http_interface_resolver: self.http_interface_resolver.as_ref(),
         })
     }
 }
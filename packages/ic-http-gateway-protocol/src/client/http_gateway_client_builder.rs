@@ -1,15 +1,71 @@
 use crate::{
-    HttpGatewayClient, HttpGatewayClientArgs, HttpGatewayResult, DEFAULT_BOUNDARY_NODE_ENDPOINT,
+    check_root_key, set_root_key, Clock, DelegationAuthenticator, Denylist, HttpGatewayClient,
+    HttpGatewayClientArgs, HttpGatewayResult, HttpInterface, HttpInterfaceResolver, RateLimit,
+    RateLimitKey, RateLimiter, ReadOnlyMode, RefusedUpgradeResponse, RequestCoalescer,
+    ResponseCache, ResponseStreamingOptions, ResponseVerificationOptions, RootKey,
+    StreamingTokenPredictor, UpdateCallPolicy, V1HeaderPolicy, VerificationCache, VerificationPool,
+    VerificationVersionPolicy, VerificationVersionRange, DEFAULT_BOUNDARY_NODE_ENDPOINT,
+    DEFAULT_MAX_STALENESS,
 };
+use candid::Principal;
 use ic_agent::Agent;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
 
 pub struct HttpGatewayClientBuilder {
     agent: Option<Agent>,
+    url: Option<String>,
+    root_key: Option<RootKey>,
+    response_verification_options: ResponseVerificationOptions,
+    response_streaming_options: ResponseStreamingOptions,
+    request_coalescing: bool,
+    response_cache_capacity: usize,
+    max_staleness: Duration,
+    canister_max_staleness: HashMap<Principal, Duration>,
+    denylist: Option<Arc<Denylist>>,
+    rate_limit_key: RateLimitKey,
+    query_rate_limit: Option<RateLimit>,
+    update_rate_limit: Option<RateLimit>,
+    read_only_mode: Option<ReadOnlyMode>,
+    canister_read_only_modes: HashMap<Principal, Option<ReadOnlyMode>>,
+    refused_upgrade_response: RefusedUpgradeResponse,
+    update_fallback_canisters: HashSet<Principal>,
+    delegation_authenticator: Option<Arc<DelegationAuthenticator>>,
+    canister_http_interfaces: HashMap<Principal, HttpInterface>,
+    http_interface_discovery: bool,
+    verification_versions: VerificationVersionRange,
+    canister_verification_versions: HashMap<Principal, VerificationVersionRange>,
 }
 
 impl HttpGatewayClientBuilder {
     pub fn new() -> Self {
-        Self { agent: None }
+        Self {
+            agent: None,
+            url: None,
+            root_key: None,
+            response_verification_options: ResponseVerificationOptions::default(),
+            response_streaming_options: ResponseStreamingOptions::default(),
+            request_coalescing: false,
+            response_cache_capacity: 0,
+            max_staleness: DEFAULT_MAX_STALENESS,
+            canister_max_staleness: HashMap::new(),
+            denylist: None,
+            rate_limit_key: RateLimitKey::default(),
+            query_rate_limit: None,
+            update_rate_limit: None,
+            read_only_mode: None,
+            canister_read_only_modes: HashMap::new(),
+            refused_upgrade_response: RefusedUpgradeResponse::default(),
+            update_fallback_canisters: HashSet::new(),
+            delegation_authenticator: None,
+            canister_http_interfaces: HashMap::new(),
+            http_interface_discovery: false,
+            verification_versions: VerificationVersionRange::default(),
+            canister_verification_versions: HashMap::new(),
+        }
     }
 
     pub fn with_agent(mut self, agent: Agent) -> Self {
@@ -18,15 +74,361 @@ impl HttpGatewayClientBuilder {
         self
     }
 
-    pub fn build(self) -> HttpGatewayResult<HttpGatewayClient> {
-        let agent = match self.agent {
-            Some(agent) => agent,
-            None => Agent::builder()
-                .with_url(DEFAULT_BOUNDARY_NODE_ENDPOINT)
-                .build()?,
-        };
+    /// The URL of the replica or API boundary node to send requests to.
+    /// Ignored if an agent is provided.
+    pub fn with_url(mut self, url: impl Into<String>) -> Self {
+        self.url = Some(url.into());
+
+        self
+    }
+
+    /// The root key used to verify certificates.
+    /// Defaults to the root key of a pre-built agent, or else to the pinned mainnet root key.
+    pub fn with_root_key(mut self, root_key: RootKey) -> Self {
+        self.root_key = Some(root_key);
+
+        self
+    }
+
+    /// The clock used to check the freshness of certificates, defaults to the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.response_verification_options.clock = clock;
+
+        self
+    }
+
+    /// How far a certificate's time may be in the past and in the future, relative to the current time.
+    /// Both default to 5 minutes.
+    pub fn with_max_cert_time_offset(mut self, past: Duration, future: Duration) -> Self {
+        self.response_verification_options.max_cert_time_offset_past = past;
+        self.response_verification_options
+            .max_cert_time_offset_future = future;
+
+        self
+    }
+
+    /// The maximum number of responses that are verified concurrently on blocking threads,
+    /// defaults to the available parallelism. A limit of 0 verifies responses on the async runtime.
+    pub fn with_max_concurrent_verifications(
+        mut self,
+        max_concurrent_verifications: usize,
+    ) -> Self {
+        self.response_verification_options.verification_pool = (max_concurrent_verifications > 0)
+            .then(|| Arc::new(VerificationPool::new(max_concurrent_verifications)));
+
+        self
+    }
+
+    /// The maximum number of successful verifications to cache, defaults to 1024.
+    /// A capacity of 0 disables the cache.
+    pub fn with_verification_cache_capacity(mut self, capacity: usize) -> Self {
+        self.response_verification_options.verification_cache =
+            (capacity > 0).then(|| Arc::new(VerificationCache::new(capacity)));
+
+        self
+    }
+
+    /// How many chunks of a large asset are fetched concurrently, defaults to 4.
+    pub fn with_max_concurrent_chunk_requests(
+        mut self,
+        max_concurrent_chunk_requests: usize,
+    ) -> Self {
+        self.response_streaming_options
+            .max_concurrent_chunk_requests = max_concurrent_chunk_requests;
+
+        self
+    }
+
+    /// Prefetches the chunks of callback-strategy streams concurrently if their tokens can be predicted,
+    /// e.g. with [`CertifiedAssetsTokenPredictor`](crate::CertifiedAssetsTokenPredictor).
+    /// By default, chunks are fetched one after another.
+    pub fn with_streaming_token_predictor(
+        mut self,
+        streaming_token_predictor: Arc<dyn StreamingTokenPredictor>,
+    ) -> Self {
+        self.response_streaming_options.streaming_token_predictor = Some(streaming_token_predictor);
+
+        self
+    }
+
+    /// Serves identical concurrent `GET` and `HEAD` requests with a single canister call and verification.
+    /// Requests with credentials and responses with streamed bodies are never shared. Disabled by default.
+    pub fn with_request_coalescing(mut self, request_coalescing: bool) -> Self {
+        self.request_coalescing = request_coalescing;
+
+        self
+    }
+
+    /// The maximum number of verified responses to cache according to their certified `Cache-Control` header.
+    /// A capacity of 0 disables the cache, which is the default.
+    pub fn with_response_cache_capacity(mut self, capacity: usize) -> Self {
+        self.response_cache_capacity = capacity;
+
+        self
+    }
+
+    /// How long cached responses may be served after they became stale, if allowed by their
+    /// `stale-while-revalidate` and `stale-if-error` directives. Defaults to 5 minutes.
+    pub fn with_max_staleness(mut self, max_staleness: Duration) -> Self {
+        self.max_staleness = max_staleness;
+
+        self
+    }
+
+    /// Overrides the maximum staleness of cached responses for a single canister.
+    pub fn with_canister_max_staleness(
+        mut self,
+        canister_id: Principal,
+        max_staleness: Duration,
+    ) -> Self {
+        self.canister_max_staleness
+            .insert(canister_id, max_staleness);
+
+        self
+    }
+
+    /// Refuses requests to the canisters and paths of the denylist before any call is made.
+    /// The denylist file is polled for changes once the client is built.
+    pub fn with_denylist(mut self, denylist: Arc<Denylist>) -> Self {
+        self.denylist = Some(denylist);
+
+        self
+    }
+
+    /// What rate limit budgets are tracked by, defaults to the client IP address
+    /// set with [set_client_ip](crate::HttpGatewayRequestBuilder::set_client_ip).
+    pub fn with_rate_limit_key(mut self, rate_limit_key: RateLimitKey) -> Self {
+        self.rate_limit_key = rate_limit_key;
+
+        self
+    }
+
+    /// The budget of every request, which starts out as a query call. Unlimited by default.
+    pub fn with_query_rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.query_rate_limit = Some(rate_limit);
+
+        self
+    }
+
+    /// The budget of requests that the canister upgrades to an update call,
+    /// in addition to their query budget. Unlimited by default.
+    pub fn with_update_rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.update_rate_limit = Some(rate_limit);
+
+        self
+    }
+
+    /// Refuses to upgrade queries to update calls, except for the methods and paths allowed by the read-only mode.
+    /// By default, every upgrade is allowed.
+    pub fn with_read_only_mode(mut self, read_only_mode: ReadOnlyMode) -> Self {
+        self.read_only_mode = Some(read_only_mode);
+
+        self
+    }
+
+    /// Overrides the read-only mode for a single canister, `None` allows every upgrade for the canister.
+    pub fn with_canister_read_only_mode(
+        mut self,
+        canister_id: Principal,
+        read_only_mode: Option<ReadOnlyMode>,
+    ) -> Self {
+        self.canister_read_only_modes
+            .insert(canister_id, read_only_mode);
+
+        self
+    }
+
+    /// The response to requests whose upgrade to an update call was refused,
+    /// defaults to `503 Service Unavailable`.
+    pub fn with_refused_upgrade_response(
+        mut self,
+        refused_upgrade_response: RefusedUpgradeResponse,
+    ) -> Self {
+        self.refused_upgrade_response = refused_upgrade_response;
+
+        self
+    }
+
+    /// Re-issues `GET`, `HEAD`, `OPTIONS` and `TRACE` requests to the canister as update calls
+    /// if their query response is not certified, since update call responses are certified by consensus.
+    /// Invalid or unacceptable certifications, e.g. stale certificates, still fail the request.
+    /// Meant for canisters that only certify some of their routes, disabled by default.
+    pub fn with_update_call_fallback(mut self, canister_id: Principal) -> Self {
+        self.update_fallback_canisters.insert(canister_id);
+
+        self
+    }
+
+    /// Calls canisters on behalf of the users whose requests carry a delegation chain to the session key
+    /// of the authenticator, instead of with the identity of the agent. Disabled by default.
+    pub fn with_delegation_authenticator(
+        mut self,
+        delegation_authenticator: Arc<DelegationAuthenticator>,
+    ) -> Self {
+        self.delegation_authenticator = Some(delegation_authenticator);
+
+        self
+    }
+
+    /// The methods that serve HTTP requests on a canister,
+    /// defaults to `http_request` and `http_request_update`.
+    pub fn with_canister_http_interface(
+        mut self,
+        canister_id: Principal,
+        http_interface: HttpInterface,
+    ) -> Self {
+        self.canister_http_interfaces
+            .insert(canister_id, http_interface);
+
+        self
+    }
+
+    /// Reads the methods that serve HTTP requests from the `candid:service` metadata of canisters,
+    /// and validates configured methods against it. Requests to canisters without a readable
+    /// candid interface fail. Disabled by default.
+    pub fn with_http_interface_discovery(mut self, http_interface_discovery: bool) -> Self {
+        self.http_interface_discovery = http_interface_discovery;
+
+        self
+    }
+
+    /// The response verification versions that are accepted from canisters, defaults to all
+    /// versions supported by the gateway. Canisters are asked for the highest allowed version,
+    /// and responses of other versions are rejected with
+    /// [VerificationVersionNotAllowed](crate::HttpGatewayError::VerificationVersionNotAllowed).
+    pub fn with_verification_versions(
+        mut self,
+        verification_versions: VerificationVersionRange,
+    ) -> Self {
+        self.verification_versions = verification_versions;
+
+        self
+    }
+
+    /// Overrides the accepted response verification versions for a canister.
+    pub fn with_canister_verification_versions(
+        mut self,
+        canister_id: Principal,
+        verification_versions: VerificationVersionRange,
+    ) -> Self {
+        self.canister_verification_versions
+            .insert(canister_id, verification_versions);
+
+        self
+    }
+
+    /// The uncertified headers of verification version 1 responses that are passed on to clients,
+    /// defaults to [V1HeaderPolicy::default].
+    pub fn with_v1_header_policy(mut self, v1_header_policy: V1HeaderPolicy) -> Self {
+        self.response_verification_options.v1_header_policy = Arc::new(v1_header_policy);
+
+        self
+    }
+
+    /// Removes the `IC-Certificate` and `IC-CertificateExpression` headers from responses,
+    /// which are of no use to browsers. Their decoded content remains available in
+    /// [HttpGatewayResponseMetadata::certificate](crate::HttpGatewayResponseMetadata::certificate).
+    /// Disabled by default, for clients that verify responses themselves.
+    pub fn with_strip_certificate_headers(mut self, strip_certificate_headers: bool) -> Self {
+        self.response_verification_options.strip_certificate_headers = strip_certificate_headers;
+
+        self
+    }
+
+    /// Builds the client without contacting the network.
+    /// Fails for [RootKey::FetchForLocalDev], which requires [Self::build_and_check_root_key].
+    pub fn build(mut self) -> HttpGatewayResult<HttpGatewayClient> {
+        let (agent, url) = self.build_agent()?;
+        set_root_key(&agent, url.as_deref(), self.root_key.take())?;
+
+        Ok(self.build_client(agent))
+    }
+
+    /// Builds the client and checks its root key against the root key reported by the network,
+    /// or fetches the root key from the network for [RootKey::FetchForLocalDev].
+    pub async fn build_and_check_root_key(mut self) -> HttpGatewayResult<HttpGatewayClient> {
+        let (agent, url) = self.build_agent()?;
+        check_root_key(&agent, url.as_deref(), self.root_key.take()).await?;
+
+        Ok(self.build_client(agent))
+    }
+
+    /// Returns the agent and the URL that it was built with, `None` for a pre-built agent.
+    fn build_agent(&mut self) -> HttpGatewayResult<(Agent, Option<String>)> {
+        // the URL of a pre-built agent is unknown, `with_url` may not match it
+        if let Some(agent) = self.agent.take() {
+            return Ok((agent, None));
+        }
+
+        let url = self
+            .url
+            .take()
+            .unwrap_or_else(|| DEFAULT_BOUNDARY_NODE_ENDPOINT.to_string());
+        let agent = Agent::builder().with_url(&url).build()?;
+
+        Ok((agent, Some(url)))
+    }
+
+    fn build_client(mut self, agent: Agent) -> HttpGatewayClient {
+        self.response_verification_options
+            .verification_version_policy = Arc::new(VerificationVersionPolicy::new(
+            self.verification_versions,
+            self.canister_verification_versions,
+        ));
+
+        if let Some(denylist) = &self.denylist {
+            denylist.watch();
+        }
+
+        let rate_limiter = (self.query_rate_limit.is_some() || self.update_rate_limit.is_some())
+            .then(|| {
+                Arc::new(RateLimiter::new(
+                    self.rate_limit_key,
+                    self.query_rate_limit,
+                    self.update_rate_limit,
+                    self.response_verification_options.clock.clone(),
+                ))
+            });
+
+        let update_call_policy = (self.read_only_mode.is_some()
+            || !self.canister_read_only_modes.is_empty()
+            || !self.update_fallback_canisters.is_empty())
+        .then(|| {
+            Arc::new(UpdateCallPolicy::new(
+                self.read_only_mode,
+                self.canister_read_only_modes,
+                self.refused_upgrade_response,
+                self.update_fallback_canisters,
+            ))
+        });
 
-        Ok(HttpGatewayClient::new(HttpGatewayClientArgs { agent }))
+        HttpGatewayClient::new(HttpGatewayClientArgs {
+            agent,
+            response_verification_options: self.response_verification_options,
+            response_streaming_options: self.response_streaming_options,
+            request_coalescer: self
+                .request_coalescing
+                .then(|| Arc::new(RequestCoalescer::new())),
+            response_cache: (self.response_cache_capacity > 0).then(|| {
+                Arc::new(ResponseCache::new(
+                    self.response_cache_capacity,
+                    self.max_staleness,
+                    self.canister_max_staleness,
+                ))
+            }),
+            denylist: self.denylist,
+            rate_limiter,
+            update_call_policy,
+            delegation_authenticator: self.delegation_authenticator,
+            http_interface_resolver: (self.http_interface_discovery
+                || !self.canister_http_interfaces.is_empty())
+            .then(|| {
+                Arc::new(HttpInterfaceResolver::new(
+                    self.canister_http_interfaces,
+                    self.http_interface_discovery,
+                ))
+            }),
+        })
     }
 }
 
@@ -0,0 +1,342 @@
+use crate::{HttpGatewayError, HttpGatewayResult};
+use candid::{types::FuncMode, Principal};
+use candid_parser::utils::CandidSource;
+use ic_agent::{Agent, AgentError};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// The metadata section that holds the candid interface of a canister.
+const CANDID_SERVICE_METADATA: &str = "candid:service";
+
+/// How long the discovered interface of a canister is used before it is read again,
+/// so that canister upgrades are picked up.
+const DISCOVERY_TTL: Duration = Duration::from_secs(300);
+
+/// How long a failure to read the metadata of a canister is remembered,
+/// so that a replica that is unavailable is not asked again for every request.
+const FAILED_DISCOVERY_TTL: Duration = Duration::from_secs(5);
+
+/// The names of the methods that serve HTTP requests on a canister.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpInterface {
+    /// The query method that is called for every request.
+    pub http_request: String,
+
+    /// The update method that is called for upgraded requests,
+    /// `None` if the canister does not support update calls.
+    pub http_request_update: Option<String>,
+}
+
+impl HttpInterface {
+    pub fn new(http_request: impl Into<String>, http_request_update: Option<String>) -> Self {
+        Self {
+            http_request: http_request.into(),
+            http_request_update,
+        }
+    }
+}
+
+impl Default for HttpInterface {
+    fn default() -> Self {
+        Self::new("http_request", Some("http_request_update".to_string()))
+    }
+}
+
+/// The interface discovered for a canister, or why it could not be discovered, and until when it is used.
+type Discovery = (Instant, HttpGatewayResult<Arc<HttpInterface>>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MethodMode {
+    Query,
+    Update,
+}
+
+/// Resolves the [HttpInterface] of canisters, from the configuration of the client
+/// or from the `candid:service` metadata of the canisters.
+#[derive(Debug)]
+pub struct HttpInterfaceResolver {
+    default_http_interface: Arc<HttpInterface>,
+    canister_http_interfaces: HashMap<Principal, Arc<HttpInterface>>,
+    discovery: bool,
+    discovered: Mutex<HashMap<Principal, Discovery>>,
+}
+
+impl HttpInterfaceResolver {
+    /// With `discovery`, the methods are read from the `candid:service` metadata of canisters.
+    /// The configured interface of a canister is validated against its metadata, and canisters
+    /// without a configured interface use the latest `http_request` and `http_request_update`
+    /// methods that they declare, for example `http_request_v2` over `http_request`.
+    pub fn new(
+        canister_http_interfaces: HashMap<Principal, HttpInterface>,
+        discovery: bool,
+    ) -> Self {
+        Self {
+            default_http_interface: Arc::new(HttpInterface::default()),
+            canister_http_interfaces: canister_http_interfaces
+                .into_iter()
+                .map(|(canister_id, http_interface)| (canister_id, Arc::new(http_interface)))
+                .collect(),
+            discovery,
+            discovered: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) async fn resolve(
+        &self,
+        agent: &Agent,
+        canister_id: Principal,
+    ) -> HttpGatewayResult<Arc<HttpInterface>> {
+        let configured = self.canister_http_interfaces.get(&canister_id);
+        if !self.discovery {
+            return Ok(configured.unwrap_or(&self.default_http_interface).clone());
+        }
+
+        if let Some((expires_at, http_interface)) =
+            self.discovered.lock().unwrap().get(&canister_id)
+        {
+            if Instant::now() < *expires_at {
+                return http_interface.clone();
+            }
+        }
+
+        let (http_interface, ttl) = match agent
+            .read_state_canister_metadata(canister_id, CANDID_SERVICE_METADATA)
+            .await
+        {
+            Ok(candid) => (
+                discover(canister_id, candid, configured).map(Arc::new),
+                DISCOVERY_TTL,
+            ),
+            // a canister without the metadata only gets it with an upgrade
+            Err(e @ AgentError::LookupPathAbsent(_)) => (
+                Err(candid_interface_unavailable(canister_id, e)),
+                DISCOVERY_TTL,
+            ),
+            // other failures, such as transport errors, may be gone with the next request
+            Err(e) => (
+                Err(candid_interface_unavailable(canister_id, e)),
+                FAILED_DISCOVERY_TTL,
+            ),
+        };
+        self.discovered
+            .lock()
+            .unwrap()
+            .insert(canister_id, (Instant::now() + ttl, http_interface.clone()));
+
+        http_interface
+    }
+}
+
+fn candid_interface_unavailable(canister_id: Principal, e: AgentError) -> HttpGatewayError {
+    HttpGatewayError::CandidInterfaceUnavailable {
+        canister_id,
+        reason: e.to_string(),
+    }
+}
+
+/// Discovers the interface of a canister from its candid interface,
+/// or validates the configured interface against it.
+fn discover(
+    canister_id: Principal,
+    candid: Vec<u8>,
+    configured: Option<&Arc<HttpInterface>>,
+) -> HttpGatewayResult<HttpInterface> {
+    let methods = String::from_utf8(candid)
+        .map_err(|e| e.to_string())
+        .and_then(|candid| parse_candid_service(&candid))
+        .map_err(|reason| HttpGatewayError::CandidInterfaceUnavailable {
+            canister_id,
+            reason,
+        })?;
+
+    match configured {
+        Some(configured) => validate_http_interface(canister_id, configured, &methods)
+            .map(|_| configured.as_ref().clone()),
+        None => discover_http_interface(canister_id, &methods),
+    }
+}
+
+/// Returns the methods declared by the candid interface and their modes, except for oneway methods.
+fn parse_candid_service(candid: &str) -> Result<HashMap<String, MethodMode>, String> {
+    let (env, actor) = CandidSource::Text(candid)
+        .load()
+        .map_err(|e| e.to_string())?;
+    let actor = actor.ok_or("the candid interface does not declare a service")?;
+
+    let mut methods = HashMap::new();
+    for (name, ty) in env.as_service(&actor).map_err(|e| e.to_string())? {
+        let function = env.as_func(ty).map_err(|e| e.to_string())?;
+        let mode = if function.modes.is_empty() {
+            MethodMode::Update
+        } else if function
+            .modes
+            .iter()
+            .any(|mode| matches!(mode, FuncMode::Query | FuncMode::CompositeQuery))
+        {
+            MethodMode::Query
+        } else {
+            continue;
+        };
+        methods.insert(name.clone(), mode);
+    }
+
+    Ok(methods)
+}
+
+fn validate_http_interface(
+    canister_id: Principal,
+    http_interface: &HttpInterface,
+    methods: &HashMap<String, MethodMode>,
+) -> HttpGatewayResult<()> {
+    let declared = [
+        Some((&http_interface.http_request, MethodMode::Query)),
+        http_interface
+            .http_request_update
+            .as_ref()
+            .map(|http_request_update| (http_request_update, MethodMode::Update)),
+    ];
+    for (method, mode) in declared.into_iter().flatten() {
+        if methods.get(method) != Some(&mode) {
+            return Err(HttpGatewayError::CanisterMethodNotDeclared {
+                canister_id,
+                method: method.clone(),
+                query: mode == MethodMode::Query,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+fn discover_http_interface(
+    canister_id: Principal,
+    methods: &HashMap<String, MethodMode>,
+) -> HttpGatewayResult<HttpInterface> {
+    let http_request =
+        latest_method(methods, "http_request", MethodMode::Query).ok_or_else(|| {
+            HttpGatewayError::CanisterMethodNotDeclared {
+                canister_id,
+                method: "http_request".to_string(),
+                query: true,
+            }
+        })?;
+
+    Ok(HttpInterface::new(
+        http_request,
+        latest_method(methods, "http_request_update", MethodMode::Update),
+    ))
+}
+
+/// Returns the method with the highest version among `name` and `name_v<version>`.
+fn latest_method(
+    methods: &HashMap<String, MethodMode>,
+    name: &str,
+    mode: MethodMode,
+) -> Option<String> {
+    methods
+        .iter()
+        .filter(|(_, method_mode)| **method_mode == mode)
+        .filter_map(|(method, _)| {
+            let version = match method.strip_prefix(name)? {
+                "" => 1,
+                suffix => suffix.strip_prefix("_v")?.parse::<u32>().ok()?,
+            };
+
+            Some((version, method))
+        })
+        .max()
+        .map(|(_, method)| method.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::mock_replica::MockReplica;
+    use assert_matches::assert_matches;
+
+    const CANDID: &str = r#"
+        type HttpRequest = record { method : text; url : text; headers : vec record { text; text }; body : blob };
+        type HttpResponse = record { status_code : nat16; headers : vec record { text; text }; body : blob };
+
+        service : {
+            http_request : (HttpRequest) -> (HttpResponse) query;
+            http_request_v2 : (HttpRequest) -> (HttpResponse) composite_query;
+            http_request_update : (HttpRequest) -> (HttpResponse);
+            http_request_update_v2 : (HttpRequest) -> (HttpResponse) query;
+            http_request_vnext : (HttpRequest) -> (HttpResponse) query;
+            notify : () -> () oneway;
+        }
+    "#;
+
+    fn canister_id() -> Principal {
+        Principal::from_slice(&[1])
+    }
+
+    #[test]
+    fn should_discover_the_latest_http_methods() {
+        let methods = parse_candid_service(CANDID).unwrap();
+
+        assert_eq!(methods.get("notify"), None);
+        assert_eq!(
+            discover_http_interface(canister_id(), &methods).unwrap(),
+            HttpInterface::new("http_request_v2", Some("http_request_update".to_string()))
+        );
+    }
+
+    #[test]
+    fn should_validate_configured_methods_against_the_candid_interface() {
+        let methods = parse_candid_service(CANDID).unwrap();
+
+        assert_matches!(
+            validate_http_interface(
+                canister_id(),
+                &HttpInterface::new("http_request_v2", None),
+                &methods
+            ),
+            Ok(())
+        );
+        assert_matches!(
+            validate_http_interface(
+                canister_id(),
+                &HttpInterface::new("http_request", Some("http_request_update_v2".to_string())),
+                &methods
+            ),
+            Err(HttpGatewayError::CanisterMethodNotDeclared { method, query: false, .. }) if method == "http_request_update_v2"
+        );
+        assert_matches!(
+            validate_http_interface(canister_id(), &HttpInterface::new("http_request_v3", None), &methods),
+            Err(HttpGatewayError::CanisterMethodNotDeclared { method, query: true, .. }) if method == "http_request_v3"
+        );
+    }
+
+    #[tokio::test]
+    async fn should_not_remember_transport_errors_for_long() {
+        // the replica does not serve read state requests
+        let replica = Arc::new(MockReplica::default());
+        let agent = replica.agent();
+        let resolver = HttpInterfaceResolver::new(HashMap::new(), true);
+
+        assert_matches!(
+            resolver.resolve(&agent, canister_id()).await,
+            Err(HttpGatewayError::CandidInterfaceUnavailable { .. })
+        );
+
+        let discovered = resolver.discovered.lock().unwrap();
+        let (expires_at, _) = discovered.get(&canister_id()).unwrap();
+        assert!(*expires_at <= Instant::now() + FAILED_DISCOVERY_TTL);
+    }
+
+    #[test]
+    fn should_fail_to_discover_canisters_without_http_methods() {
+        let methods = parse_candid_service("service : { greet : (text) -> (text) query }").unwrap();
+
+        assert_matches!(
+            discover_http_interface(canister_id(), &methods),
+            Err(HttpGatewayError::CanisterMethodNotDeclared { query: true, .. })
+        );
+        assert_matches!(parse_candid_service("type T = nat;"), Err(_));
+    }
+}
@@ -3,3 +3,51 @@ pub use http_gateway_client::*;
 
 mod http_gateway_client_builder;
 pub use http_gateway_client_builder::*;
+
+mod root_key;
+pub use root_key::*;
+
+mod clock;
+pub use clock::*;
+
+mod response_verification_options;
+pub use response_verification_options::*;
+
+mod response_streaming_options;
+pub use response_streaming_options::*;
+
+mod verification_pool;
+pub use verification_pool::*;
+
+mod verification_cache;
+pub use verification_cache::*;
+
+mod shared_response;
+pub(crate) use shared_response::*;
+
+mod request_coalescer;
+pub use request_coalescer::*;
+
+mod response_cache;
+pub use response_cache::*;
+
+mod denylist;
+pub use denylist::*;
+
+mod rate_limiter;
+pub use rate_limiter::*;
+
+mod update_call_policy;
+pub use update_call_policy::*;
+
+mod delegation_authenticator;
+pub use delegation_authenticator::*;
+
+mod http_interface;
+pub use http_interface::*;
+
+mod verification_version_policy;
+pub use verification_version_policy::*;
+
+mod v1_header_policy;
+pub use v1_header_policy::*;
@@ -0,0 +1,555 @@
+use crate::{Clock, HttpGatewayResponse, HttpGatewayResponseBody, HttpGatewayResponseMetadata};
+use bytes::Bytes;
+use candid::Principal;
+use http::{header, HeaderValue, Response, StatusCode};
+use http_body_util::Full;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::{Debug, Formatter},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+/// The number of buckets above which the least recently used buckets are evicted.
+const MAX_BUCKETS: usize = 100_000;
+
+/// A token bucket budget.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    /// The number of requests that are allowed per second on average.
+    /// A rate of 0 only allows a single burst, negative and NaN rates are treated as 0.
+    pub requests_per_second: f64,
+
+    /// The number of requests that are allowed in a burst.
+    pub burst: u32,
+}
+
+impl RateLimit {
+    pub fn new(requests_per_second: f64, burst: u32) -> Self {
+        Self {
+            requests_per_second,
+            burst,
+        }
+    }
+
+    fn refill_rate(&self) -> f64 {
+        // `f64::max` ignores NaN
+        self.requests_per_second.max(0.0)
+    }
+}
+
+/// What the token buckets of a [RateLimiter] are keyed by.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RateLimitKey {
+    /// One bucket per client IP address.
+    /// Requests without a client IP address share a single bucket.
+    #[default]
+    ClientIp,
+
+    /// One bucket per canister.
+    Canister,
+
+    /// One bucket per client IP address and canister.
+    ClientIpAndCanister,
+}
+
+/// The kind of call that a budget of a [RateLimiter] applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RateLimitedCall {
+    /// Every request, which starts out as a query call.
+    Query,
+
+    /// Queries that the canister upgraded to an update call, which go through consensus.
+    Update,
+}
+
+/// The state of the token bucket that a request was checked against.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimitStatus {
+    /// The budget that the request was checked against.
+    pub call: RateLimitedCall,
+
+    /// The size of the bucket.
+    pub limit: u32,
+
+    /// The number of whole tokens left in the bucket.
+    pub remaining: u32,
+
+    /// How long until the next request is allowed, if this request was refused.
+    pub retry_after: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct BucketKey {
+    call: RateLimitedCall,
+    client_ip: Option<IpAddr>,
+    canister_id: Option<Principal>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TokenBucket {
+    tokens: f64,
+    updated_at: SystemTime,
+    last_use: u64,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, rate_limit: &RateLimit, now: SystemTime) {
+        let elapsed = now.duration_since(self.updated_at).unwrap_or_default();
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * rate_limit.refill_rate())
+            .min(f64::from(rate_limit.burst));
+        self.updated_at = now;
+    }
+}
+
+/// Token buckets, bounded by evicting the least recently used ones.
+#[derive(Debug, Default)]
+struct TokenBuckets {
+    buckets: HashMap<BucketKey, TokenBucket>,
+    /// Every use of a bucket, oldest first. Uses that were followed by a later use
+    /// of the same bucket are skipped on eviction and dropped once the queue grows too long.
+    usage_order: VecDeque<(BucketKey, u64)>,
+    next_use: u64,
+}
+
+impl TokenBuckets {
+    /// Returns the bucket with the given key, evicting the least recently used buckets
+    /// to make room for it if necessary.
+    fn use_bucket(
+        &mut self,
+        key: BucketKey,
+        max_buckets: usize,
+        new_bucket: impl FnOnce() -> TokenBucket,
+    ) -> &mut TokenBucket {
+        if !self.buckets.contains_key(&key) {
+            while self.buckets.len() >= max_buckets {
+                let Some((oldest_key, oldest_use)) = self.usage_order.pop_front() else {
+                    break;
+                };
+                if self
+                    .buckets
+                    .get(&oldest_key)
+                    .is_some_and(|bucket| bucket.last_use == oldest_use)
+                {
+                    self.buckets.remove(&oldest_key);
+                }
+            }
+        }
+
+        if self.usage_order.len() >= 2 * max_buckets {
+            // amortized over the uses that were pushed since the last time
+            let buckets = &self.buckets;
+            self.usage_order.retain(|(key, last_use)| {
+                buckets
+                    .get(key)
+                    .is_some_and(|bucket| bucket.last_use == *last_use)
+            });
+        }
+        let last_use = self.next_use;
+        self.next_use += 1;
+        self.usage_order.push_back((key, last_use));
+
+        let bucket = self.buckets.entry(key).or_insert_with(new_bucket);
+        bucket.last_use = last_use;
+        bucket
+    }
+}
+
+/// Limits the rate of requests with token buckets, keyed by client IP address, canister, or both.
+///
+/// Every request takes a token from its query budget. Queries that the canister upgrades to
+/// an update call additionally take a token from the update budget, which is usually much smaller.
+/// Requests that exceed a budget are refused with `429 Too Many Requests` and a `Retry-After` header.
+pub struct RateLimiter {
+    key: RateLimitKey,
+    query_rate_limit: Option<RateLimit>,
+    update_rate_limit: Option<RateLimit>,
+    clock: Arc<dyn Clock>,
+    max_buckets: usize,
+    buckets: Mutex<TokenBuckets>,
+    limited_queries: AtomicU64,
+    limited_update_calls: AtomicU64,
+}
+
+impl Debug for RateLimiter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RateLimiter")
+            .field("key", &self.key)
+            .field("query_rate_limit", &self.query_rate_limit)
+            .field("update_rate_limit", &self.update_rate_limit)
+            .field("buckets", &self.buckets.lock().unwrap().buckets.len())
+            .field("limited_queries", &self.limited_queries())
+            .field("limited_update_calls", &self.limited_update_calls())
+            .finish()
+    }
+}
+
+impl RateLimiter {
+    pub fn new(
+        key: RateLimitKey,
+        query_rate_limit: Option<RateLimit>,
+        update_rate_limit: Option<RateLimit>,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        Self {
+            key,
+            query_rate_limit,
+            update_rate_limit,
+            clock,
+            max_buckets: MAX_BUCKETS,
+            buckets: Mutex::new(TokenBuckets::default()),
+            limited_queries: AtomicU64::new(0),
+            limited_update_calls: AtomicU64::new(0),
+        }
+    }
+
+    /// The number of requests that were refused because they exceeded the query budget.
+    pub fn limited_queries(&self) -> u64 {
+        self.limited_queries.load(Ordering::Relaxed)
+    }
+
+    /// The number of update calls that were refused because they exceeded the update budget.
+    pub fn limited_update_calls(&self) -> u64 {
+        self.limited_update_calls.load(Ordering::Relaxed)
+    }
+
+    /// Takes a token from the budget of the call, if there is a budget for it.
+    pub fn check(
+        &self,
+        call: RateLimitedCall,
+        client_ip: Option<IpAddr>,
+        canister_id: Principal,
+    ) -> Option<RateLimitStatus> {
+        let rate_limit = match call {
+            RateLimitedCall::Query => self.query_rate_limit?,
+            RateLimitedCall::Update => self.update_rate_limit?,
+        };
+        let key = BucketKey {
+            call,
+            client_ip: (self.key != RateLimitKey::Canister)
+                .then_some(client_ip)
+                .flatten(),
+            canister_id: (self.key != RateLimitKey::ClientIp).then_some(canister_id),
+        };
+        let now = self.clock.now();
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.use_bucket(key, self.max_buckets, || TokenBucket {
+            tokens: f64::from(rate_limit.burst),
+            updated_at: now,
+            last_use: 0,
+        });
+        bucket.refill(&rate_limit, now);
+
+        let retry_after = if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            None
+        } else {
+            match call {
+                RateLimitedCall::Query => self.limited_queries.fetch_add(1, Ordering::Relaxed),
+                RateLimitedCall::Update => {
+                    self.limited_update_calls.fetch_add(1, Ordering::Relaxed)
+                }
+            };
+
+            // a bucket that is never refilled never allows another request
+            Some(
+                Duration::try_from_secs_f64((1.0 - bucket.tokens) / rate_limit.refill_rate())
+                    .unwrap_or(Duration::MAX),
+            )
+        };
+
+        Some(RateLimitStatus {
+            call,
+            limit: rate_limit.burst,
+            remaining: bucket.tokens as u32,
+            retry_after,
+        })
+    }
+}
+
+/// The rate limiter and client that a request is checked against.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RateLimitContext<'a> {
+    pub rate_limiter: &'a RateLimiter,
+    pub client_ip: Option<IpAddr>,
+}
+
+impl RateLimitContext<'_> {
+    /// Takes a token from the budget of the call,
+    /// or returns the status of the budget as an error if it is exceeded.
+    pub fn check(
+        &self,
+        call: RateLimitedCall,
+        canister_id: Principal,
+    ) -> Result<Option<RateLimitStatus>, RateLimitStatus> {
+        match self.rate_limiter.check(call, self.client_ip, canister_id) {
+            Some(status) if status.retry_after.is_some() => Err(status),
+            status => Ok(status),
+        }
+    }
+}
+
+/// Creates the `429 Too Many Requests` response to a request that exceeded its budget.
+pub(crate) fn create_rate_limited_response(status: RateLimitStatus) -> HttpGatewayResponse {
+    let mut canister_response = Response::new(HttpGatewayResponseBody::Right(Full::new(
+        Bytes::from_static(b"Too Many Requests"),
+    )));
+    *canister_response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+    if let Some(retry_after) = status.retry_after {
+        // round up, so that clients do not retry too early
+        let retry_after_secs = retry_after
+            .as_secs()
+            .saturating_add(u64::from(retry_after.subsec_nanos() > 0));
+        canister_response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(retry_after_secs));
+    }
+
+    HttpGatewayResponse {
+        canister_response,
+        metadata: HttpGatewayResponseMetadata {
+            upgraded_to_update_call: status.call == RateLimitedCall::Update,
+            rate_limit: Some(status),
+            ..Default::default()
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::assert_matches;
+    use std::{net::Ipv4Addr, time::UNIX_EPOCH};
+
+    #[derive(Debug)]
+    struct FixedClock(Mutex<SystemTime>);
+
+    impl Clock for FixedClock {
+        fn now(&self) -> SystemTime {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    fn client_ip(last_octet: u8) -> Option<IpAddr> {
+        Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, last_octet)))
+    }
+
+    fn canister_id(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    fn rate_limiter(key: RateLimitKey) -> (RateLimiter, Arc<FixedClock>) {
+        let clock = Arc::new(FixedClock(Mutex::new(UNIX_EPOCH)));
+        let rate_limiter = RateLimiter::new(
+            key,
+            Some(RateLimit::new(10.0, 3)),
+            Some(RateLimit::new(0.5, 1)),
+            clock.clone(),
+        );
+
+        (rate_limiter, clock)
+    }
+
+    #[test]
+    fn should_refuse_requests_once_the_budget_is_exhausted() {
+        let (rate_limiter, clock) = rate_limiter(RateLimitKey::ClientIp);
+
+        for remaining in [2, 1, 0] {
+            assert_eq!(
+                rate_limiter.check(RateLimitedCall::Query, client_ip(1), canister_id(1)),
+                Some(RateLimitStatus {
+                    call: RateLimitedCall::Query,
+                    limit: 3,
+                    remaining,
+                    retry_after: None,
+                })
+            );
+        }
+        assert_matches!(
+            rate_limiter.check(RateLimitedCall::Query, client_ip(1), canister_id(2)),
+            Some(RateLimitStatus {
+                remaining: 0,
+                retry_after: Some(retry_after),
+                ..
+            }) if retry_after == Duration::from_millis(100)
+        );
+        assert_eq!(rate_limiter.limited_queries(), 1);
+
+        // other clients have their own budget
+        assert_matches!(
+            rate_limiter.check(RateLimitedCall::Query, client_ip(2), canister_id(1)),
+            Some(RateLimitStatus {
+                retry_after: None,
+                ..
+            })
+        );
+
+        // the budget refills over time
+        *clock.0.lock().unwrap() += Duration::from_millis(100);
+        assert_matches!(
+            rate_limiter.check(RateLimitedCall::Query, client_ip(1), canister_id(1)),
+            Some(RateLimitStatus {
+                retry_after: None,
+                ..
+            })
+        );
+    }
+
+    #[test]
+    fn should_limit_update_calls_separately() {
+        let (rate_limiter, _) = rate_limiter(RateLimitKey::ClientIpAndCanister);
+
+        assert_matches!(
+            rate_limiter.check(RateLimitedCall::Update, client_ip(1), canister_id(1)),
+            Some(RateLimitStatus {
+                retry_after: None,
+                ..
+            })
+        );
+        assert_matches!(
+            rate_limiter.check(RateLimitedCall::Update, client_ip(1), canister_id(1)),
+            Some(RateLimitStatus {
+                retry_after: Some(retry_after),
+                ..
+            }) if retry_after == Duration::from_secs(2)
+        );
+        assert_matches!(
+            rate_limiter.check(RateLimitedCall::Update, client_ip(1), canister_id(2)),
+            Some(RateLimitStatus {
+                retry_after: None,
+                ..
+            })
+        );
+        assert_matches!(
+            rate_limiter.check(RateLimitedCall::Query, client_ip(1), canister_id(1)),
+            Some(RateLimitStatus {
+                retry_after: None,
+                ..
+            })
+        );
+        assert_eq!(rate_limiter.limited_update_calls(), 1);
+        assert_eq!(rate_limiter.limited_queries(), 0);
+    }
+
+    #[test]
+    fn should_key_buckets_by_canister() {
+        let (rate_limiter, _) = rate_limiter(RateLimitKey::Canister);
+
+        rate_limiter.check(RateLimitedCall::Update, client_ip(1), canister_id(1));
+
+        assert_matches!(
+            rate_limiter.check(RateLimitedCall::Update, client_ip(2), canister_id(1)),
+            Some(RateLimitStatus {
+                retry_after: Some(_),
+                ..
+            })
+        );
+    }
+
+    #[test]
+    fn should_evict_the_least_recently_used_buckets() {
+        let (mut rate_limiter, _) = rate_limiter(RateLimitKey::ClientIp);
+        rate_limiter.max_buckets = 2;
+        let remaining = |client| {
+            rate_limiter
+                .check(RateLimitedCall::Query, client_ip(client), canister_id(1))
+                .unwrap()
+                .remaining
+        };
+
+        assert_eq!(remaining(1), 2);
+        assert_eq!(remaining(2), 2);
+        assert_eq!(remaining(1), 1);
+        // evicts the bucket of the second client, which was used least recently
+        assert_eq!(remaining(3), 2);
+        assert_eq!(remaining(1), 0);
+        assert_eq!(remaining(2), 2);
+
+        // rotating keys does not grow the buckets beyond the limit
+        for client in 0..=u8::MAX {
+            remaining(client);
+        }
+        let buckets = rate_limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.buckets.len(), 2);
+        assert!(buckets.usage_order.len() <= 4);
+    }
+
+    #[test]
+    fn should_not_refill_budgets_without_a_valid_rate() {
+        for requests_per_second in [0.0, -1.0, f64::NAN, f64::NEG_INFINITY] {
+            let clock = Arc::new(FixedClock(Mutex::new(UNIX_EPOCH)));
+            let rate_limiter = RateLimiter::new(
+                RateLimitKey::ClientIp,
+                Some(RateLimit::new(requests_per_second, 1)),
+                None,
+                clock.clone(),
+            );
+            let context = RateLimitContext {
+                rate_limiter: &rate_limiter,
+                client_ip: client_ip(1),
+            };
+
+            assert_matches!(
+                context.check(RateLimitedCall::Query, canister_id(1)),
+                Ok(Some(_))
+            );
+            *clock.0.lock().unwrap() += Duration::from_secs(3600);
+            let status = context
+                .check(RateLimitedCall::Query, canister_id(1))
+                .unwrap_err();
+            assert_eq!(status.retry_after, Some(Duration::MAX));
+
+            let response = create_rate_limited_response(status);
+            assert_eq!(
+                response
+                    .canister_response
+                    .headers()
+                    .get(header::RETRY_AFTER),
+                Some(&HeaderValue::from(u64::MAX))
+            );
+        }
+    }
+
+    #[test]
+    fn should_respond_with_retry_after() {
+        let (rate_limiter, _) = rate_limiter(RateLimitKey::ClientIp);
+        let context = RateLimitContext {
+            rate_limiter: &rate_limiter,
+            client_ip: client_ip(1),
+        };
+
+        assert_matches!(
+            context.check(RateLimitedCall::Update, canister_id(1)),
+            Ok(Some(_))
+        );
+        let status = context
+            .check(RateLimitedCall::Update, canister_id(1))
+            .unwrap_err();
+        let response = create_rate_limited_response(status);
+
+        assert_eq!(
+            response.canister_response.status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(
+            response
+                .canister_response
+                .headers()
+                .get(header::RETRY_AFTER),
+            Some(&HeaderValue::from(2))
+        );
+        assert!(response.metadata.upgraded_to_update_call);
+        assert_matches!(
+            response.metadata.rate_limit,
+            Some(RateLimitStatus {
+                call: RateLimitedCall::Update,
+                ..
+            })
+        );
+    }
+}
@@ -0,0 +1,356 @@
+use super::{is_private_response, RequestKey, SharedResponse};
+use crate::{CanisterRequest, HttpGatewayResponse};
+use candid::Principal;
+use futures::{
+    channel::oneshot,
+    future::{FutureExt, Shared},
+};
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Formatter},
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+type InFlightResponse = Shared<oneshot::Receiver<Option<SharedResponse>>>;
+
+/// Coalesces identical concurrent requests, so that a single canister call and verification
+/// serves every request that arrives while it is in flight.
+///
+/// Only `GET` and `HEAD` requests without credentials are coalesced. Requests are identical if
+/// they are made to the same canister with the same URL, headers and body. Responses with
+/// streamed bodies and private responses, such as those setting cookies, are not shared,
+/// requests waiting on them are made on their own instead.
+pub struct RequestCoalescer {
+    in_flight_requests: Mutex<HashMap<RequestKey, InFlightResponse>>,
+    coalesced_requests: AtomicU64,
+}
+
+impl Debug for RequestCoalescer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RequestCoalescer")
+            .field("in_flight_requests", &self.in_flight_requests())
+            .field("coalesced_requests", &self.coalesced_requests())
+            .finish()
+    }
+}
+
+impl RequestCoalescer {
+    pub fn new() -> Self {
+        Self {
+            in_flight_requests: Mutex::new(HashMap::new()),
+            coalesced_requests: AtomicU64::new(0),
+        }
+    }
+
+    /// The number of distinct requests currently in flight.
+    pub fn in_flight_requests(&self) -> usize {
+        self.in_flight_requests.lock().unwrap().len()
+    }
+
+    /// The number of requests that were served by the response to another request.
+    pub fn coalesced_requests(&self) -> u64 {
+        self.coalesced_requests.load(Ordering::Relaxed)
+    }
+
+    /// Serves the request with the response of an identical request that is in flight,
+    /// or makes the request with `process` and shares its response with identical requests
+    /// that arrive in the meantime.
+    pub(crate) async fn coalesce<F, Fut>(
+        &self,
+        canister_id: Principal,
+        request: CanisterRequest,
+        skip_verification: bool,
+        process: F,
+    ) -> HttpGatewayResponse
+    where
+        F: FnOnce(CanisterRequest) -> Fut,
+        Fut: Future<Output = HttpGatewayResponse>,
+    {
+        let Some(key) = RequestKey::new(canister_id, &request, skip_verification) else {
+            return process(request).await;
+        };
+
+        let in_flight_response = {
+            let mut in_flight_requests = self.in_flight_requests.lock().unwrap();
+            match in_flight_requests.get(&key) {
+                Some(in_flight_response) => Ok(in_flight_response.clone()),
+                None => {
+                    let (sender, receiver) = oneshot::channel();
+                    in_flight_requests.insert(key.clone(), receiver.shared());
+                    Err(sender)
+                }
+            }
+        };
+
+        match in_flight_response {
+            Ok(in_flight_response) => {
+                if let Ok(Some(response)) = in_flight_response.await {
+                    self.coalesced_requests.fetch_add(1, Ordering::Relaxed);
+
+                    return response.into_response();
+                }
+
+                // the request in flight was cancelled, or its response is private or streamed
+                process(request).await
+            }
+            Err(sender) => {
+                // if this request is cancelled, the sender is dropped and waiting requests are made on their own
+                let _in_flight_guard = InFlightGuard {
+                    coalescer: self,
+                    key,
+                };
+
+                let response = process(request).await;
+                if is_private_response(&response) {
+                    let _ = sender.send(None);
+                    return response;
+                }
+
+                match SharedResponse::try_from_response(response).await {
+                    Ok(response) => {
+                        let _ = sender.send(Some(response.clone()));
+                        response.into_response()
+                    }
+                    Err(response) => {
+                        let _ = sender.send(None);
+                        response
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Default for RequestCoalescer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Removes a request from the requests in flight once it completes or is cancelled.
+struct InFlightGuard<'a> {
+    coalescer: &'a RequestCoalescer,
+    key: RequestKey,
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.coalescer
+            .in_flight_requests
+            .lock()
+            .unwrap()
+            .remove(&self.key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{HttpGatewayResponseBody, HttpGatewayResponseMetadata, ResponseBodyStream};
+    use bytes::Bytes;
+    use futures::{future::join_all, stream};
+    use http::{header, Method, Request, Response};
+    use http_body_util::{BodyExt, Full};
+    use std::{sync::atomic::AtomicUsize, time::Duration};
+
+    fn canister_id() -> Principal {
+        Principal::from_slice(&[1, 2, 3, 4])
+    }
+
+    fn metadata() -> HttpGatewayResponseMetadata {
+        HttpGatewayResponseMetadata {
+            response_verification_version: Some(2),
+            ..Default::default()
+        }
+    }
+
+    async fn coalesce_concurrently(
+        coalescer: &RequestCoalescer,
+        requests: Vec<CanisterRequest>,
+        body: HttpGatewayResponseBody,
+    ) -> (usize, Vec<HttpGatewayResponse>) {
+        let process_count = AtomicUsize::new(0);
+        let body = Mutex::new(Some(body));
+        let process = |_| async {
+            process_count.fetch_add(1, Ordering::Relaxed);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+
+            let body = body
+                .lock()
+                .unwrap()
+                .take()
+                .unwrap_or_else(|| HttpGatewayResponseBody::Right(Full::new(Bytes::new())));
+            HttpGatewayResponse {
+                canister_response: Response::new(body),
+                metadata: metadata(),
+            }
+        };
+
+        let responses = join_all(
+            requests
+                .into_iter()
+                .map(|request| coalescer.coalesce(canister_id(), request, false, process)),
+        )
+        .await;
+
+        (process_count.load(Ordering::Relaxed), responses)
+    }
+
+    async fn collect_body(response: HttpGatewayResponse) -> Bytes {
+        response
+            .canister_response
+            .into_body()
+            .collect()
+            .await
+            .unwrap()
+            .to_bytes()
+    }
+
+    #[tokio::test]
+    async fn should_serve_identical_requests_with_one_call() {
+        let coalescer = RequestCoalescer::new();
+        let requests = vec![
+            Request::get("/index.html")
+                .header("accept", "text/html")
+                .header("x-request-id", "1")
+                .body(Bytes::new())
+                .unwrap(),
+            Request::get("/index.html")
+                .header("x-request-id", "2")
+                .header("accept", "text/html")
+                .body(Bytes::new())
+                .unwrap(),
+        ];
+
+        let (process_count, responses) = coalesce_concurrently(
+            &coalescer,
+            requests,
+            HttpGatewayResponseBody::Right(Full::new(Bytes::from_static(b"hello"))),
+        )
+        .await;
+
+        assert_eq!(process_count, 1);
+        assert_eq!(coalescer.coalesced_requests(), 1);
+        assert_eq!(coalescer.in_flight_requests(), 0);
+        for response in responses {
+            assert_eq!(response.metadata.response_verification_version, Some(2));
+            assert_eq!(collect_body(response).await, Bytes::from_static(b"hello"));
+        }
+    }
+
+    #[tokio::test]
+    async fn should_not_coalesce_different_requests() {
+        let coalescer = RequestCoalescer::new();
+        let requests = vec![
+            Request::get("/index.html").body(Bytes::new()).unwrap(),
+            Request::get("/index.html?lang=en")
+                .body(Bytes::new())
+                .unwrap(),
+            Request::get("/index.html")
+                .header("accept-language", "en")
+                .body(Bytes::new())
+                .unwrap(),
+        ];
+
+        let (process_count, _) = coalesce_concurrently(
+            &coalescer,
+            requests,
+            HttpGatewayResponseBody::Right(Full::new(Bytes::new())),
+        )
+        .await;
+
+        assert_eq!(process_count, 3);
+        assert_eq!(coalescer.coalesced_requests(), 0);
+    }
+
+    #[tokio::test]
+    async fn should_not_coalesce_requests_with_credentials_or_side_effects() {
+        for (method, header_name) in [
+            (Method::GET, Some(header::AUTHORIZATION)),
+            (Method::GET, Some(header::COOKIE)),
+            (Method::POST, None),
+        ] {
+            let coalescer = RequestCoalescer::new();
+            let request = || {
+                let mut request = Request::builder().method(method.clone()).uri("/profile");
+                if let Some(header_name) = &header_name {
+                    request = request.header(header_name, "secret");
+                }
+                request.body(Bytes::new()).unwrap()
+            };
+
+            let (process_count, _) = coalesce_concurrently(
+                &coalescer,
+                vec![request(), request()],
+                HttpGatewayResponseBody::Right(Full::new(Bytes::new())),
+            )
+            .await;
+
+            assert_eq!(process_count, 2);
+            assert_eq!(coalescer.coalesced_requests(), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn should_not_share_streamed_bodies() {
+        let coalescer = RequestCoalescer::new();
+        let request = || Request::get("/video.mp4").body(Bytes::new()).unwrap();
+        let body_stream = ResponseBodyStream::new(Box::pin(stream::empty()));
+
+        let (process_count, _) = coalesce_concurrently(
+            &coalescer,
+            vec![request(), request()],
+            HttpGatewayResponseBody::Left(body_stream),
+        )
+        .await;
+
+        assert_eq!(process_count, 2);
+        assert_eq!(coalescer.coalesced_requests(), 0);
+        assert_eq!(coalescer.in_flight_requests(), 0);
+    }
+
+    #[tokio::test]
+    async fn should_not_share_responses_that_set_cookies() {
+        let coalescer = RequestCoalescer::new();
+        let process_count = AtomicUsize::new(0);
+        let process = |_| async {
+            let count = process_count.fetch_add(1, Ordering::Relaxed) + 1;
+            tokio::time::sleep(Duration::from_millis(20)).await;
+
+            let mut canister_response =
+                Response::new(HttpGatewayResponseBody::Right(Full::new(Bytes::new())));
+            canister_response.headers_mut().insert(
+                header::SET_COOKIE,
+                format!("session={count}").parse().unwrap(),
+            );
+            HttpGatewayResponse {
+                canister_response,
+                metadata: metadata(),
+            }
+        };
+
+        let responses = join_all((0..2).map(|_| {
+            coalescer.coalesce(
+                canister_id(),
+                Request::get("/index.html").body(Bytes::new()).unwrap(),
+                false,
+                process,
+            )
+        }))
+        .await;
+
+        assert_eq!(process_count.load(Ordering::Relaxed), 2);
+        assert_eq!(coalescer.coalesced_requests(), 0);
+        assert_eq!(coalescer.in_flight_requests(), 0);
+        let mut cookies = responses
+            .iter()
+            .map(|response| response.canister_response.headers()[header::SET_COOKIE].clone())
+            .collect::<Vec<_>>();
+        cookies.sort();
+        assert_eq!(cookies, ["session=1", "session=2"]);
+    }
+}
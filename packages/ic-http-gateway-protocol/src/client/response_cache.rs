@@ -0,0 +1,711 @@
+use super::{is_private_response, RequestKey, SharedResponse};
+use crate::{CacheStatus, CanisterRequest, Clock, HttpGatewayResponse};
+use candid::Principal;
+use http::{header, HeaderValue, Request};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::{Debug, Formatter},
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+pub(crate) const DEFAULT_MAX_STALENESS: Duration = Duration::from_secs(300);
+
+/// The directives of a `Cache-Control` header that determine how long a response may be served from the cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct CacheControl {
+    max_age: Option<Duration>,
+    stale_while_revalidate: Option<Duration>,
+    stale_if_error: Option<Duration>,
+    no_store: bool,
+}
+
+impl CacheControl {
+    fn parse<'a>(header_values: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut cache_control = Self::default();
+        let mut shared_max_age = None;
+
+        for directive in header_values.into_iter().flat_map(|value| value.split(',')) {
+            let (name, value) = match directive.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (directive.trim(), None),
+            };
+            let seconds = value
+                .and_then(|value| value.parse().ok())
+                .map(Duration::from_secs);
+
+            match name.to_ascii_lowercase().as_str() {
+                "max-age" => cache_control.max_age = seconds,
+                "s-maxage" => shared_max_age = seconds,
+                "stale-while-revalidate" => cache_control.stale_while_revalidate = seconds,
+                "stale-if-error" => cache_control.stale_if_error = seconds,
+                // responses that must be revalidated are never served from the cache
+                "no-store" | "no-cache" | "private" => cache_control.no_store = true,
+                _ => {}
+            }
+        }
+
+        // the gateway is a shared cache
+        cache_control.max_age = shared_max_age.or(cache_control.max_age);
+        cache_control
+    }
+}
+
+struct ResponseCacheEntry {
+    response: SharedResponse,
+    stored_at: SystemTime,
+    cache_control: CacheControl,
+    revalidating: bool,
+}
+
+#[derive(Default)]
+struct ResponseCacheEntries {
+    entries: HashMap<RequestKey, ResponseCacheEntry>,
+    insertion_order: VecDeque<RequestKey>,
+}
+
+/// A bounded cache of verified responses with full bodies.
+///
+/// Responses are cached according to their certified `Cache-Control` header, so only responses
+/// verified with response verification v2 or later are cached. Stale responses are served while they
+/// are refreshed in the background if allowed by `stale-while-revalidate`, and when the canister
+/// cannot be reached if allowed by `stale-if-error`, but never for longer than the maximum staleness
+/// configured for their canister.
+///
+/// Responses that are private to a user, because they set cookies or are marked `private`, are never cached.
+pub struct ResponseCache {
+    capacity: usize,
+    max_staleness: Duration,
+    canister_max_staleness: HashMap<Principal, Duration>,
+    entries: Mutex<ResponseCacheEntries>,
+    hits: AtomicU64,
+    stale_hits: AtomicU64,
+}
+
+impl Debug for ResponseCache {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ResponseCache")
+            .field("capacity", &self.capacity)
+            .field("max_staleness", &self.max_staleness)
+            .field("canister_max_staleness", &self.canister_max_staleness)
+            .field("len", &self.len())
+            .field("hits", &self.hits())
+            .field("stale_hits", &self.stale_hits())
+            .finish()
+    }
+}
+
+enum CacheLookup {
+    Fresh(SharedResponse, Duration),
+    StaleWhileRevalidate(SharedResponse, Duration, bool),
+    Miss,
+}
+
+impl ResponseCache {
+    pub fn new(
+        capacity: usize,
+        max_staleness: Duration,
+        canister_max_staleness: HashMap<Principal, Duration>,
+    ) -> Self {
+        Self {
+            capacity,
+            max_staleness,
+            canister_max_staleness,
+            entries: Mutex::new(ResponseCacheEntries::default()),
+            hits: AtomicU64::new(0),
+            stale_hits: AtomicU64::new(0),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of fresh responses that were served from the cache.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// The number of stale responses that were served from the cache.
+    pub fn stale_hits(&self) -> u64 {
+        self.stale_hits.load(Ordering::Relaxed)
+    }
+
+    /// How long the responses of a canister may be served after they became stale.
+    pub fn max_staleness(&self, canister_id: &Principal) -> Duration {
+        self.canister_max_staleness
+            .get(canister_id)
+            .copied()
+            .unwrap_or(self.max_staleness)
+    }
+
+    /// Serves the request from the cache if possible, otherwise makes the request with `process`.
+    ///
+    /// Stale responses that may still be served are refreshed by spawning `revalidate`
+    /// on the tokio runtime, at most once at a time.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) async fn serve<P, PFut, R, RFut>(
+        self: &Arc<Self>,
+        canister_id: Principal,
+        request: CanisterRequest,
+        skip_verification: bool,
+        clock: &Arc<dyn Clock>,
+        process: P,
+        revalidate: R,
+    ) -> HttpGatewayResponse
+    where
+        P: FnOnce(CanisterRequest) -> PFut,
+        PFut: Future<Output = HttpGatewayResponse>,
+        R: FnOnce(CanisterRequest) -> RFut + Send + 'static,
+        RFut: Future<Output = HttpGatewayResponse> + Send + 'static,
+    {
+        // unverified responses are never cached
+        let key = RequestKey::new(canister_id, &request, skip_verification)
+            .filter(|_| !skip_verification);
+        let Some(key) = key else {
+            return process(request).await;
+        };
+
+        match self.lookup(&key, clock.now()) {
+            CacheLookup::Fresh(response, age) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+
+                return into_cached_response(response, age, CacheStatus::Fresh);
+            }
+            CacheLookup::StaleWhileRevalidate(response, age, should_revalidate) => {
+                self.stale_hits.fetch_add(1, Ordering::Relaxed);
+                if should_revalidate {
+                    let response_cache = Arc::clone(self);
+                    let clock = Arc::clone(clock);
+                    let revalidation_request = clone_request(&request);
+
+                    tokio::spawn(async move {
+                        let response = revalidate(revalidation_request).await;
+                        response_cache.store(key, response, clock.now()).await;
+                    });
+                }
+
+                return into_cached_response(response, age, CacheStatus::StaleWhileRevalidate);
+            }
+            CacheLookup::Miss => {}
+        }
+
+        let response = process(request).await;
+        let now = clock.now();
+
+        if response.canister_response.status().is_server_error() {
+            if let Some((stale_response, age)) = self.lookup_stale_if_error(&key, now) {
+                self.stale_hits.fetch_add(1, Ordering::Relaxed);
+
+                return into_cached_response(stale_response, age, CacheStatus::StaleIfError);
+            }
+        }
+
+        self.store(key, response, now).await
+    }
+
+    fn lookup(&self, key: &RequestKey, now: SystemTime) -> CacheLookup {
+        let mut entries = self.entries.lock().unwrap();
+        let Some(entry) = entries.entries.get_mut(key) else {
+            return CacheLookup::Miss;
+        };
+
+        let age = now.duration_since(entry.stored_at).unwrap_or_default();
+        let max_age = entry.cache_control.max_age.unwrap_or_default();
+        if age <= max_age {
+            return CacheLookup::Fresh(entry.response.clone(), age);
+        }
+
+        let max_staleness = self.max_staleness(&key.canister_id);
+        let staleness = age - max_age;
+        let stale_while_revalidate = entry
+            .cache_control
+            .stale_while_revalidate
+            .unwrap_or_default()
+            .min(max_staleness);
+        if staleness <= stale_while_revalidate {
+            let should_revalidate = !entry.revalidating;
+            entry.revalidating = true;
+
+            return CacheLookup::StaleWhileRevalidate(
+                entry.response.clone(),
+                age,
+                should_revalidate,
+            );
+        }
+
+        let stale_if_error = entry
+            .cache_control
+            .stale_if_error
+            .unwrap_or_default()
+            .min(max_staleness);
+        if staleness > stale_if_error {
+            entries.remove(key);
+        }
+
+        CacheLookup::Miss
+    }
+
+    fn lookup_stale_if_error(
+        &self,
+        key: &RequestKey,
+        now: SystemTime,
+    ) -> Option<(SharedResponse, Duration)> {
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.entries.get(key)?;
+
+        let age = now.duration_since(entry.stored_at).unwrap_or_default();
+        let max_age = entry.cache_control.max_age.unwrap_or_default();
+        let stale_if_error = entry
+            .cache_control
+            .stale_if_error
+            .unwrap_or_default()
+            .min(self.max_staleness(&key.canister_id));
+
+        (age.saturating_sub(max_age) <= stale_if_error).then(|| (entry.response.clone(), age))
+    }
+
+    /// Caches the response if it is cacheable, and returns it.
+    async fn store(
+        &self,
+        key: RequestKey,
+        response: HttpGatewayResponse,
+        now: SystemTime,
+    ) -> HttpGatewayResponse {
+        // a replica could otherwise pin a forged response with an uncertified `Cache-Control` header,
+        // so v1 responses and responses that were excluded from certification are not cached
+        let cache_control = CacheControl::parse(
+            response
+                .metadata
+                .verification_report
+                .iter()
+                .flat_map(|report| &report.certified_response)
+                .flat_map(|certified_response| &certified_response.headers)
+                .filter(|(name, _)| name.eq_ignore_ascii_case(header::CACHE_CONTROL.as_str()))
+                .map(|(_, value)| value.as_str()),
+        );
+        let is_cacheable = self.capacity > 0
+            && !cache_control.no_store
+            && cache_control != CacheControl::default()
+            && !is_private_response(&response)
+            && response
+                .metadata
+                .response_verification_version
+                .is_some_and(|version| version >= 2)
+            && (response.canister_response.status().is_success()
+                || response.canister_response.status().is_redirection());
+
+        if !is_cacheable {
+            // a failed revalidation keeps the stale response
+            if let Some(entry) = self.entries.lock().unwrap().entries.get_mut(&key) {
+                entry.revalidating = false;
+            }
+
+            return response;
+        }
+
+        let response = match SharedResponse::try_from_response(response).await {
+            Ok(response) => response,
+            Err(streamed_response) => return streamed_response,
+        };
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(
+            key,
+            ResponseCacheEntry {
+                response: response.clone(),
+                stored_at: now,
+                cache_control,
+                revalidating: false,
+            },
+            self.capacity,
+        );
+
+        response.into_response()
+    }
+}
+
+impl ResponseCacheEntries {
+    fn insert(&mut self, key: RequestKey, entry: ResponseCacheEntry, capacity: usize) {
+        if self.entries.insert(key.clone(), entry).is_some() {
+            return;
+        }
+
+        self.insertion_order.push_back(key);
+        while self.insertion_order.len() > capacity {
+            if let Some(oldest_key) = self.insertion_order.pop_front() {
+                self.entries.remove(&oldest_key);
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &RequestKey) {
+        self.entries.remove(key);
+        self.insertion_order.retain(|k| k != key);
+    }
+}
+
+fn into_cached_response(
+    response: SharedResponse,
+    age: Duration,
+    cache_status: CacheStatus,
+) -> HttpGatewayResponse {
+    let mut response = response.into_response();
+    response
+        .canister_response
+        .headers_mut()
+        .insert(header::AGE, HeaderValue::from(age.as_secs()));
+    response.metadata.cache_status = Some(cache_status);
+
+    response
+}
+
+fn clone_request(request: &CanisterRequest) -> CanisterRequest {
+    let mut cloned_request = Request::new(request.body().clone());
+    *cloned_request.method_mut() = request.method().clone();
+    *cloned_request.uri_mut() = request.uri().clone();
+    *cloned_request.version_mut() = request.version();
+    *cloned_request.headers_mut() = request.headers().clone();
+
+    cloned_request
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        CertifiedResponse, HttpGatewayResponseBody, HttpGatewayResponseMetadata, VerificationReport,
+    };
+    use bytes::Bytes;
+    use http::{Response, StatusCode};
+    use http_body_util::{BodyExt, Full};
+    use std::{sync::atomic::AtomicUsize, time::UNIX_EPOCH};
+
+    #[derive(Debug)]
+    struct FixedClock(Mutex<SystemTime>);
+
+    impl Clock for FixedClock {
+        fn now(&self) -> SystemTime {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    struct TestCache {
+        response_cache: Arc<ResponseCache>,
+        fixed_clock: Arc<FixedClock>,
+        clock: Arc<dyn Clock>,
+        process_count: Arc<AtomicUsize>,
+    }
+
+    impl TestCache {
+        fn new(max_staleness: Duration) -> Self {
+            let fixed_clock = Arc::new(FixedClock(Mutex::new(UNIX_EPOCH)));
+
+            Self {
+                response_cache: Arc::new(ResponseCache::new(10, max_staleness, HashMap::new())),
+                clock: fixed_clock.clone(),
+                fixed_clock,
+                process_count: Arc::new(AtomicUsize::new(0)),
+            }
+        }
+
+        fn advance(&self, duration: Duration) {
+            *self.fixed_clock.0.lock().unwrap() += duration;
+        }
+
+        async fn serve(
+            &self,
+            status: StatusCode,
+            cache_control: &'static str,
+        ) -> HttpGatewayResponse {
+            self.serve_with_headers(status, &[(header::CACHE_CONTROL, cache_control)], true)
+                .await
+        }
+
+        /// Serves a v2 response with the given headers, which are all certified if `certified` is set.
+        async fn serve_with_headers(
+            &self,
+            status: StatusCode,
+            headers: &[(header::HeaderName, &'static str)],
+            certified: bool,
+        ) -> HttpGatewayResponse {
+            let headers = headers.to_vec();
+            let process_count = Arc::clone(&self.process_count);
+            let process = move |_| async move {
+                let count = process_count.fetch_add(1, Ordering::Relaxed) + 1;
+                let mut canister_response = Response::new(HttpGatewayResponseBody::Right(
+                    Full::new(Bytes::from(format!("response {}", count))),
+                ));
+                *canister_response.status_mut() = status;
+                for (name, value) in &headers {
+                    canister_response
+                        .headers_mut()
+                        .append(name, HeaderValue::from_static(value));
+                }
+                let certified_response = certified.then(|| CertifiedResponse {
+                    status_code: Some(status.as_u16()),
+                    headers: headers
+                        .iter()
+                        .map(|(name, value)| (name.to_string(), value.to_string()))
+                        .collect(),
+                    body_length: 0,
+                });
+
+                HttpGatewayResponse {
+                    canister_response,
+                    metadata: HttpGatewayResponseMetadata {
+                        response_verification_version: Some(2),
+                        verification_report: Some(VerificationReport {
+                            version: 2,
+                            certificate_time: None,
+                            certificate_age: None,
+                            subnet_id: None,
+                            delegated: false,
+                            expr_path: None,
+                            certified_response,
+                            dropped_headers: vec![],
+                            stripped_headers: vec![],
+                        }),
+                        ..Default::default()
+                    },
+                }
+            };
+
+            self.response_cache
+                .serve(
+                    Principal::from_slice(&[1, 2, 3, 4]),
+                    Request::get("/index.html").body(Bytes::new()).unwrap(),
+                    false,
+                    &self.clock,
+                    process.clone(),
+                    process,
+                )
+                .await
+        }
+    }
+
+    async fn body_and_age(response: HttpGatewayResponse) -> (Bytes, Option<HeaderValue>) {
+        let age = response
+            .canister_response
+            .headers()
+            .get(header::AGE)
+            .cloned();
+        let body = response
+            .canister_response
+            .into_body()
+            .collect()
+            .await
+            .unwrap()
+            .to_bytes();
+
+        (body, age)
+    }
+
+    #[test]
+    fn should_parse_cache_control() {
+        let mut headers = http::HeaderMap::new();
+        headers.append(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("public, max-age=60, s-maxage=\"120\""),
+        );
+        headers.append(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("stale-while-revalidate=30, Stale-If-Error=600"),
+        );
+
+        assert_eq!(
+            CacheControl::parse(
+                headers
+                    .get_all(header::CACHE_CONTROL)
+                    .iter()
+                    .map(|value| value.to_str().unwrap())
+            ),
+            CacheControl {
+                max_age: Some(Duration::from_secs(120)),
+                stale_while_revalidate: Some(Duration::from_secs(30)),
+                stale_if_error: Some(Duration::from_secs(600)),
+                no_store: false,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn should_serve_fresh_responses_with_age() {
+        let cache = TestCache::new(DEFAULT_MAX_STALENESS);
+        let cache_control = "max-age=60";
+
+        let first_response = cache.serve(StatusCode::OK, cache_control).await;
+        assert_eq!(first_response.metadata.cache_status, None);
+
+        cache.advance(Duration::from_secs(10));
+        let cached_response = cache.serve(StatusCode::OK, cache_control).await;
+        assert_eq!(
+            cached_response.metadata.cache_status,
+            Some(CacheStatus::Fresh)
+        );
+        assert_eq!(
+            body_and_age(cached_response).await,
+            (
+                Bytes::from_static(b"response 1"),
+                Some(HeaderValue::from(10))
+            )
+        );
+        assert_eq!(cache.process_count.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn should_serve_stale_responses_while_revalidating() {
+        let cache = TestCache::new(DEFAULT_MAX_STALENESS);
+        let cache_control = "max-age=60, stale-while-revalidate=30";
+
+        cache.serve(StatusCode::OK, cache_control).await;
+        cache.advance(Duration::from_secs(70));
+
+        let stale_response = cache.serve(StatusCode::OK, cache_control).await;
+        assert_eq!(
+            stale_response.metadata.cache_status,
+            Some(CacheStatus::StaleWhileRevalidate)
+        );
+        assert_eq!(
+            body_and_age(stale_response).await,
+            (
+                Bytes::from_static(b"response 1"),
+                Some(HeaderValue::from(70))
+            )
+        );
+
+        // let the revalidation complete
+        while cache.process_count.load(Ordering::Relaxed) < 2 {
+            tokio::task::yield_now().await;
+        }
+        tokio::task::yield_now().await;
+
+        let revalidated_response = cache.serve(StatusCode::OK, cache_control).await;
+        assert_eq!(
+            revalidated_response.metadata.cache_status,
+            Some(CacheStatus::Fresh)
+        );
+        assert_eq!(
+            body_and_age(revalidated_response).await,
+            (
+                Bytes::from_static(b"response 2"),
+                Some(HeaderValue::from(0))
+            )
+        );
+        assert_eq!(cache.response_cache.stale_hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn should_serve_stale_responses_on_error() {
+        let cache = TestCache::new(DEFAULT_MAX_STALENESS);
+        let cache_control = "max-age=60, stale-if-error=120";
+
+        cache.serve(StatusCode::OK, cache_control).await;
+        cache.advance(Duration::from_secs(150));
+
+        let stale_response = cache
+            .serve(StatusCode::SERVICE_UNAVAILABLE, cache_control)
+            .await;
+        assert_eq!(
+            stale_response.metadata.cache_status,
+            Some(CacheStatus::StaleIfError)
+        );
+        assert_eq!(stale_response.canister_response.status(), StatusCode::OK);
+
+        // too stale
+        cache.advance(Duration::from_secs(60));
+        let error_response = cache
+            .serve(StatusCode::SERVICE_UNAVAILABLE, cache_control)
+            .await;
+        assert_eq!(error_response.metadata.cache_status, None);
+        assert_eq!(
+            error_response.canister_response.status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+
+    #[tokio::test]
+    async fn should_limit_staleness() {
+        let cache = TestCache::new(Duration::from_secs(10));
+        let cache_control = "max-age=60, stale-while-revalidate=300, stale-if-error=300";
+
+        cache.serve(StatusCode::OK, cache_control).await;
+        cache.advance(Duration::from_secs(75));
+
+        let error_response = cache
+            .serve(StatusCode::SERVICE_UNAVAILABLE, cache_control)
+            .await;
+        assert_eq!(error_response.metadata.cache_status, None);
+        assert_eq!(cache.process_count.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn should_not_cache_uncacheable_responses() {
+        for (status, cache_control) in [
+            (StatusCode::OK, "public, no-cache, no-store"),
+            (StatusCode::OK, "private, max-age=60"),
+            (StatusCode::OK, "public"),
+            (StatusCode::INTERNAL_SERVER_ERROR, "max-age=60"),
+        ] {
+            let cache = TestCache::new(DEFAULT_MAX_STALENESS);
+
+            cache.serve(status, cache_control).await;
+            cache.serve(status, cache_control).await;
+
+            assert!(cache.response_cache.is_empty());
+            assert_eq!(cache.process_count.load(Ordering::Relaxed), 2);
+        }
+    }
+
+    #[tokio::test]
+    async fn should_not_cache_responses_that_set_cookies() {
+        let cache = TestCache::new(DEFAULT_MAX_STALENESS);
+        let headers = [
+            (header::CACHE_CONTROL, "public, max-age=60"),
+            (header::SET_COOKIE, "session=1"),
+        ];
+
+        cache
+            .serve_with_headers(StatusCode::OK, &headers, true)
+            .await;
+        let response = cache
+            .serve_with_headers(StatusCode::OK, &headers, true)
+            .await;
+
+        assert_eq!(response.metadata.cache_status, None);
+        assert!(cache.response_cache.is_empty());
+        assert_eq!(cache.process_count.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn should_not_cache_responses_with_an_uncertified_cache_control() {
+        let cache = TestCache::new(DEFAULT_MAX_STALENESS);
+        let headers = [(
+            header::CACHE_CONTROL,
+            "max-age=31536000, stale-if-error=31536000",
+        )];
+
+        cache
+            .serve_with_headers(StatusCode::OK, &headers, false)
+            .await;
+        let response = cache
+            .serve_with_headers(StatusCode::OK, &headers, false)
+            .await;
+
+        assert_eq!(response.metadata.cache_status, None);
+        assert!(cache.response_cache.is_empty());
+        assert_eq!(cache.process_count.load(Ordering::Relaxed), 2);
+    }
+}
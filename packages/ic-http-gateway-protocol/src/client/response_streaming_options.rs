@@ -0,0 +1,25 @@
+use crate::StreamingTokenPredictor;
+use std::sync::Arc;
+
+const DEFAULT_MAX_CONCURRENT_CHUNK_REQUESTS: usize = 4;
+
+/// Options for streaming responses that are too large for a single canister response.
+#[derive(Debug, Clone)]
+pub struct ResponseStreamingOptions {
+    /// How many chunks of a stream are fetched concurrently.
+    /// At most this many chunks are buffered in memory per response.
+    pub max_concurrent_chunk_requests: usize,
+
+    /// Predicts the tokens of callback-strategy streams, so that their chunks can be prefetched concurrently.
+    /// If `None`, or if a token cannot be predicted, chunks are fetched one after another.
+    pub streaming_token_predictor: Option<Arc<dyn StreamingTokenPredictor>>,
+}
+
+impl Default for ResponseStreamingOptions {
+    fn default() -> Self {
+        Self {
+            max_concurrent_chunk_requests: DEFAULT_MAX_CONCURRENT_CHUNK_REQUESTS,
+            streaming_token_predictor: None,
+        }
+    }
+}
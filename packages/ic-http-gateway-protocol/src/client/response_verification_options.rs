@@ -0,0 +1,53 @@
+use crate::{
+    Clock, SystemClock, V1HeaderPolicy, VerificationCache, VerificationPool,
+    VerificationVersionPolicy,
+};
+use std::{sync::Arc, time::Duration};
+
+const DEFAULT_MAX_CERT_TIME_OFFSET: Duration = Duration::from_secs(300);
+
+/// Options for the verification of responses.
+#[derive(Debug, Clone)]
+pub struct ResponseVerificationOptions {
+    /// The clock used to check the freshness of certificates.
+    pub clock: Arc<dyn Clock>,
+
+    /// How far in the past a certificate's time may be, relative to the current time.
+    pub max_cert_time_offset_past: Duration,
+
+    /// How far in the future a certificate's time may be, relative to the current time.
+    pub max_cert_time_offset_future: Duration,
+
+    /// The pool of blocking threads that responses are verified on.
+    /// If `None`, responses are verified on the async runtime.
+    pub verification_pool: Option<Arc<VerificationPool>>,
+
+    /// The cache of successful verifications, so that identical responses are not verified again.
+    /// If `None`, every response is verified.
+    pub verification_cache: Option<Arc<VerificationCache>>,
+
+    /// Which response verification versions are accepted from canisters.
+    pub verification_version_policy: Arc<VerificationVersionPolicy>,
+
+    /// The uncertified headers of verification version 1 responses that are passed on to clients.
+    pub v1_header_policy: Arc<V1HeaderPolicy>,
+
+    /// Whether the `IC-Certificate` and `IC-CertificateExpression` headers are removed from responses.
+    /// Their decoded content is available in the response metadata either way.
+    pub strip_certificate_headers: bool,
+}
+
+impl Default for ResponseVerificationOptions {
+    fn default() -> Self {
+        Self {
+            clock: Arc::new(SystemClock),
+            max_cert_time_offset_past: DEFAULT_MAX_CERT_TIME_OFFSET,
+            max_cert_time_offset_future: DEFAULT_MAX_CERT_TIME_OFFSET,
+            verification_pool: Some(Arc::new(VerificationPool::default())),
+            verification_cache: Some(Arc::new(VerificationCache::default())),
+            verification_version_policy: Arc::new(VerificationVersionPolicy::default()),
+            v1_header_policy: Arc::new(V1HeaderPolicy::default()),
+            strip_certificate_headers: false,
+        }
+    }
+}
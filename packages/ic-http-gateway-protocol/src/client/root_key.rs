@@ -0,0 +1,300 @@
+use crate::{HttpGatewayError, HttpGatewayResult, IC_ROOT_KEY};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use http::Uri;
+use ic_agent::Agent;
+use std::{net::IpAddr, path::PathBuf};
+
+static PEM_BEGIN_MARKER: &str = "-----BEGIN";
+static PEM_END_MARKER: &str = "-----END";
+
+/// The root key used to verify certificates returned by the Internet Computer.
+///
+/// [HttpGatewayClientBuilder::build](crate::HttpGatewayClientBuilder::build) sets the root key
+/// without contacting the network,
+/// [HttpGatewayClientBuilder::build_and_check_root_key](crate::HttpGatewayClientBuilder::build_and_check_root_key)
+/// additionally checks it against the root key reported by the network.
+#[derive(Debug, Clone, Default)]
+pub enum RootKey {
+    /// The pinned root key of the Internet Computer mainnet.
+    #[default]
+    Mainnet,
+
+    /// A pinned, DER-encoded root key.
+    Pinned(Vec<u8>),
+
+    /// A root key loaded from a DER or PEM encoded file.
+    File(PathBuf),
+
+    /// Fetch the root key from the network on startup,
+    /// only supported by [HttpGatewayClientBuilder::build_and_check_root_key](crate::HttpGatewayClientBuilder::build_and_check_root_key).
+    ///
+    /// *Only use this for local development, fetching the root key from mainnet
+    /// makes the gateway prone to man-in-the-middle attacks!*
+    /// The URL of the replica must be a loopback address, so this cannot be used
+    /// with a pre-built agent, whose URL cannot be checked.
+    FetchForLocalDev,
+}
+
+/// Sets the configured root key on the agent, without contacting the network.
+/// Without a configured root key, a pre-built agent keeps its own root key
+/// and an agent built from a URL uses the mainnet root key.
+///
+/// `url` is the URL that the agent was built with, `None` for a pre-built agent.
+pub(crate) fn set_root_key(
+    agent: &Agent,
+    url: Option<&str>,
+    root_key: Option<RootKey>,
+) -> HttpGatewayResult {
+    let root_key = match root_key {
+        Some(root_key) => root_key,
+        None if url.is_some() => RootKey::Mainnet,
+        None => return Ok(()),
+    };
+
+    match root_key {
+        RootKey::Mainnet => agent.set_root_key(IC_ROOT_KEY.to_vec()),
+        RootKey::Pinned(root_key) => agent.set_root_key(root_key),
+        RootKey::File(path) => {
+            let root_key = std::fs::read(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|contents| decode_root_key(&contents))
+                .map_err(|reason| HttpGatewayError::RootKeyLoadingError {
+                    path: path.display().to_string(),
+                    reason,
+                })?;
+
+            agent.set_root_key(root_key);
+        }
+        RootKey::FetchForLocalDev => {
+            check_root_key_fetch(url)?;
+
+            return Err(HttpGatewayError::RootKeyFetchNotAllowed {
+                url: url.map(str::to_string),
+                reason: "the root key can only be fetched by `build_and_check_root_key`"
+                    .to_string(),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Sets the configured root key on the agent, like [set_root_key],
+/// and checks it against the root key reported by the network.
+/// [RootKey::FetchForLocalDev] fetches the root key from the network instead.
+///
+/// `url` is the URL that the agent was built with, `None` for a pre-built agent.
+pub(crate) async fn check_root_key(
+    agent: &Agent,
+    url: Option<&str>,
+    root_key: Option<RootKey>,
+) -> HttpGatewayResult {
+    if let Some(RootKey::FetchForLocalDev) = root_key {
+        check_root_key_fetch(url)?;
+
+        // reset any previously configured root key, otherwise the agent will not fetch it
+        agent.set_root_key(IC_ROOT_KEY.to_vec());
+        agent.fetch_root_key().await?;
+
+        return Ok(());
+    }
+
+    set_root_key(agent, url, root_key)?;
+
+    // the network does not have to report its root key, but if it does, it must match
+    if let Some(network_root_key) = agent.status().await?.root_key {
+        if network_root_key != agent.read_root_key() {
+            return Err(HttpGatewayError::RootKeyMismatch);
+        }
+    }
+
+    Ok(())
+}
+
+fn check_root_key_fetch(url: Option<&str>) -> HttpGatewayResult {
+    let Some(url) = url else {
+        return Err(HttpGatewayError::RootKeyFetchNotAllowed {
+            url: None,
+            reason: "the URL of a pre-built agent cannot be checked".to_string(),
+        });
+    };
+    if !is_loopback_url(url) {
+        return Err(HttpGatewayError::RootKeyFetchNotAllowed {
+            url: Some(url.to_string()),
+            reason: "the URL is not a loopback address".to_string(),
+        });
+    }
+
+    Ok(())
+}
+
+fn decode_root_key(contents: &[u8]) -> Result<Vec<u8>, String> {
+    let Some(pem) = std::str::from_utf8(contents)
+        .ok()
+        .filter(|contents| contents.trim_start().starts_with(PEM_BEGIN_MARKER))
+    else {
+        return Ok(contents.to_vec());
+    };
+
+    let base64_body = pem
+        .lines()
+        .map(str::trim)
+        .skip_while(|line| !line.starts_with(PEM_BEGIN_MARKER))
+        .skip(1)
+        .take_while(|line| !line.starts_with(PEM_END_MARKER))
+        .collect::<String>();
+
+    BASE64
+        .decode(base64_body)
+        .map_err(|e| format!("invalid PEM encoding: {}", e))
+}
+
+fn is_loopback_url(url: &str) -> bool {
+    let Some(host) = url
+        .parse::<Uri>()
+        .ok()
+        .and_then(|uri| uri.host().map(str::to_string))
+    else {
+        return false;
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    host.eq_ignore_ascii_case("localhost")
+        || host.to_ascii_lowercase().ends_with(".localhost")
+        || host
+            .parse::<IpAddr>()
+            .map(|ip| ip.is_loopback())
+            .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::mock_replica::MockReplica;
+    use assert_matches::assert_matches;
+    use std::sync::Arc;
+
+    #[test]
+    fn should_set_root_keys_without_contacting_the_network() {
+        let replica = Arc::new(MockReplica {
+            root_key: Some(vec![1, 2, 3]),
+            ..Default::default()
+        });
+
+        let agent = replica.agent();
+        agent.set_root_key(vec![4, 5, 6]);
+        assert_matches!(set_root_key(&agent, None, None), Ok(()));
+        assert_eq!(agent.read_root_key(), vec![4, 5, 6]);
+
+        assert_matches!(
+            set_root_key(&agent, None, Some(RootKey::Pinned(vec![7, 8, 9]))),
+            Ok(())
+        );
+        assert_eq!(agent.read_root_key(), vec![7, 8, 9]);
+
+        assert_matches!(
+            set_root_key(&agent, Some("http://mock-replica"), None),
+            Ok(())
+        );
+        assert_eq!(agent.read_root_key(), IC_ROOT_KEY.to_vec());
+
+        assert_eq!(replica.request_count("status"), 0);
+    }
+
+    #[tokio::test]
+    async fn should_check_the_root_key_against_the_network() {
+        let replica = Arc::new(MockReplica {
+            root_key: Some(IC_ROOT_KEY.to_vec()),
+            ..Default::default()
+        });
+        let agent = replica.agent();
+        assert_matches!(
+            check_root_key(&agent, None, Some(RootKey::Mainnet)).await,
+            Ok(())
+        );
+        assert_eq!(agent.read_root_key(), IC_ROOT_KEY.to_vec());
+
+        let replica = Arc::new(MockReplica {
+            root_key: Some(vec![1, 2, 3]),
+            ..Default::default()
+        });
+        assert_matches!(
+            check_root_key(&replica.agent(), None, Some(RootKey::Mainnet)).await,
+            Err(HttpGatewayError::RootKeyMismatch)
+        );
+        assert_matches!(
+            check_root_key(&replica.agent(), None, Some(RootKey::Pinned(vec![1, 2, 3]))).await,
+            Ok(())
+        );
+    }
+
+    #[tokio::test]
+    async fn should_not_fetch_the_root_key_of_pre_built_agents() {
+        let replica = Arc::new(MockReplica {
+            root_key: Some(vec![1, 2, 3]),
+            ..Default::default()
+        });
+
+        assert_matches!(
+            check_root_key(&replica.agent(), None, Some(RootKey::FetchForLocalDev)).await,
+            Err(HttpGatewayError::RootKeyFetchNotAllowed { url: None, .. })
+        );
+    }
+
+    #[test]
+    fn should_only_fetch_the_root_key_when_checking_it() {
+        let replica = Arc::new(MockReplica::default());
+
+        assert_matches!(
+            set_root_key(
+                &replica.agent(),
+                Some("http://localhost:4943"),
+                Some(RootKey::FetchForLocalDev)
+            ),
+            Err(HttpGatewayError::RootKeyFetchNotAllowed { url: Some(_), .. })
+        );
+    }
+
+    #[test]
+    fn should_detect_loopback_urls() {
+        assert!(is_loopback_url("http://localhost:4943"));
+        assert!(is_loopback_url("http://LOCALHOST"));
+        assert!(is_loopback_url(
+            "http://qoctq-giaaa-aaaaa-aaaea-cai.localhost:4943"
+        ));
+        assert!(is_loopback_url("http://127.0.0.1:8080/"));
+        assert!(is_loopback_url("http://127.1.2.3"));
+        assert!(is_loopback_url("http://[::1]:4943"));
+
+        assert!(!is_loopback_url("https://icp-api.io"));
+        assert!(!is_loopback_url("http://localhost.example.com"));
+        assert!(!is_loopback_url("http://10.0.0.1"));
+        assert!(!is_loopback_url("not a url"));
+    }
+
+    #[test]
+    fn should_decode_der_root_key() {
+        assert_eq!(decode_root_key(IC_ROOT_KEY).unwrap(), IC_ROOT_KEY.to_vec());
+    }
+
+    #[test]
+    fn should_decode_pem_root_key() {
+        let pem = format!(
+            "-----BEGIN PUBLIC KEY-----\n{}\n{}\n-----END PUBLIC KEY-----\n",
+            &BASE64.encode(IC_ROOT_KEY)[..64],
+            &BASE64.encode(IC_ROOT_KEY)[64..],
+        );
+
+        assert_eq!(
+            decode_root_key(pem.as_bytes()).unwrap(),
+            IC_ROOT_KEY.to_vec()
+        );
+    }
+
+    #[test]
+    fn should_fail_decoding_malformed_pem_root_key() {
+        let pem = "-----BEGIN PUBLIC KEY-----\nnot base64!\n-----END PUBLIC KEY-----\n";
+
+        assert!(decode_root_key(pem.as_bytes()).is_err());
+    }
+}
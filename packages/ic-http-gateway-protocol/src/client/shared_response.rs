@@ -0,0 +1,140 @@
+use crate::{
+    CanisterRequest, HttpGatewayResponse, HttpGatewayResponseBody, HttpGatewayResponseMetadata,
+};
+use bytes::Bytes;
+use candid::Principal;
+use http::{header, HeaderMap, Method, Response, StatusCode, Version};
+use http_body_util::{BodyExt, Full};
+
+/// Requests carrying any of these headers are specific to a user and are never shared.
+const CREDENTIAL_HEADER_NAMES: [header::HeaderName; 3] = [
+    header::AUTHORIZATION,
+    header::PROXY_AUTHORIZATION,
+    header::COOKIE,
+];
+
+/// Responses with any of these `Cache-Control` directives are specific to a user or must not be kept,
+/// so they are never shared.
+const PRIVATE_CACHE_DIRECTIVES: [&str; 2] = ["private", "no-store"];
+
+/// A normalized request, identifying the requests that can be served with the same response.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct RequestKey {
+    pub(crate) canister_id: Principal,
+    skip_verification: bool,
+    method: Method,
+    path_and_query: String,
+    headers: Vec<(String, Vec<u8>)>,
+    body: Bytes,
+}
+
+impl RequestKey {
+    /// Normalizes the request, or returns `None` if it has side effects or carries credentials,
+    /// so that its response must not be shared.
+    pub(crate) fn new(
+        canister_id: Principal,
+        request: &CanisterRequest,
+        skip_verification: bool,
+    ) -> Option<Self> {
+        if request.method() != Method::GET && request.method() != Method::HEAD {
+            return None;
+        }
+        if CREDENTIAL_HEADER_NAMES
+            .iter()
+            .any(|name| request.headers().contains_key(name))
+        {
+            return None;
+        }
+
+        // the request id is not sent to the canister
+        let mut headers = request
+            .headers()
+            .iter()
+            .filter(|(name, _)| name.as_str() != "x-request-id")
+            .map(|(name, value)| (name.to_string(), value.as_bytes().to_vec()))
+            .collect::<Vec<_>>();
+        // the order of values of the same header is significant, the order of headers is not
+        headers.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        Some(Self {
+            canister_id,
+            skip_verification,
+            method: request.method().clone(),
+            path_and_query: request
+                .uri()
+                .path_and_query()
+                .map(|path_and_query| path_and_query.to_string())
+                .unwrap_or_default(),
+            headers,
+            body: request.body().clone(),
+        })
+    }
+}
+
+/// Whether the response is specific to the user that requested it, because it sets cookies
+/// or its `Cache-Control` header makes it private, so that it must not be served to other requests.
+pub(crate) fn is_private_response(response: &HttpGatewayResponse) -> bool {
+    let headers = response.canister_response.headers();
+    if headers.contains_key(header::SET_COOKIE) {
+        return true;
+    }
+
+    headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|directive| directive.split('=').next().unwrap_or_default().trim())
+        .any(|name| {
+            PRIVATE_CACHE_DIRECTIVES
+                .iter()
+                .any(|private| private.eq_ignore_ascii_case(name))
+        })
+}
+
+/// A response with a full body, which can be shared between requests.
+#[derive(Debug, Clone)]
+pub(crate) struct SharedResponse {
+    status: StatusCode,
+    version: Version,
+    headers: HeaderMap,
+    body: Bytes,
+    metadata: HttpGatewayResponseMetadata,
+}
+
+impl SharedResponse {
+    /// Returns the response as is if its body is streamed.
+    pub(crate) async fn try_from_response(
+        response: HttpGatewayResponse,
+    ) -> Result<Self, HttpGatewayResponse> {
+        let (parts, body) = response.canister_response.into_parts();
+        let HttpGatewayResponseBody::Right(body) = body else {
+            return Err(HttpGatewayResponse {
+                canister_response: Response::from_parts(parts, body),
+                metadata: response.metadata,
+            });
+        };
+
+        Ok(Self {
+            status: parts.status,
+            version: parts.version,
+            headers: parts.headers,
+            // collecting a full body cannot fail, and does not copy it
+            body: body.collect().await.unwrap_or_default().to_bytes(),
+            metadata: response.metadata,
+        })
+    }
+
+    pub(crate) fn into_response(self) -> HttpGatewayResponse {
+        let mut canister_response =
+            Response::new(HttpGatewayResponseBody::Right(Full::new(self.body)));
+        *canister_response.status_mut() = self.status;
+        *canister_response.version_mut() = self.version;
+        *canister_response.headers_mut() = self.headers;
+
+        HttpGatewayResponse {
+            canister_response,
+            metadata: self.metadata,
+        }
+    }
+}
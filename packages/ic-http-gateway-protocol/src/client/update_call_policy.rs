@@ -0,0 +1,373 @@
+use crate::{
+    create_rate_limited_response, normalize_path, HttpGatewayResponse, HttpGatewayResponseBody,
+    HttpGatewayResponseMetadata, RateLimitContext, RateLimitStatus, RateLimitedCall,
+};
+use bytes::Bytes;
+use candid::Principal;
+use http::{header, HeaderValue, Method, Response, StatusCode};
+use http_body_util::Full;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{Debug, Formatter},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Refuses to upgrade queries to update calls, so that only certified query responses are served.
+///
+/// Requests with one of the allowed methods, or with a path starting with one of the allowed
+/// prefixes, may still be upgraded.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReadOnlyMode {
+    allowed_methods: Vec<Method>,
+    allowed_path_prefixes: Vec<String>,
+}
+
+impl ReadOnlyMode {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allows requests with the method to be upgraded to update calls.
+    pub fn with_allowed_method(mut self, method: Method) -> Self {
+        self.allowed_methods.push(method);
+
+        self
+    }
+
+    /// Allows requests with a path starting with the prefix to be upgraded to update calls.
+    pub fn with_allowed_path_prefix(mut self, path_prefix: impl Into<String>) -> Self {
+        self.allowed_path_prefixes.push(path_prefix.into());
+
+        self
+    }
+
+    /// Whether a request with the method and path may be upgraded to an update call.
+    /// The path is normalized before it is matched, so that it cannot escape an allowed prefix,
+    /// e.g. with `..` segments.
+    pub fn allows_upgrade(&self, method: &Method, path: &str) -> bool {
+        if self.allowed_methods.contains(method) {
+            return true;
+        }
+
+        let path = normalize_path(path);
+        self.allowed_path_prefixes
+            .iter()
+            .any(|path_prefix| path.starts_with(path_prefix))
+    }
+}
+
+/// The response to requests whose upgrade to an update call was refused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefusedUpgradeResponse {
+    pub status_code: StatusCode,
+    pub body: Bytes,
+}
+
+impl Default for RefusedUpgradeResponse {
+    fn default() -> Self {
+        Self {
+            status_code: StatusCode::SERVICE_UNAVAILABLE,
+            body: Bytes::from_static(
+                b"This gateway only serves query responses, but the canister requires an update call to respond to this request",
+            ),
+        }
+    }
+}
+
+/// Which update calls the gateway makes on behalf of clients.
+///
+/// The read-only mode of a canister takes precedence over the read-only mode of the client.
+/// Requests with safe methods to the canisters that allow update call fallbacks are re-issued
+/// as update calls if their query response is not certified, unless the read-only mode refuses it.
+/// Responses whose certification is invalid or not acceptable, e.g. a stale certificate, are never re-issued.
+pub struct UpdateCallPolicy {
+    read_only_mode: Option<ReadOnlyMode>,
+    canister_read_only_modes: HashMap<Principal, Option<ReadOnlyMode>>,
+    refused_upgrade_response: RefusedUpgradeResponse,
+    update_fallback_canisters: HashSet<Principal>,
+    refused_upgrades: AtomicU64,
+    update_fallbacks: AtomicU64,
+}
+
+impl Debug for UpdateCallPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UpdateCallPolicy")
+            .field("read_only_mode", &self.read_only_mode)
+            .field("canister_read_only_modes", &self.canister_read_only_modes)
+            .field("refused_upgrade_response", &self.refused_upgrade_response)
+            .field("update_fallback_canisters", &self.update_fallback_canisters)
+            .field("refused_upgrades", &self.refused_upgrades())
+            .field("update_fallbacks", &self.update_fallbacks())
+            .finish()
+    }
+}
+
+impl UpdateCallPolicy {
+    /// `canister_read_only_modes` overrides `read_only_mode` per canister,
+    /// `None` allows every upgrade for the canister.
+    pub fn new(
+        read_only_mode: Option<ReadOnlyMode>,
+        canister_read_only_modes: HashMap<Principal, Option<ReadOnlyMode>>,
+        refused_upgrade_response: RefusedUpgradeResponse,
+        update_fallback_canisters: HashSet<Principal>,
+    ) -> Self {
+        Self {
+            read_only_mode,
+            canister_read_only_modes,
+            refused_upgrade_response,
+            update_fallback_canisters,
+            refused_upgrades: AtomicU64::new(0),
+            update_fallbacks: AtomicU64::new(0),
+        }
+    }
+
+    /// The number of requests whose upgrade to an update call was refused.
+    pub fn refused_upgrades(&self) -> u64 {
+        self.refused_upgrades.load(Ordering::Relaxed)
+    }
+
+    /// The number of requests that were re-issued as update calls because their query response was not certified.
+    pub fn update_fallbacks(&self) -> u64 {
+        self.update_fallbacks.load(Ordering::Relaxed)
+    }
+
+    /// Whether a request to the canister with the method and path may be re-issued as an update call
+    /// if its query response is not certified.
+    pub fn allows_update_fallback(
+        &self,
+        canister_id: &Principal,
+        method: &Method,
+        path: &str,
+    ) -> bool {
+        method.is_safe()
+            && self.update_fallback_canisters.contains(canister_id)
+            && self.allows_upgrade(canister_id, method, path)
+    }
+
+    /// Whether a request to the canister with the method and path may be upgraded to an update call.
+    pub fn allows_upgrade(&self, canister_id: &Principal, method: &Method, path: &str) -> bool {
+        let read_only_mode = match self.canister_read_only_modes.get(canister_id) {
+            Some(read_only_mode) => read_only_mode.as_ref(),
+            None => self.read_only_mode.as_ref(),
+        };
+
+        read_only_mode.is_none_or(|read_only_mode| read_only_mode.allows_upgrade(method, path))
+    }
+}
+
+/// Why an upgrade to an update call was refused.
+#[derive(Debug)]
+pub(crate) enum RefusedUpgrade<'a> {
+    ReadOnly(&'a RefusedUpgradeResponse),
+    RateLimited(RateLimitStatus),
+}
+
+impl RefusedUpgrade<'_> {
+    pub fn into_response(self) -> HttpGatewayResponse {
+        match self {
+            RefusedUpgrade::ReadOnly(refused_upgrade_response) => {
+                let mut canister_response = Response::new(HttpGatewayResponseBody::Right(
+                    Full::new(refused_upgrade_response.body.clone()),
+                ));
+                *canister_response.status_mut() = refused_upgrade_response.status_code;
+                canister_response.headers_mut().insert(
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static("text/plain; charset=utf-8"),
+                );
+
+                HttpGatewayResponse {
+                    canister_response,
+                    metadata: HttpGatewayResponseMetadata {
+                        upgrade_refused: true,
+                        ..Default::default()
+                    },
+                }
+            }
+            RefusedUpgrade::RateLimited(status) => create_rate_limited_response(status),
+        }
+    }
+}
+
+/// The checks that a request must pass before it is upgraded to an update call.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct UpgradeGuard<'a> {
+    pub update_call_policy: Option<&'a UpdateCallPolicy>,
+    pub rate_limit: Option<RateLimitContext<'a>>,
+}
+
+impl<'a> UpgradeGuard<'a> {
+    /// Whether the request may be re-issued as an update call after its query response was not certified.
+    pub fn allows_update_fallback(
+        &self,
+        canister_id: Principal,
+        method: &Method,
+        path: &str,
+    ) -> bool {
+        let Some(update_call_policy) = self.update_call_policy else {
+            return false;
+        };
+        if !update_call_policy.allows_update_fallback(&canister_id, method, path) {
+            return false;
+        }
+
+        update_call_policy
+            .update_fallbacks
+            .fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Checks whether the request may be upgraded, and takes a token from its update budget if so.
+    pub fn check(
+        &self,
+        canister_id: Principal,
+        method: &Method,
+        path: &str,
+    ) -> Result<Option<RateLimitStatus>, RefusedUpgrade<'a>> {
+        if let Some(update_call_policy) = self.update_call_policy {
+            if !update_call_policy.allows_upgrade(&canister_id, method, path) {
+                update_call_policy
+                    .refused_upgrades
+                    .fetch_add(1, Ordering::Relaxed);
+
+                return Err(RefusedUpgrade::ReadOnly(
+                    &update_call_policy.refused_upgrade_response,
+                ));
+            }
+        }
+
+        match self.rate_limit {
+            Some(rate_limit) => rate_limit
+                .check(RateLimitedCall::Update, canister_id)
+                .map_err(RefusedUpgrade::RateLimited),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::assert_matches;
+
+    fn canister_id(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    #[test]
+    fn should_allow_upgrades_by_method_and_path_prefix() {
+        let read_only_mode = ReadOnlyMode::new()
+            .with_allowed_method(Method::POST)
+            .with_allowed_path_prefix("/api/");
+
+        assert!(read_only_mode.allows_upgrade(&Method::POST, "/index.html"));
+        assert!(read_only_mode.allows_upgrade(&Method::GET, "/api/counter"));
+        assert!(!read_only_mode.allows_upgrade(&Method::GET, "/index.html"));
+        assert!(!read_only_mode.allows_upgrade(&Method::PUT, "/apis"));
+    }
+
+    #[test]
+    fn should_not_allow_upgrades_for_paths_escaping_an_allowed_prefix() {
+        let read_only_mode = ReadOnlyMode::new().with_allowed_path_prefix("/api/");
+
+        for path in [
+            "/api/../admin",
+            "/api/%2e%2e/admin",
+            "/api/%2E%2E/admin",
+            "/api/./../admin",
+            "/api%2f..%2fadmin",
+        ] {
+            assert!(
+                !read_only_mode.allows_upgrade(&Method::POST, path),
+                "{path}"
+            );
+        }
+        for path in [
+            "//api/counter",
+            "/api//counter",
+            "/api/./counter",
+            "/%61pi/counter",
+        ] {
+            assert!(read_only_mode.allows_upgrade(&Method::POST, path), "{path}");
+        }
+    }
+
+    #[test]
+    fn should_override_read_only_mode_per_canister() {
+        let update_call_policy = UpdateCallPolicy::new(
+            Some(ReadOnlyMode::new()),
+            HashMap::from([
+                (canister_id(1), None),
+                (
+                    canister_id(2),
+                    Some(ReadOnlyMode::new().with_allowed_method(Method::POST)),
+                ),
+            ]),
+            RefusedUpgradeResponse::default(),
+            HashSet::new(),
+        );
+
+        assert!(update_call_policy.allows_upgrade(&canister_id(1), &Method::GET, "/"));
+        assert!(update_call_policy.allows_upgrade(&canister_id(2), &Method::POST, "/"));
+        assert!(!update_call_policy.allows_upgrade(&canister_id(2), &Method::GET, "/"));
+        assert!(!update_call_policy.allows_upgrade(&canister_id(3), &Method::POST, "/"));
+    }
+
+    #[test]
+    fn should_refuse_upgrades_with_the_configured_response() {
+        let update_call_policy = UpdateCallPolicy::new(
+            Some(ReadOnlyMode::new()),
+            HashMap::new(),
+            RefusedUpgradeResponse {
+                status_code: StatusCode::METHOD_NOT_ALLOWED,
+                body: Bytes::from_static(b"read-only"),
+            },
+            HashSet::new(),
+        );
+        let upgrade_guard = UpgradeGuard {
+            update_call_policy: Some(&update_call_policy),
+            rate_limit: None,
+        };
+
+        let refused_upgrade = upgrade_guard
+            .check(canister_id(1), &Method::GET, "/")
+            .unwrap_err();
+        assert_matches!(refused_upgrade, RefusedUpgrade::ReadOnly(_));
+
+        let response = refused_upgrade.into_response();
+        assert_eq!(
+            response.canister_response.status(),
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+        assert!(response.metadata.upgrade_refused);
+        assert!(!response.metadata.upgraded_to_update_call);
+        assert_eq!(update_call_policy.refused_upgrades(), 1);
+
+        assert_matches!(
+            UpgradeGuard::default().check(canister_id(1), &Method::GET, "/"),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn should_only_fall_back_to_update_calls_for_safe_methods_of_allowlisted_canisters() {
+        let update_call_policy = UpdateCallPolicy::new(
+            None,
+            HashMap::from([(canister_id(2), Some(ReadOnlyMode::new()))]),
+            RefusedUpgradeResponse::default(),
+            HashSet::from([canister_id(1), canister_id(2)]),
+        );
+        let upgrade_guard = UpgradeGuard {
+            update_call_policy: Some(&update_call_policy),
+            rate_limit: None,
+        };
+
+        assert!(upgrade_guard.allows_update_fallback(canister_id(1), &Method::GET, "/"));
+        assert!(upgrade_guard.allows_update_fallback(canister_id(1), &Method::HEAD, "/"));
+        assert!(!upgrade_guard.allows_update_fallback(canister_id(1), &Method::POST, "/"));
+        // read-only canisters never fall back to update calls
+        assert!(!upgrade_guard.allows_update_fallback(canister_id(2), &Method::GET, "/"));
+        assert!(!upgrade_guard.allows_update_fallback(canister_id(3), &Method::GET, "/"));
+        assert!(!UpgradeGuard::default().allows_update_fallback(canister_id(1), &Method::GET, "/"));
+        assert_eq!(update_call_policy.update_fallbacks(), 2);
+    }
+}
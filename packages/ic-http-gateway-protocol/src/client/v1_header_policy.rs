@@ -0,0 +1,106 @@
+use http::{header, HeaderName};
+use ic_http_certification::CERTIFICATE_HEADER_NAME;
+use std::collections::HashSet;
+
+/// Headers that only describe the certified body of a response,
+/// or can only restrict what clients do with it.
+const DEFAULT_ALLOWED_HEADERS: [HeaderName; 12] = [
+    header::ACCEPT_RANGES,
+    header::CONTENT_DISPOSITION,
+    header::CONTENT_ENCODING,
+    header::CONTENT_LANGUAGE,
+    header::CONTENT_LENGTH,
+    header::CONTENT_RANGE,
+    header::CONTENT_TYPE,
+    header::DATE,
+    header::ETAG,
+    header::LAST_MODIFIED,
+    header::VARY,
+    header::X_CONTENT_TYPE_OPTIONS,
+];
+
+/// The headers of verification version 1 responses that are passed on to clients.
+///
+/// Version 1 does not certify headers, so a malicious replica could add headers such as
+/// `Set-Cookie`, `Location` or `Access-Control-Allow-Origin` to a response. Only allowed
+/// headers are passed on, all others are dropped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct V1HeaderPolicy {
+    allowed_headers: HashSet<HeaderName>,
+}
+
+impl Default for V1HeaderPolicy {
+    /// Allows the headers that describe the certified body and the `IC-Certificate` header.
+    fn default() -> Self {
+        Self::new(DEFAULT_ALLOWED_HEADERS).with_allowed_header(
+            HeaderName::from_lowercase(CERTIFICATE_HEADER_NAME.to_ascii_lowercase().as_bytes())
+                .expect("IC-Certificate is a valid header name"),
+        )
+    }
+}
+
+impl V1HeaderPolicy {
+    /// Only allows `allowed_headers`.
+    pub fn new(allowed_headers: impl IntoIterator<Item = HeaderName>) -> Self {
+        Self {
+            allowed_headers: allowed_headers.into_iter().collect(),
+        }
+    }
+
+    /// Allows another header, for example in addition to the default headers.
+    pub fn with_allowed_header(mut self, header_name: HeaderName) -> Self {
+        self.allowed_headers.insert(header_name);
+
+        self
+    }
+
+    /// Drops a header, for example one of the default headers.
+    pub fn without_allowed_header(mut self, header_name: &HeaderName) -> Self {
+        self.allowed_headers.remove(header_name);
+
+        self
+    }
+
+    /// Whether a header of a version 1 response is passed on, header names are case-insensitive.
+    pub fn allows(&self, header_name: &str) -> bool {
+        HeaderName::from_bytes(header_name.as_bytes())
+            .is_ok_and(|header_name| self.allowed_headers.contains(&header_name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_allow_benign_headers_by_default() {
+        let policy = V1HeaderPolicy::default();
+
+        assert!(policy.allows("Content-Type"));
+        assert!(policy.allows("content-encoding"));
+        assert!(policy.allows("IC-Certificate"));
+        assert!(!policy.allows("Set-Cookie"));
+        assert!(!policy.allows("Location"));
+        assert!(!policy.allows("Content-Security-Policy"));
+        assert!(!policy.allows("Access-Control-Allow-Origin"));
+        assert!(!policy.allows("Refresh"));
+        assert!(!policy.allows("Cache-Control"));
+        assert!(!policy.allows("invalid header"));
+    }
+
+    #[test]
+    fn should_allow_configured_headers() {
+        let policy = V1HeaderPolicy::default()
+            .with_allowed_header(header::CACHE_CONTROL)
+            .without_allowed_header(&header::CONTENT_DISPOSITION);
+
+        assert!(policy.allows("Cache-Control"));
+        assert!(!policy.allows("Content-Disposition"));
+        assert!(policy.allows("Content-Type"));
+
+        let policy = V1HeaderPolicy::new([header::CONTENT_TYPE]);
+
+        assert!(policy.allows("content-type"));
+        assert!(!policy.allows("Content-Length"));
+    }
+}
@@ -0,0 +1,292 @@
+use ic_http_certification::{HttpRequest, HttpResponse};
+use ic_response_verification::types::{VerificationInfo, VerifiedResponse};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::{Debug, Formatter},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+pub(crate) const DEFAULT_VERIFICATION_CACHE_CAPACITY: usize = 1024;
+
+/// The result of a successful verification, without the response body,
+/// which is the body of the response that was verified.
+#[derive(Debug, Clone)]
+struct CachedVerification {
+    verification_version: u16,
+    certified_response: Option<CachedCertifiedResponse>,
+}
+
+#[derive(Debug, Clone)]
+struct CachedCertifiedResponse {
+    status_code: Option<u16>,
+    headers: Vec<(String, String)>,
+}
+
+#[derive(Default)]
+struct VerificationCacheEntries {
+    verifications: HashMap<[u8; 32], CachedVerification>,
+    insertion_order: VecDeque<[u8; 32]>,
+}
+
+/// A bounded cache of the results of successful response verifications.
+///
+/// The response verification library checks the certificate and the response together,
+/// so entries are keyed by a hash of the certificate header and the root key, together with the canister,
+/// the lowest allowed verification version and the request and response that were verified.
+/// A hit skips the verification of the certificate's signature and subnet delegation,
+/// as well as the Merkle tree. The time of the certificate is checked on every request.
+pub struct VerificationCache {
+    capacity: usize,
+    entries: Mutex<VerificationCacheEntries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Debug for VerificationCache {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VerificationCache")
+            .field("capacity", &self.capacity)
+            .field("len", &self.len())
+            .field("hits", &self.hits())
+            .field("misses", &self.misses())
+            .finish()
+    }
+}
+
+impl VerificationCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(VerificationCacheEntries::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().verifications.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of verifications that were skipped because their result was cached.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// The number of verifications whose result was not cached.
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// Returns the cached result of the verification of the response with the given body, if any.
+    pub(crate) fn get(
+        &self,
+        key: &VerificationKey,
+        response_body: &[u8],
+    ) -> Option<VerificationInfo> {
+        let cached_verification = self
+            .entries
+            .lock()
+            .unwrap()
+            .verifications
+            .get(&key.0)
+            .cloned();
+
+        match cached_verification {
+            Some(cached_verification) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(cached_verification.into_verification_info(response_body))
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Caches the result of a successful verification.
+    pub(crate) fn insert(&self, key: VerificationKey, verification_info: &VerificationInfo) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let cached_verification = CachedVerification {
+            verification_version: verification_info.verification_version,
+            certified_response: verification_info.response.as_ref().map(|response| {
+                CachedCertifiedResponse {
+                    status_code: response.status_code,
+                    headers: response.headers.clone(),
+                }
+            }),
+        };
+        let mut entries = self.entries.lock().unwrap();
+        if entries
+            .verifications
+            .insert(key.0, cached_verification)
+            .is_some()
+        {
+            return;
+        }
+
+        entries.insertion_order.push_back(key.0);
+        while entries.insertion_order.len() > self.capacity {
+            if let Some(oldest_key) = entries.insertion_order.pop_front() {
+                entries.verifications.remove(&oldest_key);
+            }
+        }
+    }
+}
+
+impl Default for VerificationCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_VERIFICATION_CACHE_CAPACITY)
+    }
+}
+
+impl CachedVerification {
+    fn into_verification_info(self, response_body: &[u8]) -> VerificationInfo {
+        VerificationInfo {
+            response: self
+                .certified_response
+                .map(|certified_response| VerifiedResponse {
+                    status_code: certified_response.status_code,
+                    headers: certified_response.headers,
+                    body: response_body.to_vec(),
+                }),
+            verification_version: self.verification_version,
+        }
+    }
+}
+
+/// The hash of everything that a verification depends on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct VerificationKey([u8; 32]);
+
+impl VerificationKey {
+    pub(crate) fn new(
+        certificate_header: &str,
+        root_key: &[u8],
+        canister_id: &[u8],
+        min_verification_version: u16,
+        request: &HttpRequest,
+        response: &HttpResponse,
+    ) -> Self {
+        let mut hasher = Sha256::new();
+        // every field is prefixed with its length, so that the fields cannot be shifted into each other
+        let mut update = |bytes: &[u8]| {
+            hasher.update((bytes.len() as u64).to_be_bytes());
+            hasher.update(bytes);
+        };
+
+        update(certificate_header.as_bytes());
+        update(root_key);
+        update(canister_id);
+        update(&min_verification_version.to_be_bytes());
+
+        update(request.method().as_str().as_bytes());
+        update(request.url().as_bytes());
+        update(&(request.headers().len() as u64).to_be_bytes());
+        for (name, value) in request.headers() {
+            update(name.as_bytes());
+            update(value.as_bytes());
+        }
+        update(request.body());
+
+        update(&response.status_code().as_u16().to_be_bytes());
+        update(&(response.headers().len() as u64).to_be_bytes());
+        for (name, value) in response.headers() {
+            update(name.as_bytes());
+            update(value.as_bytes());
+        }
+        update(response.body());
+
+        Self(hasher.finalize().into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_http_certification::StatusCode;
+
+    fn key(body: &[u8]) -> VerificationKey {
+        let response = HttpResponse::builder()
+            .with_status_code(StatusCode::OK)
+            .with_headers(vec![(
+                "IC-Certificate".to_string(),
+                "certificate".to_string(),
+            )])
+            .with_body(body.to_vec())
+            .build();
+
+        VerificationKey::new(
+            "certificate",
+            b"root key",
+            &[1, 2, 3, 4],
+            2,
+            &HttpRequest::get("/").build(),
+            &response,
+        )
+    }
+
+    fn verification_info(body: &[u8]) -> VerificationInfo {
+        VerificationInfo {
+            response: Some(VerifiedResponse {
+                status_code: Some(200),
+                headers: vec![("content-type".to_string(), "text/html".to_string())],
+                body: body.to_vec(),
+            }),
+            verification_version: 2,
+        }
+    }
+
+    #[test]
+    fn should_find_cached_results() {
+        let cache = VerificationCache::new(10);
+
+        assert!(cache.get(&key(b"body"), b"body").is_none());
+        cache.insert(key(b"body"), &verification_info(b"body"));
+
+        let cached_verification_info = cache.get(&key(b"body"), b"body").unwrap();
+        assert_eq!(cached_verification_info.verification_version, 2);
+        assert_eq!(
+            cached_verification_info.response,
+            verification_info(b"body").response
+        );
+        assert!(cache.get(&key(b"other body"), b"other body").is_none());
+        assert_eq!(cache.hits(), 1);
+        assert_eq!(cache.misses(), 2);
+    }
+
+    #[test]
+    fn should_evict_oldest_results_when_full() {
+        let cache = VerificationCache::new(2);
+        for body in [&b"first"[..], b"second", b"third"] {
+            cache.insert(key(body), &verification_info(body));
+        }
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&key(b"first"), b"first").is_none());
+        assert!(cache.get(&key(b"second"), b"second").is_some());
+        assert!(cache.get(&key(b"third"), b"third").is_some());
+    }
+
+    #[test]
+    fn should_not_cache_with_zero_capacity() {
+        let cache = VerificationCache::new(0);
+        cache.insert(key(b"body"), &verification_info(b"body"));
+
+        assert!(cache.is_empty());
+    }
+}
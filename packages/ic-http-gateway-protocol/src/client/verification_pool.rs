@@ -0,0 +1,214 @@
+use crate::{HttpGatewayError, HttpGatewayResult};
+use std::{
+    fmt::{Debug, Formatter},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread::available_parallelism,
+    time::{Duration, Instant},
+};
+use tokio::{sync::Semaphore, task};
+
+const DEFAULT_MAX_CONCURRENT_VERIFICATIONS: usize = 4;
+
+/// A bounded pool of blocking threads that responses are verified on,
+/// so that signature checks and hash tree work do not stall the async runtime.
+///
+/// At most `max_concurrent_verifications` responses are verified at a time.
+/// Once the pool is saturated, further verifications wait for a free worker,
+/// which applies backpressure to the requests and streams that are waiting on them.
+pub struct VerificationPool {
+    max_concurrent_verifications: usize,
+    permits: Arc<Semaphore>,
+    queued: AtomicU64,
+    verifications: AtomicU64,
+    total_queue_time_ns: AtomicU64,
+    max_queue_time_ns: AtomicU64,
+}
+
+impl Debug for VerificationPool {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VerificationPool")
+            .field(
+                "max_concurrent_verifications",
+                &self.max_concurrent_verifications,
+            )
+            .field("queued", &self.queued())
+            .field("verifications", &self.verifications())
+            .field("total_queue_time", &self.total_queue_time())
+            .field("max_queue_time", &self.max_queue_time())
+            .finish()
+    }
+}
+
+impl VerificationPool {
+    pub fn new(max_concurrent_verifications: usize) -> Self {
+        let max_concurrent_verifications = max_concurrent_verifications.max(1);
+
+        Self {
+            max_concurrent_verifications,
+            permits: Arc::new(Semaphore::new(max_concurrent_verifications)),
+            queued: AtomicU64::new(0),
+            verifications: AtomicU64::new(0),
+            total_queue_time_ns: AtomicU64::new(0),
+            max_queue_time_ns: AtomicU64::new(0),
+        }
+    }
+
+    pub fn max_concurrent_verifications(&self) -> usize {
+        self.max_concurrent_verifications
+    }
+
+    /// The number of verifications currently waiting for a free worker.
+    pub fn queued(&self) -> u64 {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// The number of verifications that have been started.
+    pub fn verifications(&self) -> u64 {
+        self.verifications.load(Ordering::Relaxed)
+    }
+
+    /// The total time that verifications have spent waiting for a free worker.
+    pub fn total_queue_time(&self) -> Duration {
+        Duration::from_nanos(self.total_queue_time_ns.load(Ordering::Relaxed))
+    }
+
+    /// The longest time that a single verification has spent waiting for a free worker.
+    pub fn max_queue_time(&self) -> Duration {
+        Duration::from_nanos(self.max_queue_time_ns.load(Ordering::Relaxed))
+    }
+
+    /// Runs `verify` on a blocking thread once a worker is free.
+    /// Must be called within a tokio runtime.
+    pub(crate) async fn run<T, F>(&self, verify: F) -> HttpGatewayResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let queued_at = Instant::now();
+        let queued_guard = QueuedGuard::new(&self.queued);
+        let permit = self.permits.clone().acquire_owned().await;
+        drop(queued_guard);
+        // the semaphore is never closed
+        let permit = permit.map_err(|e| HttpGatewayError::VerificationTaskFailed {
+            reason: e.to_string(),
+        })?;
+
+        let queue_time_ns = u64::try_from(queued_at.elapsed().as_nanos()).unwrap_or(u64::MAX);
+        self.verifications.fetch_add(1, Ordering::Relaxed);
+        self.total_queue_time_ns
+            .fetch_add(queue_time_ns, Ordering::Relaxed);
+        self.max_queue_time_ns
+            .fetch_max(queue_time_ns, Ordering::Relaxed);
+
+        task::spawn_blocking(move || {
+            let result = verify();
+            drop(permit);
+
+            result
+        })
+        .await
+        .map_err(|e| HttpGatewayError::VerificationTaskFailed {
+            reason: e.to_string(),
+        })
+    }
+}
+
+/// Counts a verification as queued until it gets a worker or is cancelled.
+struct QueuedGuard<'a> {
+    queued: &'a AtomicU64,
+}
+
+impl<'a> QueuedGuard<'a> {
+    fn new(queued: &'a AtomicU64) -> Self {
+        queued.fetch_add(1, Ordering::Relaxed);
+
+        Self { queued }
+    }
+}
+
+impl Drop for QueuedGuard<'_> {
+    fn drop(&mut self) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Default for VerificationPool {
+    fn default() -> Self {
+        Self::new(
+            available_parallelism()
+                .map(|parallelism| parallelism.get())
+                .unwrap_or(DEFAULT_MAX_CONCURRENT_VERIFICATIONS),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::assert_matches;
+    use futures::future::join_all;
+    use std::thread;
+
+    #[tokio::test]
+    async fn should_run_verifications_on_blocking_threads() {
+        let pool = VerificationPool::new(2);
+        let runtime_thread = thread::current().id();
+
+        let verification_thread = pool.run(|| thread::current().id()).await.unwrap();
+
+        assert_ne!(verification_thread, runtime_thread);
+        assert_eq!(pool.verifications(), 1);
+        assert_eq!(pool.queued(), 0);
+    }
+
+    #[tokio::test]
+    async fn should_queue_verifications_once_saturated() {
+        let pool = VerificationPool::new(1);
+
+        let verifications = (0..3).map(|i| {
+            pool.run(move || {
+                thread::sleep(Duration::from_millis(20));
+                i
+            })
+        });
+
+        assert_eq!(join_all(verifications).await.len(), 3);
+        assert_eq!(pool.verifications(), 3);
+        assert_eq!(pool.queued(), 0);
+        // the last verification waited for the two before it
+        assert!(pool.max_queue_time() >= Duration::from_millis(40));
+        assert!(pool.total_queue_time() >= pool.max_queue_time());
+    }
+
+    #[tokio::test]
+    async fn should_not_count_cancelled_verifications_as_queued() {
+        let pool = VerificationPool::new(1);
+        let permit = pool.permits.clone().acquire_owned().await.unwrap();
+
+        let mut queued_verification = Box::pin(pool.run(|| 1));
+        assert!(futures::poll!(queued_verification.as_mut()).is_pending());
+        assert_eq!(pool.queued(), 1);
+
+        drop(queued_verification);
+        assert_eq!(pool.queued(), 0);
+
+        drop(permit);
+        assert_eq!(pool.run(|| 1).await.unwrap(), 1);
+        assert_eq!(pool.queued(), 0);
+    }
+
+    #[tokio::test]
+    async fn should_fail_verifications_that_panic() {
+        let pool = VerificationPool::new(1);
+
+        assert_matches!(
+            pool.run(|| panic!("verification panicked")).await,
+            Err::<(), _>(HttpGatewayError::VerificationTaskFailed { .. })
+        );
+        // the worker is released
+        assert_eq!(pool.run(|| 1).await.unwrap(), 1);
+    }
+}
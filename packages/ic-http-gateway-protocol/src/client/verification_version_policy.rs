@@ -0,0 +1,178 @@
+use crate::HttpGatewayError;
+use candid::Principal;
+use ic_response_verification::{MAX_VERIFICATION_VERSION, MIN_VERIFICATION_VERSION};
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Formatter},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// The response verification versions that are accepted from a canister.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VerificationVersionRange {
+    pub min: u16,
+    pub max: u16,
+}
+
+impl VerificationVersionRange {
+    pub fn new(min: u16, max: u16) -> Self {
+        Self { min, max }
+    }
+
+    /// Only accepts responses of verification version 2 and above,
+    /// which certify their status code and headers.
+    pub fn certified_headers_only() -> Self {
+        Self::new(2, u16::from(MAX_VERIFICATION_VERSION))
+    }
+
+    pub fn contains(&self, version: u16) -> bool {
+        (self.min..=self.max).contains(&version)
+    }
+}
+
+impl Default for VerificationVersionRange {
+    fn default() -> Self {
+        Self::new(
+            u16::from(MIN_VERIFICATION_VERSION),
+            u16::from(MAX_VERIFICATION_VERSION),
+        )
+    }
+}
+
+/// Which response verification versions are accepted, globally and per canister.
+///
+/// Canisters are asked for the highest allowed version that is supported by the gateway.
+/// Responses of a version outside of the allowed range are rejected before they are verified.
+pub struct VerificationVersionPolicy {
+    verification_versions: VerificationVersionRange,
+    canister_verification_versions: HashMap<Principal, VerificationVersionRange>,
+    rejected_responses: AtomicU64,
+}
+
+impl Debug for VerificationVersionPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VerificationVersionPolicy")
+            .field("verification_versions", &self.verification_versions)
+            .field(
+                "canister_verification_versions",
+                &self.canister_verification_versions,
+            )
+            .field("rejected_responses", &self.rejected_responses())
+            .finish()
+    }
+}
+
+impl Default for VerificationVersionPolicy {
+    fn default() -> Self {
+        Self::new(VerificationVersionRange::default(), HashMap::new())
+    }
+}
+
+impl VerificationVersionPolicy {
+    /// `canister_verification_versions` overrides `verification_versions` per canister.
+    pub fn new(
+        verification_versions: VerificationVersionRange,
+        canister_verification_versions: HashMap<Principal, VerificationVersionRange>,
+    ) -> Self {
+        Self {
+            verification_versions,
+            canister_verification_versions,
+            rejected_responses: AtomicU64::new(0),
+        }
+    }
+
+    /// The number of responses that were rejected because of their verification version.
+    pub fn rejected_responses(&self) -> u64 {
+        self.rejected_responses.load(Ordering::Relaxed)
+    }
+
+    /// The verification versions that are accepted from the canister.
+    pub fn verification_versions(&self, canister_id: &Principal) -> VerificationVersionRange {
+        self.canister_verification_versions
+            .get(canister_id)
+            .copied()
+            .unwrap_or(self.verification_versions)
+    }
+
+    /// The verification version that the canister is asked for.
+    pub fn requested_version(&self, canister_id: &Principal) -> u16 {
+        self.verification_versions(canister_id)
+            .max
+            .min(u16::from(MAX_VERIFICATION_VERSION))
+    }
+
+    /// Checks that a response of the verification version is accepted from the canister.
+    pub(crate) fn check(
+        &self,
+        canister_id: &Principal,
+        version: u16,
+    ) -> Result<(), HttpGatewayError> {
+        let verification_versions = self.verification_versions(canister_id);
+        if verification_versions.contains(version) {
+            return Ok(());
+        }
+
+        self.rejected_responses.fetch_add(1, Ordering::Relaxed);
+        Err(HttpGatewayError::VerificationVersionNotAllowed {
+            canister_id: *canister_id,
+            version,
+            min: verification_versions.min,
+            max: verification_versions.max,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::assert_matches;
+
+    fn canister_id(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    #[test]
+    fn should_apply_canister_verification_versions() {
+        let policy = VerificationVersionPolicy::new(
+            VerificationVersionRange::certified_headers_only(),
+            HashMap::from([(canister_id(2), VerificationVersionRange::new(1, 1))]),
+        );
+
+        assert_matches!(policy.check(&canister_id(1), 2), Ok(()));
+        assert_matches!(
+            policy.check(&canister_id(1), 1),
+            Err(HttpGatewayError::VerificationVersionNotAllowed {
+                version: 1,
+                min: 2,
+                max: 2,
+                ..
+            })
+        );
+        assert_eq!(policy.requested_version(&canister_id(1)), 2);
+
+        assert_matches!(policy.check(&canister_id(2), 1), Ok(()));
+        assert_matches!(
+            policy.check(&canister_id(2), 2),
+            Err(HttpGatewayError::VerificationVersionNotAllowed {
+                version: 2,
+                min: 1,
+                max: 1,
+                ..
+            })
+        );
+        assert_eq!(policy.requested_version(&canister_id(2)), 1);
+
+        assert_eq!(policy.rejected_responses(), 2);
+    }
+
+    #[test]
+    fn should_request_the_highest_supported_version() {
+        let policy =
+            VerificationVersionPolicy::new(VerificationVersionRange::new(1, 10), HashMap::new());
+
+        assert_eq!(
+            policy.requested_version(&canister_id(1)),
+            u16::from(MAX_VERIFICATION_VERSION)
+        );
+    }
+}
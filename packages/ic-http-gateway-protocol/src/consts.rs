@@ -1,4 +1,8 @@
-pub(crate) static CACHE_HEADER_NAME: &str = "cache-control";
 pub(crate) static ACCEPT_ENCODING_HEADER_NAME: &str = "accept-encoding";
+pub(crate) static REPR_DIGEST_HEADER_NAME: &str = "repr-digest";
+pub(crate) static CONTENT_DIGEST_HEADER_NAME: &str = "content-digest";
 
 pub(crate) static DEFAULT_BOUNDARY_NODE_ENDPOINT: &str = "https://icp-api.io";
+
+/// The DER-encoded public key of the Internet Computer mainnet.
+pub(crate) static IC_ROOT_KEY: &[u8; 133] = b"\x30\x81\x82\x30\x1d\x06\x0d\x2b\x06\x01\x04\x01\x82\xdc\x7c\x05\x03\x01\x02\x01\x06\x0c\x2b\x06\x01\x04\x01\x82\xdc\x7c\x05\x03\x02\x01\x03\x61\x00\x81\x4c\x0e\x6e\xc7\x1f\xab\x58\x3b\x08\xbd\x81\x37\x3c\x25\x5c\x3c\x37\x1b\x2e\x84\x86\x3c\x98\xa4\xf1\xe0\x8b\x74\x23\x5d\x14\xfb\x5d\x9c\x0c\xd5\x46\xd9\x68\x5f\x91\x3a\x0c\x0b\x2c\xc5\x34\x15\x83\xbf\x4b\x43\x92\xe4\x67\xdb\x96\xd6\x5b\x9b\xb4\xcb\x71\x71\x12\xf8\x47\x2e\x0d\x5a\x4d\x14\x50\x5f\xfd\x74\x84\xb0\x12\x91\x09\x1c\x5f\x87\xb9\x88\x83\x46\x3f\x98\x09\x1a\x0b\xaa\xae";
@@ -1,6 +1,7 @@
 //! The error module contains types for common errors that may be thrown
 //! by other modules in this crate.
 
+use candid::Principal;
 use ic_agent::AgentError;
 use ic_response_verification::ResponseVerificationError;
 use std::sync::Arc;
@@ -27,6 +28,71 @@ pub enum HttpGatewayError {
         header_name: String,
         header_value: String,
     },
+
+    /// The root key could not be loaded from a file.
+    #[error(r#"Failed to load the root key from "{path}": {reason}"#)]
+    RootKeyLoadingError { path: String, reason: String },
+
+    /// Fetching the root key from the network was refused.
+    #[error("Refusing to fetch the root key from {url:?}: {reason}")]
+    RootKeyFetchNotAllowed { url: Option<String>, reason: String },
+
+    /// The configured root key does not match the root key reported by the network.
+    #[error("The configured root key does not match the root key reported by the network")]
+    RootKeyMismatch,
+
+    /// The certificate is older than the allowed offset from the current time.
+    #[error("The certificate is stale, certificate time: {certificate_time_ns}ns, current time: {current_time_ns}ns")]
+    StaleCertificate {
+        certificate_time_ns: u128,
+        current_time_ns: u128,
+    },
+
+    /// The certificate is newer than the allowed offset from the current time.
+    #[error("The certificate is from the future, certificate time: {certificate_time_ns}ns, current time: {current_time_ns}ns")]
+    FutureCertificate {
+        certificate_time_ns: u128,
+        current_time_ns: u128,
+    },
+
+    /// The denylist could not be loaded from a file.
+    #[error(r#"Failed to load the denylist from "{path}": {reason}"#)]
+    DenylistLoadingError { path: String, reason: String },
+
+    /// The delegation chain of a request could not be validated.
+    #[error("Invalid delegation: {reason}")]
+    InvalidDelegation { reason: String },
+
+    /// The candid interface of a canister could not be read from its metadata.
+    #[error("The candid interface of canister {canister_id} is not available: {reason}")]
+    CandidInterfaceUnavailable {
+        canister_id: Principal,
+        reason: String,
+    },
+
+    /// A method that is needed to serve a request is not declared by the canister.
+    #[error(
+        r#"Canister {canister_id} does not declare the {} method "{method}""#,
+        if *query { "query" } else { "update" }
+    )]
+    CanisterMethodNotDeclared {
+        canister_id: Principal,
+        method: String,
+        query: bool,
+    },
+
+    /// The response verification version of a response is not allowed for the canister.
+    #[error("Response verification version {version} of canister {canister_id} is not allowed, the allowed versions are {min} to {max}")]
+    VerificationVersionNotAllowed {
+        canister_id: Principal,
+        version: u16,
+        min: u16,
+        max: u16,
+    },
+
+    /// The verification of a response panicked or was cancelled.
+    #[error("The verification task failed: {reason}")]
+    VerificationTaskFailed { reason: String },
 }
 
 impl From<AgentError> for HttpGatewayError {
@@ -18,3 +18,6 @@ pub(crate) use consts::*;
 
 mod error;
 pub use error::*;
+
+mod metrics;
+pub use metrics::*;
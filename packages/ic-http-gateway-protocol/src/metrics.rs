@@ -0,0 +1,82 @@
+use std::time::Duration;
+
+/// A snapshot of the metrics of an [HttpGatewayClient](crate::HttpGatewayClient).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HttpGatewayMetrics {
+    /// The number of verifications currently waiting for a free worker of the verification pool.
+    pub verification_queue_length: u64,
+
+    /// The number of verifications that have been run on the verification pool.
+    pub verifications: u64,
+
+    /// The total time that verifications have spent waiting for a free worker.
+    pub verification_queue_time: Duration,
+
+    /// The longest time that a single verification has spent waiting for a free worker.
+    pub max_verification_queue_time: Duration,
+
+    /// The number of verifications that were skipped because their result was in the verification cache.
+    pub verification_cache_hits: u64,
+
+    /// The number of verifications whose result was not in the verification cache.
+    pub verification_cache_misses: u64,
+
+    /// The number of results currently in the verification cache.
+    pub verification_cache_size: usize,
+
+    /// The number of requests that were served by the response to an identical concurrent request.
+    pub coalesced_requests: u64,
+
+    /// The number of fresh responses that were served from the response cache.
+    pub response_cache_hits: u64,
+
+    /// The number of stale responses that were served from the response cache,
+    /// while they were revalidated or because the canister returned an error.
+    pub stale_response_cache_hits: u64,
+
+    /// The number of requests that were refused because they matched the denylist.
+    pub denylisted_requests: u64,
+
+    /// The number of times the changed denylist file could not be reloaded.
+    pub denylist_reload_failures: u64,
+
+    /// The number of requests that were refused because they exceeded the query rate limit.
+    pub rate_limited_queries: u64,
+
+    /// The number of update calls that were refused because they exceeded the update rate limit.
+    pub rate_limited_update_calls: u64,
+
+    /// The number of requests whose upgrade to an update call was refused by the read-only mode.
+    pub refused_upgrades: u64,
+
+    /// The number of requests that were re-issued as update calls because their query response was not certified.
+    pub update_fallbacks: u64,
+
+    /// The number of requests that were sent on behalf of a user with a delegation chain.
+    pub delegated_requests: u64,
+
+    /// The number of requests that were refused because their delegation chain was invalid.
+    pub rejected_delegations: u64,
+
+    /// The number of responses that were rejected because their verification version was not allowed.
+    pub rejected_verification_versions: u64,
+}
+
+impl HttpGatewayMetrics {
+    /// The average time that verifications have spent waiting for a free worker,
+    /// or `None` if no verifications have been run on the verification pool yet.
+    pub fn average_verification_queue_time(&self) -> Option<Duration> {
+        u32::try_from(self.verifications)
+            .ok()
+            .filter(|verifications| *verifications > 0)
+            .map(|verifications| self.verification_queue_time / verifications)
+    }
+
+    /// The ratio of verification cache lookups that were hits,
+    /// or `None` if the cache has not been used yet.
+    pub fn verification_cache_hit_rate(&self) -> Option<f64> {
+        let lookups = self.verification_cache_hits + self.verification_cache_misses;
+
+        (lookups > 0).then(|| self.verification_cache_hits as f64 / lookups as f64)
+    }
+}
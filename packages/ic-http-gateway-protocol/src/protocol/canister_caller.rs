@@ -0,0 +1,143 @@
+use crate::{AgentResponseAny, HttpGatewayResult, HttpInterface, HttpInterfaceResolver};
+use candid::{ser::IDLBuilder, CandidType, Principal};
+use ic_agent::{Agent, AgentError};
+use ic_utils::interfaces::http_request::{HeaderField, StreamingCallbackHttpResponse, Token};
+use std::{sync::Arc, time::Duration};
+
+#[derive(CandidType)]
+struct HttpRequest<'a> {
+    method: &'a str,
+    url: &'a str,
+    headers: &'a [HeaderField<'a>],
+    body: &'a [u8],
+    certificate_version: Option<u16>,
+}
+
+#[derive(CandidType)]
+struct HttpUpdateRequest<'a> {
+    method: &'a str,
+    url: &'a str,
+    headers: &'a [HeaderField<'a>],
+    body: &'a [u8],
+}
+
+/// The overrides of a request that apply to all of its calls,
+/// including the calls for the chunks of a streamed body.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct CallOverrides {
+    pub ingress_expiry: Option<Duration>,
+    pub effective_canister_id: Option<Principal>,
+}
+
+/// Makes the query and update calls of a request to the methods of a canister's [HttpInterface].
+#[derive(Clone, Copy)]
+pub(crate) struct CanisterCaller<'a> {
+    pub agent: &'a Agent,
+    pub canister_id: Principal,
+    pub overrides: CallOverrides,
+    pub http_interface_resolver: Option<&'a HttpInterfaceResolver>,
+}
+
+impl<'a> CanisterCaller<'a> {
+    pub fn new(agent: &'a Agent, canister_id: Principal) -> Self {
+        Self {
+            agent,
+            canister_id,
+            overrides: CallOverrides::default(),
+            http_interface_resolver: None,
+        }
+    }
+
+    pub async fn http_interface(&self) -> HttpGatewayResult<Arc<HttpInterface>> {
+        match self.http_interface_resolver {
+            Some(http_interface_resolver) => {
+                http_interface_resolver
+                    .resolve(self.agent, self.canister_id)
+                    .await
+            }
+            None => Ok(Arc::default()),
+        }
+    }
+
+    pub async fn http_request(
+        &self,
+        method_name: &str,
+        method: &str,
+        url: &str,
+        headers: &[HeaderField<'_>],
+        body: &[u8],
+        certificate_version: u16,
+    ) -> Result<AgentResponseAny, AgentError> {
+        let arg = candid::encode_one(HttpRequest {
+            method,
+            url,
+            headers,
+            body,
+            certificate_version: Some(certificate_version),
+        })?;
+
+        let mut query = self
+            .agent
+            .query(&self.canister_id, method_name)
+            .with_arg(arg);
+        if let Some(effective_canister_id) = self.overrides.effective_canister_id {
+            query = query.with_effective_canister_id(effective_canister_id);
+        }
+        if let Some(ingress_expiry) = self.overrides.ingress_expiry {
+            query = query.expire_after(ingress_expiry);
+        }
+
+        Ok(candid::decode_one(&query.call().await?)?)
+    }
+
+    pub async fn http_request_stream_callback(
+        &self,
+        method_name: &str,
+        token: Token,
+    ) -> Result<StreamingCallbackHttpResponse<Token>, AgentError> {
+        // tokens can be of any type, so they are encoded as the values they were decoded as
+        let arg = IDLBuilder::new().value_arg(&token.0)?.serialize_to_vec()?;
+
+        let mut query = self
+            .agent
+            .query(&self.canister_id, method_name)
+            .with_arg(arg);
+        if let Some(effective_canister_id) = self.overrides.effective_canister_id {
+            query = query.with_effective_canister_id(effective_canister_id);
+        }
+        if let Some(ingress_expiry) = self.overrides.ingress_expiry {
+            query = query.expire_after(ingress_expiry);
+        }
+
+        Ok(candid::decode_one(&query.call().await?)?)
+    }
+
+    pub async fn http_request_update(
+        &self,
+        method_name: &str,
+        method: &str,
+        url: &str,
+        headers: &[HeaderField<'_>],
+        body: &[u8],
+    ) -> Result<AgentResponseAny, AgentError> {
+        let arg = candid::encode_one(HttpUpdateRequest {
+            method,
+            url,
+            headers,
+            body,
+        })?;
+
+        let mut update = self
+            .agent
+            .update(&self.canister_id, method_name)
+            .with_arg(arg);
+        if let Some(effective_canister_id) = self.overrides.effective_canister_id {
+            update = update.with_effective_canister_id(effective_canister_id);
+        }
+        if let Some(ingress_expiry) = self.overrides.ingress_expiry {
+            update = update.expire_after(ingress_expiry);
+        }
+
+        Ok(candid::decode_one(&update.call_and_wait().await?)?)
+    }
+}
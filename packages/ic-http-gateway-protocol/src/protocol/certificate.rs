@@ -0,0 +1,265 @@
+use crate::{CertificateDelegation, ResponseCertificateMetadata};
+use candid::Principal;
+use ic_certification::{Certificate, LookupResult};
+use ic_http_certification::{CERTIFICATE_EXPRESSION_HEADER_NAME, CERTIFICATE_HEADER_NAME};
+use ic_response_verification::CertificateHeader;
+use std::time::{Duration, UNIX_EPOCH};
+
+/// The certificate of a response, as provided by the `IC-Certificate` header.
+pub struct ResponseCertificate {
+    /// The decoded certificate.
+    pub certificate: Certificate,
+
+    /// The response verification version of the response.
+    pub version: u16,
+
+    /// The path of the certified expression, only present for response verification v2.
+    pub expr_path: Option<Vec<String>>,
+}
+
+impl ResponseCertificate {
+    /// Parses the certificate from the value of an `IC-Certificate` header.
+    pub fn parse(header_value: &str) -> Option<Self> {
+        let certificate_header = CertificateHeader::from(header_value).ok()?;
+
+        Some(Self {
+            certificate: certificate_header.certificate,
+            version: u16::from(certificate_header.version),
+            expr_path: certificate_header.expr_path,
+        })
+    }
+
+    /// The time at which the certificate was created, in nanoseconds since the UNIX epoch.
+    pub fn time_ns(&self) -> Option<u128> {
+        get_certificate_time_ns(&self.certificate)
+    }
+
+    /// The decoded certificate, with the value of the `IC-CertificateExpression` header.
+    pub fn into_metadata(
+        self,
+        certificate_expression: Option<&str>,
+    ) -> ResponseCertificateMetadata {
+        ResponseCertificateMetadata {
+            time: self
+                .time_ns()
+                .and_then(|time_ns| u64::try_from(time_ns).ok())
+                .map(|time_ns| UNIX_EPOCH + Duration::from_nanos(time_ns)),
+            delegation: self
+                .certificate
+                .delegation
+                .map(|delegation| CertificateDelegation {
+                    subnet_id: Principal::from_slice(&delegation.subnet_id),
+                    certificate: delegation.certificate,
+                }),
+            expr_path: self.expr_path,
+            certificate_expression: certificate_expression.map(str::to_string),
+        }
+    }
+}
+
+/// Decodes the `IC-Certificate` and `IC-CertificateExpression` headers, if present.
+pub fn get_certificate_metadata<'a>(
+    headers: impl IntoIterator<Item = (&'a str, &'a str)> + Clone,
+) -> Option<ResponseCertificateMetadata> {
+    let certificate = ResponseCertificate::parse(get_certificate_header(headers.clone())?)?;
+    let certificate_expression = headers
+        .into_iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(CERTIFICATE_EXPRESSION_HEADER_NAME))
+        .map(|(_, value)| value);
+
+    Some(certificate.into_metadata(certificate_expression))
+}
+
+/// Returns the value of the `IC-Certificate` header, if present.
+pub fn get_certificate_header<'a>(
+    headers: impl IntoIterator<Item = (&'a str, &'a str)>,
+) -> Option<&'a str> {
+    headers
+        .into_iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(CERTIFICATE_HEADER_NAME))
+        .map(|(_, value)| value)
+}
+
+/// Returns the time at which the certificate was created, in nanoseconds since the UNIX epoch.
+pub fn get_certificate_time_ns(certificate: &Certificate) -> Option<u128> {
+    match certificate.tree.lookup_path(["time"]) {
+        LookupResult::Found(time) => decode_leb128(time),
+        _ => None,
+    }
+}
+
+fn decode_leb128(bytes: &[u8]) -> Option<u128> {
+    let mut value: u128 = 0;
+
+    for (i, byte) in bytes.iter().enumerate() {
+        let shift = u32::try_from(i * 7).ok().filter(|shift| *shift < 128)?;
+        value |= u128::from(byte & 0x7f).checked_shl(shift)?;
+
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+    use ic_certification::{
+        hash_tree::{empty, fork, label, leaf},
+        HashTree,
+    };
+    use ic_verify_bls_signature::PrivateKey;
+    use rand_chacha::{rand_core::SeedableRng, ChaCha20Rng};
+    use sha2::{Digest, Sha256};
+
+    /// The DER prefix of a BLS public key, as expected by the agent's root key.
+    const ROOT_KEY_DER_PREFIX: &[u8; 37] = b"\x30\x81\x82\x30\x1d\x06\x0d\x2b\x06\x01\x04\x01\x82\xdc\x7c\x05\x03\x01\x02\x01\x06\x0c\x2b\x06\x01\x04\x01\x82\xdc\x7c\x05\x03\x02\x01\x03\x61\x00";
+
+    pub(crate) fn encode_leb128(mut value: u128) -> Vec<u8> {
+        let mut bytes = vec![];
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                bytes.push(byte);
+                return bytes;
+            }
+            bytes.push(byte | 0x80);
+        }
+    }
+
+    /// Creates an `IC-Certificate` header value with an unsigned certificate created at the given time.
+    pub(crate) fn create_certificate_header(certificate_time_ns: u128) -> String {
+        let certificate = Certificate {
+            tree: label("time", leaf(encode_leb128(certificate_time_ns))),
+            signature: vec![0; 48],
+            delegation: None,
+        };
+        let certificate_cbor = serde_cbor::to_vec(&certificate).unwrap();
+        let tree_cbor = serde_cbor::to_vec(&empty::<Vec<u8>>()).unwrap();
+
+        format!(
+            "certificate=:{}:, tree=:{}:, version=2, expr_path=:{}:",
+            BASE64.encode(certificate_cbor),
+            BASE64.encode(tree_cbor),
+            BASE64.encode(serde_cbor::to_vec(&vec!["http_expr", "<*>"]).unwrap()),
+        )
+    }
+
+    /// Creates an `IC-Certificate` header value that certifies the body at the path with response verification v1,
+    /// together with the DER-encoded root key that signed the certificate.
+    pub(crate) fn create_v1_certificate_header(
+        canister_id: &Principal,
+        path: &str,
+        body: &[u8],
+        certificate_time_ns: u128,
+    ) -> (Vec<u8>, String) {
+        let asset_tree: HashTree = label(
+            "http_assets",
+            label(path, leaf(Sha256::digest(body).to_vec())),
+        );
+        let tree = fork(
+            label(
+                "canister",
+                label(
+                    canister_id.as_slice(),
+                    label("certified_data", leaf(asset_tree.digest().to_vec())),
+                ),
+            ),
+            label("time", leaf(encode_leb128(certificate_time_ns))),
+        );
+
+        let root_key = PrivateKey::random(&mut ChaCha20Rng::seed_from_u64(0));
+        let mut message = b"\x0Dic-state-root".to_vec();
+        message.extend_from_slice(&tree.digest());
+        let certificate = Certificate {
+            signature: root_key.sign(&message).serialize().to_vec(),
+            tree,
+            delegation: None,
+        };
+
+        let mut root_key_der = ROOT_KEY_DER_PREFIX.to_vec();
+        root_key_der.extend_from_slice(&root_key.public_key().serialize());
+
+        (
+            root_key_der,
+            format!(
+                "certificate=:{}:, tree=:{}:",
+                BASE64.encode(serde_cbor::to_vec(&certificate).unwrap()),
+                BASE64.encode(serde_cbor::to_vec(&asset_tree).unwrap()),
+            ),
+        )
+    }
+
+    #[test]
+    fn should_decode_leb128() {
+        for value in [
+            0,
+            1,
+            127,
+            128,
+            300,
+            1_700_000_000_000_000_000,
+            u64::MAX as u128,
+        ] {
+            assert_eq!(decode_leb128(&encode_leb128(value)), Some(value));
+        }
+    }
+
+    #[test]
+    fn should_fail_decoding_truncated_leb128() {
+        assert_eq!(decode_leb128(&[]), None);
+        assert_eq!(decode_leb128(&[0x80, 0x80]), None);
+    }
+
+    #[test]
+    fn should_parse_response_certificate() {
+        let certificate_time_ns = 1_700_000_000_000_000_000;
+        let certificate =
+            ResponseCertificate::parse(&create_certificate_header(certificate_time_ns)).unwrap();
+
+        assert_eq!(certificate.time_ns(), Some(certificate_time_ns));
+        assert_eq!(certificate.version, 2);
+        assert!(ResponseCertificate::parse("certificate=:not base64:").is_none());
+    }
+
+    #[test]
+    fn should_get_certificate_metadata() {
+        let certificate_header = create_certificate_header(1_700_000_000_000_000_000);
+        let headers = [
+            ("Content-Type", "text/html"),
+            ("IC-Certificate", certificate_header.as_str()),
+            (
+                "IC-CertificateExpression",
+                "default_certification(ValidationArgs{no_certification:Empty{}})",
+            ),
+        ];
+
+        assert_eq!(
+            get_certificate_metadata(headers),
+            Some(ResponseCertificateMetadata {
+                time: Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+                delegation: None,
+                expr_path: Some(vec!["http_expr".to_string(), "<*>".to_string()]),
+                certificate_expression: Some(
+                    "default_certification(ValidationArgs{no_certification:Empty{}})".to_string()
+                ),
+            })
+        );
+        assert_eq!(get_certificate_metadata(headers[..1].iter().copied()), None);
+    }
+
+    #[test]
+    fn should_get_certificate_header() {
+        let headers = [("Content-Type", "text/html"), ("ic-certificate", "value")];
+
+        assert_eq!(get_certificate_header(headers), Some("value"));
+        assert_eq!(
+            get_certificate_header([("Content-Type", "text/html")]),
+            None
+        );
+    }
+}
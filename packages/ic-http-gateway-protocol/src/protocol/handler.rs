@@ -1,23 +1,26 @@
-use super::validate;
+use super::{
+    get_certificate_metadata, validate_on_pool, CanisterCaller, VerifiableRequest,
+    VerifiableResponse,
+};
 use crate::{
     get_206_stream_response_body_and_total_length, get_body_and_streaming_body, CanisterRequest,
-    CanisterResponse, HttpGatewayError, HttpGatewayResponse, HttpGatewayResponseBody,
-    HttpGatewayResponseMetadata, HttpGatewayResult, ACCEPT_ENCODING_HEADER_NAME, CACHE_HEADER_NAME,
+    CanisterResponse, CertifiedResponse, HttpGatewayError, HttpGatewayResponse,
+    HttpGatewayResponseBody, HttpGatewayResponseMetadata, HttpGatewayResult,
+    ResponseCertificateMetadata, ResponseStreamingOptions, ResponseVerificationOptions,
+    UpgradeGuard, V1HeaderPolicy, VerificationReport, ACCEPT_ENCODING_HEADER_NAME,
+    BODY_DIGEST_TRAILER_NAME, RESPONSE_BODY_STREAM_TRAILER_NAMES,
 };
-use candid::Principal;
 use http::header as http_header;
-use http::{Response, StatusCode};
+use http::{HeaderMap, Response, StatusCode, Version};
 use http_body_util::{BodyExt, Either, Full};
 use ic_agent::{
     agent::{RejectCode, RejectResponse},
-    Agent, AgentError,
-};
-use ic_http_certification::{HttpRequest, HttpResponse};
-use ic_response_verification::MAX_VERIFICATION_VERSION;
-use ic_utils::{
-    call::{AsyncCall, SyncCall},
-    interfaces::{http_request::HeaderField, HttpRequestCanister},
+    AgentError,
 };
+use ic_http_certification::{CERTIFICATE_EXPRESSION_HEADER_NAME, CERTIFICATE_HEADER_NAME};
+use ic_response_verification::{types::VerificationInfo, ResponseVerificationError};
+use ic_utils::interfaces::http_request::HeaderField;
+use std::{mem, time::SystemTime};
 
 fn create_err_response(status_code: StatusCode, msg: &str) -> CanisterResponse {
     let mut response = Response::new(HttpGatewayResponseBody::Right(Full::from(
@@ -28,45 +31,46 @@ fn create_err_response(status_code: StatusCode, msg: &str) -> CanisterResponse {
     response
 }
 
-fn convert_request(request: CanisterRequest) -> HttpGatewayResult<HttpRequest<'static>> {
-    let uri = request.uri();
-    let mut url = uri.path().to_string();
-    if let Some(query) = uri.query() {
+fn convert_request(request: CanisterRequest) -> HttpGatewayResult<VerifiableRequest> {
+    let (parts, body) = request.into_parts();
+    let mut url = parts.uri.path().to_string();
+    if let Some(query) = parts.uri.query() {
         url.push('?');
         url.push_str(query);
     }
 
-    Ok(HttpRequest::builder()
-        .with_method(request.method().clone())
-        .with_url(url)
-        .with_headers(
-            request
-                .headers()
-                .into_iter()
-                .map(|(name, value)| {
-                    Ok((
-                        name.to_string(),
-                        value
-                            .to_str()
-                            .map_err(|_| HttpGatewayError::HeaderValueParsingError {
-                                header_name: name.to_string(),
-                                header_value: String::from_utf8_lossy(value.as_bytes()).to_string(),
-                            })?
-                            .to_string(),
-                    ))
-                })
-                .collect::<HttpGatewayResult<Vec<_>>>()?,
-        )
-        .with_body(request.body().to_vec())
-        .build())
+    Ok(VerifiableRequest {
+        method: parts.method,
+        url,
+        headers: parts
+            .headers
+            .iter()
+            .map(|(name, value)| {
+                Ok((
+                    name.to_string(),
+                    value
+                        .to_str()
+                        .map_err(|_| HttpGatewayError::HeaderValueParsingError {
+                            header_name: name.to_string(),
+                            header_value: String::from_utf8_lossy(value.as_bytes()).to_string(),
+                        })?
+                        .to_string(),
+                ))
+            })
+            .collect::<HttpGatewayResult<Vec<_>>>()?,
+        body,
+    })
 }
 
 pub async fn process_request(
-    agent: &Agent,
+    canister_caller: CanisterCaller<'_>,
     request: CanisterRequest,
-    canister_id: Principal,
     skip_verification: bool,
+    response_verification_options: &ResponseVerificationOptions,
+    response_streaming_options: &ResponseStreamingOptions,
+    upgrade_guard: UpgradeGuard<'_>,
 ) -> HttpGatewayResponse {
+    let request_version = request.version();
     let http_request = match convert_request(request) {
         Ok(http_request) => http_request,
         Err(e) => {
@@ -76,18 +80,30 @@ pub async fn process_request(
                     &format!("Failed to parse request: {}", e),
                 ),
                 metadata: HttpGatewayResponseMetadata {
-                    upgraded_to_update_call: false,
-                    response_verification_version: None,
                     internal_error: Some(e),
+                    ..Default::default()
                 },
             }
         }
     };
 
-    let canister = HttpRequestCanister::create(agent, canister_id);
+    let agent = canister_caller.agent;
+    let canister_id = canister_caller.canister_id;
+    let http_interface = match canister_caller.http_interface().await {
+        Ok(http_interface) => http_interface,
+        Err(e) => {
+            return HttpGatewayResponse {
+                canister_response: create_err_response(StatusCode::BAD_GATEWAY, &e.to_string()),
+                metadata: HttpGatewayResponseMetadata {
+                    internal_error: Some(e),
+                    ..Default::default()
+                },
+            }
+        }
+    };
     let mut is_range_request = false;
     let header_fields = http_request
-        .headers()
+        .headers
         .iter()
         .filter(|(name, _)| name != "x-request-id")
         .map(|(name, value)| {
@@ -107,147 +123,202 @@ pub async fn process_request(
 
             HeaderField(name.into(), value.into())
         })
-        .collect::<Vec<HeaderField>>()
-        .into_iter();
-
-    let query_result = canister
-        .http_request_custom(
-            http_request.method().as_str(),
-            http_request.url(),
-            header_fields.clone(),
-            http_request.body(),
-            Some(&u16::from(MAX_VERIFICATION_VERSION)),
+        .collect::<Vec<HeaderField>>();
+
+    let query_result = canister_caller
+        .http_request(
+            &http_interface.http_request,
+            http_request.method.as_str(),
+            &http_request.url,
+            &header_fields,
+            &http_request.body,
+            response_verification_options
+                .verification_version_policy
+                .requested_version(&canister_caller.canister_id),
         )
-        .call()
         .await;
 
-    let agent_response = match query_result {
-        Ok((response,)) => response,
+    let mut agent_response = match query_result {
+        Ok(response) => response,
         Err(e) => {
             return HttpGatewayResponse {
                 canister_response: handle_agent_error(&e),
                 metadata: HttpGatewayResponseMetadata {
-                    upgraded_to_update_call: false,
-                    response_verification_version: None,
                     internal_error: Some(e.into()),
+                    ..Default::default()
                 },
             };
         }
     };
 
-    let is_update_call = agent_response.upgrade == Some(true);
-    let agent_response = if is_update_call {
-        let update_result = canister
-            .http_request_update_custom(
-                http_request.method().as_str(),
-                http_request.url(),
-                header_fields.clone(),
-                http_request.body(),
-            )
-            .call_and_wait()
-            .await;
+    let path = http_request
+        .url
+        .split_once('?')
+        .map_or(http_request.url.as_str(), |(path, _)| path);
+    let mut update_rate_limit = None;
+    // set if the query response is not certified and the request is retried as an update call
+    let mut update_fallback_reason = None;
+    let (is_update_call, response_body, validation_info) = loop {
+        let is_update_call =
+            update_fallback_reason.is_some() || agent_response.upgrade == Some(true);
+        if is_update_call {
+            let Some(http_request_update) = &http_interface.http_request_update else {
+                let e = HttpGatewayError::CanisterMethodNotDeclared {
+                    canister_id,
+                    method: "http_request_update".to_string(),
+                    query: false,
+                };
 
-        match update_result {
-            Ok((response,)) => response,
-            Err(e) => {
                 return HttpGatewayResponse {
-                    canister_response: handle_agent_error(&e),
+                    canister_response: create_err_response(StatusCode::BAD_GATEWAY, &e.to_string()),
                     metadata: HttpGatewayResponseMetadata {
                         upgraded_to_update_call: true,
-                        response_verification_version: None,
-                        internal_error: Some(e.into()),
+                        internal_error: Some(e),
+                        update_fallback_reason,
+                        ..Default::default()
                     },
                 };
+            };
+            match upgrade_guard.check(canister_id, &http_request.method, path) {
+                Ok(status) => update_rate_limit = status,
+                Err(refused_upgrade) => return refused_upgrade.into_response(),
             }
+
+            let update_result = canister_caller
+                .http_request_update(
+                    http_request_update,
+                    http_request.method.as_str(),
+                    &http_request.url,
+                    &header_fields,
+                    &http_request.body,
+                )
+                .await;
+
+            agent_response = match update_result {
+                Ok(response) => response,
+                Err(e) => {
+                    return HttpGatewayResponse {
+                        canister_response: handle_agent_error(&e),
+                        metadata: HttpGatewayResponseMetadata {
+                            upgraded_to_update_call: true,
+                            internal_error: Some(e.into()),
+                            rate_limit: update_rate_limit,
+                            update_fallback_reason,
+                            ..Default::default()
+                        },
+                    };
+                }
+            };
         }
-    } else {
-        agent_response
-    };
 
-    let response_body = match get_body_and_streaming_body(agent, &agent_response).await {
-        Ok(response_body) => response_body,
-        Err(e) => {
-            return HttpGatewayResponse {
-                canister_response: create_err_response(
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    &format!("Failed to parse response body: {}", e),
-                ),
-                metadata: HttpGatewayResponseMetadata {
-                    upgraded_to_update_call: is_update_call,
-                    response_verification_version: None,
-                    internal_error: Some(e.into()),
-                },
+        let response_body = match get_body_and_streaming_body(
+            canister_caller,
+            mem::take(&mut agent_response.body),
+            &agent_response.headers,
+            agent_response.streaming_strategy.take(),
+            response_streaming_options,
+        )
+        .await
+        {
+            Ok(response_body) => response_body,
+            Err(e) => {
+                return HttpGatewayResponse {
+                    canister_response: create_err_response(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        &format!("Failed to parse response body: {}", e),
+                    ),
+                    metadata: HttpGatewayResponseMetadata {
+                        upgraded_to_update_call: is_update_call,
+                        internal_error: Some(e.into()),
+                        rate_limit: update_rate_limit,
+                        update_fallback_reason,
+                        ..Default::default()
+                    },
+                }
             }
-        }
-    };
+        };
 
-    // There is no need to verify the response if the request was upgraded to an update call.
-    let validation_info = if !is_update_call {
-        // At the moment verification is only performed if the response is not using a streaming
-        // strategy. Performing verification for those requests would require to join all the chunks
-        // and this could cause memory issues and possibly create DOS attack vectors.
-        match &response_body {
-            Either::Right(body) => {
-                // this unwrap should never panic because `Either::Right` will always have a full body
-                let body = body.clone().collect().await.unwrap().to_bytes().to_vec();
-
-                let status_code = match StatusCode::from_u16(agent_response.status_code) {
-                    Ok(status) => status,
-                    Err(e) => {
-                        return HttpGatewayResponse {
-                            canister_response: create_err_response(
-                                StatusCode::INTERNAL_SERVER_ERROR,
-                                &format!("Invalid canister response status code: {}", e),
-                            ),
-                            metadata: HttpGatewayResponseMetadata {
-                                upgraded_to_update_call: is_update_call,
-                                response_verification_version: None,
-                                internal_error: Some(http::Error::from(e).into()),
-                            },
-                        };
-                    }
-                };
-                let response = HttpResponse::builder()
-                    .with_status_code(status_code)
-                    .with_headers(
-                        agent_response
-                            .headers
-                            .iter()
-                            .map(|HeaderField(k, v)| (k.to_string(), v.to_string()))
-                            .collect(),
+        // There is no need to verify the response if the request was upgraded to an update call.
+        let validation_info = if !is_update_call {
+            // At the moment verification is only performed if the response is not using a streaming
+            // strategy. Performing verification for those requests would require to join all the chunks
+            // and this could cause memory issues and possibly create DOS attack vectors.
+            match &response_body {
+                Either::Right(body) => {
+                    // this unwrap should never panic because `Either::Right` will always have a full body,
+                    // which is collected without copying
+                    let body = body.clone().collect().await.unwrap().to_bytes();
+
+                    let status_code = match StatusCode::from_u16(agent_response.status_code) {
+                        Ok(status) => status,
+                        Err(e) => {
+                            return HttpGatewayResponse {
+                                canister_response: create_err_response(
+                                    StatusCode::INTERNAL_SERVER_ERROR,
+                                    &format!("Invalid canister response status code: {}", e),
+                                ),
+                                metadata: HttpGatewayResponseMetadata {
+                                    upgraded_to_update_call: is_update_call,
+                                    internal_error: Some(http::Error::from(e).into()),
+                                    rate_limit: update_rate_limit,
+                                    update_fallback_reason,
+                                    ..Default::default()
+                                },
+                            };
+                        }
+                    };
+                    let response =
+                        VerifiableResponse::new(status_code, &agent_response.headers, body);
+
+                    let validation_result = validate_on_pool(
+                        agent,
+                        &canister_id,
+                        &http_request,
+                        &response,
+                        skip_verification,
+                        response_verification_options,
                     )
-                    .with_body(body)
-                    .build();
-
-                let validation_result = validate(
-                    agent,
-                    &canister_id,
-                    http_request.clone(),
-                    response,
-                    skip_verification,
-                );
-
-                match validation_result {
-                    Err(e) => {
-                        return HttpGatewayResponse {
-                            canister_response: create_err_response(
-                                StatusCode::INTERNAL_SERVER_ERROR,
-                                &format!("Response verification failed: {}", e),
-                            ),
-                            metadata: HttpGatewayResponseMetadata {
-                                upgraded_to_update_call: is_update_call,
-                                response_verification_version: None,
-                                internal_error: Some(e),
-                            },
-                        };
+                    .await;
+
+                    match validation_result {
+                        Err(e)
+                            if update_fallback_reason.is_none()
+                                && is_certification_missing(&e)
+                                && http_interface.http_request_update.is_some()
+                                && upgrade_guard.allows_update_fallback(
+                                    canister_id,
+                                    &http_request.method,
+                                    path,
+                                ) =>
+                        {
+                            update_fallback_reason = Some(e);
+                            continue;
+                        }
+                        Err(e) => {
+                            return HttpGatewayResponse {
+                                canister_response: create_err_response(
+                                    StatusCode::INTERNAL_SERVER_ERROR,
+                                    &format!("Response verification failed: {}", e),
+                                ),
+                                metadata: HttpGatewayResponseMetadata {
+                                    upgraded_to_update_call: is_update_call,
+                                    internal_error: Some(e),
+                                    rate_limit: update_rate_limit,
+                                    update_fallback_reason,
+                                    ..Default::default()
+                                },
+                            };
+                        }
+                        Ok(validation_info) => validation_info,
                     }
-                    Ok(validation_info) => validation_info,
                 }
+                _ => None,
             }
-            _ => None,
-        }
-    } else {
-        None
+        } else {
+            None
+        };
+
+        break (is_update_call, response_body, validation_info);
     };
 
     let response_verification_version = validation_info.as_ref().map(|e| e.verification_version);
@@ -264,6 +335,9 @@ pub async fn process_request(
                     upgraded_to_update_call: is_update_call,
                     response_verification_version,
                     internal_error: Some(http::Error::from(e).into()),
+                    rate_limit: update_rate_limit,
+                    update_fallback_reason,
+                    ..Default::default()
                 },
             }
         }
@@ -281,8 +355,12 @@ pub async fn process_request(
 
         Some(validation_info) => {
             if validation_info.verification_version < 2 {
-                // status codes are not certified in v1, reject known dangerous status codes
-                if agent_response.status_code >= 300 && agent_response.status_code < 400 {
+                let Some(v1_response_builder) = filter_v1_response(
+                    response_builder,
+                    status_code,
+                    &agent_response.headers,
+                    &response_verification_options.v1_header_policy,
+                ) else {
                     return HttpGatewayResponse {
                         canister_response: create_err_response(
                             StatusCode::INTERNAL_SERVER_ERROR,
@@ -291,17 +369,13 @@ pub async fn process_request(
                         metadata: HttpGatewayResponseMetadata {
                             upgraded_to_update_call: is_update_call,
                             response_verification_version,
-                            internal_error: None,
+                            rate_limit: update_rate_limit,
+                            update_fallback_reason,
+                            ..Default::default()
                         },
                     };
-                }
-
-                // headers are also not certified in v1, filter known dangerous headers
-                for HeaderField(name, value) in &agent_response.headers {
-                    if !name.eq_ignore_ascii_case(CACHE_HEADER_NAME) {
-                        response_builder = response_builder.header(name.as_ref(), value.as_ref());
-                    }
-                }
+                };
+                response_builder = v1_response_builder;
             } else {
                 match &validation_info.response {
                     // if there is no response, the canister has decided to certifiably skip verification,
@@ -348,17 +422,43 @@ pub async fn process_request(
         }
     }
 
+    // the headers that the gateway removes on purpose, rather than for lack of certification
+    let mut stripped_header_names = vec![];
+    if !is_range_request && status_code == 206 {
+        stripped_header_names.extend([
+            http_header::CONTENT_RANGE.as_str(),
+            http_header::CONTENT_LENGTH.as_str(),
+        ]);
+    }
+    if response_verification_options.strip_certificate_headers {
+        if let Some(headers) = response_builder.headers_mut() {
+            headers.remove(CERTIFICATE_HEADER_NAME);
+            headers.remove(CERTIFICATE_EXPRESSION_HEADER_NAME);
+        }
+        stripped_header_names.extend([CERTIFICATE_HEADER_NAME, CERTIFICATE_EXPRESSION_HEADER_NAME]);
+    }
+    let certificate = validation_info.as_ref().and_then(|_| {
+        get_certificate_metadata(
+            agent_response
+                .headers
+                .iter()
+                .map(|HeaderField(name, value)| (name.as_ref(), value.as_ref())),
+        )
+    });
     let response_body: HttpGatewayResponseBody = if status_code == 206 && !is_range_request {
         // We got only the first chunk, add a correct content-length-header,
         // and turn the response into a streaming response.
         let (stream_response_body, content_length) =
             match get_206_stream_response_body_and_total_length(
-                agent,
+                canister_caller,
                 http_request,
-                canister_id,
+                http_interface.http_request.clone(),
                 &agent_response.headers,
+                validation_info.as_ref(),
                 response_body,
                 skip_verification,
+                response_verification_options.clone(),
+                response_streaming_options,
             )
             .await
             {
@@ -375,6 +475,9 @@ pub async fn process_request(
                             upgraded_to_update_call: is_update_call,
                             response_verification_version,
                             internal_error: Some(e.into()),
+                            rate_limit: update_rate_limit,
+                            update_fallback_reason,
+                            ..Default::default()
                         },
                     }
                 }
@@ -387,6 +490,27 @@ pub async fn process_request(
         response_body
     };
 
+    // streamed bodies end with trailers reporting their verification status
+    if let Either::Left(_) = response_body {
+        if let Some(trailer_names) =
+            get_trailer_header_value(request_version, response_builder.headers_ref())
+        {
+            response_builder = response_builder.header(http_header::TRAILER, trailer_names);
+        }
+    }
+
+    // the report is based on the headers that are returned to the client
+    let verification_report = validation_info.as_ref().map(|validation_info| {
+        create_verification_report(
+            validation_info,
+            certificate.as_ref(),
+            &agent_response.headers,
+            response_builder.headers_ref(),
+            &stripped_header_names,
+            response_verification_options.clock.now(),
+        )
+    });
+
     let response = match response_builder.body(response_body) {
         Ok(response) => response,
         Err(e) => {
@@ -399,6 +523,9 @@ pub async fn process_request(
                     upgraded_to_update_call: is_update_call,
                     response_verification_version,
                     internal_error: Some(e.into()),
+                    rate_limit: update_rate_limit,
+                    update_fallback_reason,
+                    ..Default::default()
                 },
             }
         }
@@ -409,11 +536,136 @@ pub async fn process_request(
         metadata: HttpGatewayResponseMetadata {
             upgraded_to_update_call: is_update_call,
             response_verification_version,
-            internal_error: None,
+            rate_limit: update_rate_limit,
+            update_fallback_reason,
+            certificate,
+            verification_report,
+            ..Default::default()
         },
     }
 }
 
+/// Whether the verification failed because the canister did not certify the response,
+/// as opposed to the certification being invalid or not acceptable to the gateway.
+/// A certification that does not match the response, e.g. because of a hash mismatch, is invalid.
+fn is_certification_missing(error: &HttpGatewayError) -> bool {
+    matches!(
+        error,
+        HttpGatewayError::ResponseVerificationError(
+            ResponseVerificationError::HeaderMissingCertification
+                | ResponseVerificationError::HeaderMissingCertificate
+                | ResponseVerificationError::HeaderMissingTree
+                | ResponseVerificationError::HeaderMissingCertificateExpressionPath
+                | ResponseVerificationError::HeaderMissingCertificateExpression
+                | ResponseVerificationError::CertificateMissingCertifiedData { .. }
+                | ResponseVerificationError::ExactExpressionPathNotFoundInTree { .. }
+                | ResponseVerificationError::WildcardExpressionPathNotFoundInTree { .. }
+        )
+    )
+}
+
+/// Status codes and headers are not certified in v1, so redirects are rejected with `None`
+/// and only the headers allowed by the [V1HeaderPolicy] are added to the response.
+fn filter_v1_response(
+    mut response_builder: http::response::Builder,
+    status_code: StatusCode,
+    headers: &[HeaderField<'_>],
+    v1_header_policy: &V1HeaderPolicy,
+) -> Option<http::response::Builder> {
+    if status_code.is_redirection() {
+        return None;
+    }
+
+    for HeaderField(name, value) in headers {
+        if v1_header_policy.allows(name) {
+            response_builder = response_builder.header(name.as_ref(), value.as_ref());
+        }
+    }
+
+    Some(response_builder)
+}
+
+/// Returns the value of the `Trailer` header of a streamed body, if the response can carry trailers,
+/// i.e. over HTTP/2 and later, or over HTTP/1.1 with chunked encoding, which rules out a `Content-Length`.
+/// A `Repr-Digest` that the canister already sent as a header is not advertised again.
+fn get_trailer_header_value(version: Version, headers: Option<&HeaderMap>) -> Option<String> {
+    let has_header = |name: &str| headers.is_some_and(|headers| headers.contains_key(name));
+    let can_carry_trailers = version >= Version::HTTP_2
+        || (version == Version::HTTP_11 && !has_header(http_header::CONTENT_LENGTH.as_str()));
+    if !can_carry_trailers {
+        return None;
+    }
+
+    let trailer_names = RESPONSE_BODY_STREAM_TRAILER_NAMES
+        .into_iter()
+        .filter(|name| *name != BODY_DIGEST_TRAILER_NAME || !has_header(name))
+        .collect::<Vec<_>>();
+
+    Some(trailer_names.join(", "))
+}
+
+/// Reports what the verification certified, and which of the canister's `headers`
+/// are missing from the `returned_headers`, either because they are among the `stripped_header_names`
+/// or because they were dropped.
+fn create_verification_report(
+    validation_info: &VerificationInfo,
+    certificate: Option<&ResponseCertificateMetadata>,
+    headers: &[HeaderField<'_>],
+    returned_headers: Option<&HeaderMap>,
+    stripped_header_names: &[&str],
+    now: SystemTime,
+) -> VerificationReport {
+    let certificate_time = certificate.and_then(|certificate| certificate.time);
+    let subnet_id = certificate
+        .and_then(|certificate| certificate.delegation.as_ref())
+        .map(|delegation| delegation.subnet_id);
+
+    let mut dropped_headers = Vec::<String>::new();
+    let mut stripped_headers = Vec::<String>::new();
+    for HeaderField(name, _) in headers {
+        let returned = returned_headers.is_some_and(|returned_headers| {
+            returned_headers.contains_key(name.to_ascii_lowercase().as_str())
+        });
+        if returned {
+            continue;
+        }
+
+        let missing_headers = if stripped_header_names
+            .iter()
+            .any(|stripped| stripped.eq_ignore_ascii_case(name))
+        {
+            &mut stripped_headers
+        } else {
+            &mut dropped_headers
+        };
+        if !missing_headers
+            .iter()
+            .any(|missing| missing.eq_ignore_ascii_case(name))
+        {
+            missing_headers.push(name.to_string());
+        }
+    }
+
+    VerificationReport {
+        version: validation_info.verification_version,
+        certificate_time,
+        certificate_age: certificate_time.and_then(|time| now.duration_since(time).ok()),
+        subnet_id,
+        delegated: subnet_id.is_some(),
+        expr_path: certificate.and_then(|certificate| certificate.expr_path.clone()),
+        certified_response: validation_info
+            .response
+            .as_ref()
+            .map(|response| CertifiedResponse {
+                status_code: response.status_code,
+                headers: response.headers.clone(),
+                body_length: response.body.len(),
+            }),
+        dropped_headers,
+        stripped_headers,
+    }
+}
+
 fn handle_agent_error(error: &AgentError) -> CanisterResponse {
     match error {
         // Turn all `DestinationInvalid`s into 404
@@ -481,8 +733,64 @@ fn handle_agent_error(error: &AgentError) -> CanisterResponse {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::protocol::{
+        certificate::tests::{create_certificate_header, create_v1_certificate_header},
+        mock_replica::MockReplica,
+    };
+    use crate::{
+        CertificateDelegation, RefusedUpgradeResponse, UpdateCallPolicy, VerificationVersionPolicy,
+        VerificationVersionRange,
+    };
     use bytes::Bytes;
+    use candid::Principal;
     use http::Request;
+    use ic_agent::Agent;
+    use ic_http_certification::HttpRequest;
+    use ic_response_verification::types::VerifiedResponse;
+    use std::{
+        collections::{HashMap, HashSet},
+        sync::Arc,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    #[test]
+    fn test_get_trailer_header_value() {
+        let all_trailer_names = RESPONSE_BODY_STREAM_TRAILER_NAMES.join(", ");
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            get_trailer_header_value(Version::HTTP_11, Some(&headers)),
+            Some(all_trailer_names.clone())
+        );
+        assert_eq!(
+            get_trailer_header_value(Version::HTTP_10, Some(&headers)),
+            None
+        );
+
+        headers.insert(http_header::CONTENT_LENGTH, "11".parse().unwrap());
+        assert_eq!(
+            get_trailer_header_value(Version::HTTP_11, Some(&headers)),
+            None
+        );
+        assert_eq!(
+            get_trailer_header_value(Version::HTTP_2, Some(&headers)),
+            Some(all_trailer_names.clone())
+        );
+        assert_eq!(
+            get_trailer_header_value(Version::HTTP_3, Some(&headers)),
+            Some(all_trailer_names)
+        );
+
+        headers.insert(
+            BODY_DIGEST_TRAILER_NAME,
+            "sha-256=:digest:".parse().unwrap(),
+        );
+        assert_eq!(
+            get_trailer_header_value(Version::HTTP_2, Some(&headers)),
+            Some(
+                "x-ic-verification-status, x-ic-chunk-count, x-ic-verified-chunk-count".to_string()
+            )
+        );
+    }
 
     #[test]
     fn test_convert_request() {
@@ -496,7 +804,7 @@ mod tests {
         let http_request = convert_request(request).unwrap();
 
         assert_eq!(
-            http_request,
+            http_request.as_http_request(),
             HttpRequest::get("/foo/bar/baz?q=hello+world&t=1")
                 .with_headers(vec![
                     ("accept".to_string(), "text/html".to_string()),
@@ -509,4 +817,387 @@ mod tests {
                 .build()
         );
     }
+
+    fn filter_v1_headers(
+        status_code: StatusCode,
+        headers: &[(&str, &str)],
+        v1_header_policy: &V1HeaderPolicy,
+    ) -> Option<Vec<(String, String)>> {
+        let headers = headers
+            .iter()
+            .map(|(name, value)| HeaderField((*name).into(), (*value).into()))
+            .collect::<Vec<_>>();
+        let response_builder = filter_v1_response(
+            Response::builder().status(status_code),
+            status_code,
+            &headers,
+            v1_header_policy,
+        )?;
+
+        let response = response_builder.body(()).unwrap();
+        Some(
+            response
+                .headers()
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_str().unwrap().to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_filter_v1_response_headers() {
+        let headers = [
+            ("Content-Type", "text/html"),
+            ("Content-Encoding", "gzip"),
+            ("IC-Certificate", "certificate=:AA==:, tree=:AA==:"),
+            ("Set-Cookie", "session=attacker"),
+            ("Location", "https://attacker.example"),
+            ("Content-Security-Policy", "default-src *"),
+            ("Access-Control-Allow-Origin", "*"),
+            ("Refresh", "0; url=https://attacker.example"),
+            ("Cache-Control", "max-age=31536000"),
+        ];
+
+        assert_eq!(
+            filter_v1_headers(StatusCode::OK, &headers, &V1HeaderPolicy::default()),
+            Some(vec![
+                ("content-type".to_string(), "text/html".to_string()),
+                ("content-encoding".to_string(), "gzip".to_string()),
+                (
+                    "ic-certificate".to_string(),
+                    "certificate=:AA==:, tree=:AA==:".to_string()
+                ),
+            ])
+        );
+        assert_eq!(
+            filter_v1_headers(
+                StatusCode::OK,
+                &headers,
+                &V1HeaderPolicy::new([http_header::CACHE_CONTROL])
+            ),
+            Some(vec![(
+                "cache-control".to_string(),
+                "max-age=31536000".to_string()
+            )])
+        );
+    }
+
+    #[test]
+    fn test_filter_v1_response_redirects() {
+        let headers = [("Location", "https://attacker.example")];
+
+        for status_code in [
+            StatusCode::MOVED_PERMANENTLY,
+            StatusCode::FOUND,
+            StatusCode::TEMPORARY_REDIRECT,
+        ] {
+            assert_eq!(
+                filter_v1_headers(status_code, &headers, &V1HeaderPolicy::default()),
+                None
+            );
+        }
+        assert_eq!(
+            filter_v1_headers(StatusCode::NOT_FOUND, &headers, &V1HeaderPolicy::default()),
+            Some(vec![])
+        );
+    }
+
+    async fn process_v1_response(
+        status_code: u16,
+        headers: &[(&str, &str)],
+    ) -> HttpGatewayResponse {
+        let body = b"<html>v1</html>";
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let (root_key, certificate_header) =
+            create_v1_certificate_header(&canister_id(), "/", body, now.as_nanos());
+        let mut headers = headers.to_vec();
+        headers.push(("IC-Certificate", &certificate_header));
+        let replica = Arc::new(MockReplica::serving(status_code, &headers, body));
+        let agent = replica.agent();
+        agent.set_root_key(root_key);
+
+        process_request(
+            CanisterCaller::new(&agent, canister_id()),
+            Request::builder().uri("/").body(Bytes::new()).unwrap(),
+            false,
+            &ResponseVerificationOptions::default(),
+            &ResponseStreamingOptions::default(),
+            UpgradeGuard::default(),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_process_v1_response_with_dangerous_headers() {
+        let response = process_v1_response(
+            200,
+            &[
+                ("Content-Type", "text/html"),
+                ("Set-Cookie", "session=attacker"),
+                ("Location", "https://attacker.example"),
+                ("Content-Security-Policy", "default-src *"),
+                ("Access-Control-Allow-Origin", "*"),
+                ("Refresh", "0; url=https://attacker.example"),
+                ("Cache-Control", "max-age=31536000"),
+            ],
+        )
+        .await;
+
+        assert_eq!(response.metadata.response_verification_version, Some(1));
+        assert_eq!(response.canister_response.status(), StatusCode::OK);
+        let mut header_names = response
+            .canister_response
+            .headers()
+            .keys()
+            .map(http::HeaderName::as_str)
+            .collect::<Vec<_>>();
+        header_names.sort_unstable();
+        assert_eq!(header_names, vec!["content-type", "ic-certificate"]);
+        assert_eq!(
+            response
+                .canister_response
+                .into_body()
+                .collect()
+                .await
+                .unwrap()
+                .to_bytes(),
+            Bytes::from_static(b"<html>v1</html>")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_process_v1_response_with_redirect() {
+        let response = process_v1_response(302, &[("Location", "https://attacker.example")]).await;
+
+        assert_eq!(response.metadata.response_verification_version, Some(1));
+        assert_eq!(
+            response.canister_response.status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert!(response
+            .canister_response
+            .headers()
+            .get(http_header::LOCATION)
+            .is_none());
+    }
+
+    #[test]
+    fn test_create_verification_report() {
+        let certificate_time = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let subnet_id = Principal::from_slice(&[1]);
+        let certificate = ResponseCertificateMetadata {
+            time: Some(certificate_time),
+            delegation: Some(CertificateDelegation {
+                subnet_id,
+                certificate: vec![],
+            }),
+            expr_path: Some(vec!["http_expr".to_string(), "<*>".to_string()]),
+            certificate_expression: None,
+        };
+        let validation_info = VerificationInfo {
+            response: Some(VerifiedResponse {
+                status_code: Some(200),
+                headers: vec![("content-type".to_string(), "text/html".to_string())],
+                body: b"body".to_vec(),
+            }),
+            verification_version: 2,
+        };
+        let headers = [
+            HeaderField("Content-Type".into(), "text/html".into()),
+            HeaderField("Set-Cookie".into(), "a=1".into()),
+            HeaderField("set-cookie".into(), "b=2".into()),
+            HeaderField("IC-Certificate".into(), "certificate=:AA==:".into()),
+            HeaderField("Content-Range".into(), "bytes 0-3/10".into()),
+            HeaderField("Content-Length".into(), "4".into()),
+        ];
+        // the partial response is streamed in full, with the Content-Length of the whole body
+        let mut returned_headers = HeaderMap::new();
+        returned_headers.insert(http_header::CONTENT_TYPE, "text/html".parse().unwrap());
+        returned_headers.insert(http_header::CONTENT_LENGTH, "10".parse().unwrap());
+
+        assert_eq!(
+            create_verification_report(
+                &validation_info,
+                Some(&certificate),
+                &headers,
+                Some(&returned_headers),
+                &[
+                    http_header::CONTENT_RANGE.as_str(),
+                    http_header::CONTENT_LENGTH.as_str(),
+                    CERTIFICATE_HEADER_NAME,
+                ],
+                certificate_time + Duration::from_secs(5),
+            ),
+            VerificationReport {
+                version: 2,
+                certificate_time: Some(certificate_time),
+                certificate_age: Some(Duration::from_secs(5)),
+                subnet_id: Some(subnet_id),
+                delegated: true,
+                expr_path: Some(vec!["http_expr".to_string(), "<*>".to_string()]),
+                certified_response: Some(CertifiedResponse {
+                    status_code: Some(200),
+                    headers: vec![("content-type".to_string(), "text/html".to_string())],
+                    body_length: 4,
+                }),
+                dropped_headers: vec!["Set-Cookie".to_string()],
+                stripped_headers: vec!["IC-Certificate".to_string(), "Content-Range".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn test_create_v1_verification_report() {
+        let validation_info = VerificationInfo {
+            response: None,
+            verification_version: 1,
+        };
+        let headers = [HeaderField("Set-Cookie".into(), "a=1".into())];
+
+        let report = create_verification_report(
+            &validation_info,
+            None,
+            &headers,
+            None,
+            &[],
+            SystemTime::now(),
+        );
+
+        assert_eq!(report.version, 1);
+        assert_eq!(report.certificate_time, None);
+        assert_eq!(report.certificate_age, None);
+        assert!(!report.delegated);
+        assert_eq!(report.certified_response, None);
+        assert_eq!(report.dropped_headers, vec!["Set-Cookie".to_string()]);
+        assert!(report.stripped_headers.is_empty());
+    }
+
+    async fn process_with_update_fallback(
+        agent: &Agent,
+        response_verification_options: &ResponseVerificationOptions,
+        update_call_policy: &UpdateCallPolicy,
+    ) -> HttpGatewayResponse {
+        process_request(
+            CanisterCaller::new(agent, canister_id()),
+            Request::builder().uri("/").body(Bytes::new()).unwrap(),
+            false,
+            response_verification_options,
+            &ResponseStreamingOptions::default(),
+            UpgradeGuard {
+                update_call_policy: Some(update_call_policy),
+                rate_limit: None,
+            },
+        )
+        .await
+    }
+
+    fn canister_id() -> Principal {
+        Principal::from_slice(&[1, 2, 3, 4])
+    }
+
+    fn update_fallback_policy() -> UpdateCallPolicy {
+        UpdateCallPolicy::new(
+            None,
+            HashMap::new(),
+            RefusedUpgradeResponse::default(),
+            HashSet::from([canister_id()]),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_update_fallback_for_uncertified_responses() {
+        let replica = Arc::new(MockReplica::serving(200, &[], b"uncertified"));
+        let update_call_policy = update_fallback_policy();
+
+        let response = process_with_update_fallback(
+            &replica.agent(),
+            &ResponseVerificationOptions::default(),
+            &update_call_policy,
+        )
+        .await;
+
+        assert!(response.metadata.upgraded_to_update_call);
+        assert!(matches!(
+            response.metadata.update_fallback_reason,
+            Some(HttpGatewayError::ResponseVerificationError(_))
+        ));
+        assert_eq!(update_call_policy.update_fallbacks(), 1);
+        assert_eq!(replica.request_count("call"), 1);
+    }
+
+    #[tokio::test]
+    async fn test_no_update_fallback_for_disallowed_verification_versions() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let certificate_header = create_certificate_header(now.as_nanos());
+        let replica = Arc::new(MockReplica::serving(
+            200,
+            &[("IC-Certificate", &certificate_header)],
+            b"certified",
+        ));
+        let update_call_policy = update_fallback_policy();
+        let response_verification_options = ResponseVerificationOptions {
+            verification_version_policy: Arc::new(VerificationVersionPolicy::new(
+                VerificationVersionRange::new(1, 1),
+                HashMap::new(),
+            )),
+            ..Default::default()
+        };
+
+        let response = process_with_update_fallback(
+            &replica.agent(),
+            &response_verification_options,
+            &update_call_policy,
+        )
+        .await;
+
+        assert_eq!(
+            response.canister_response.status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert!(!response.metadata.upgraded_to_update_call);
+        assert!(matches!(
+            response.metadata.internal_error,
+            Some(HttpGatewayError::VerificationVersionNotAllowed { version: 2, .. })
+        ));
+        assert!(response.metadata.update_fallback_reason.is_none());
+        assert_eq!(update_call_policy.update_fallbacks(), 0);
+        assert_eq!(replica.request_count("call"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_no_update_fallback_for_responses_that_do_not_match_their_certification() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let (root_key, certificate_header) =
+            create_v1_certificate_header(&canister_id(), "/", b"certified", now.as_nanos());
+        let replica = Arc::new(MockReplica::serving(
+            200,
+            &[("IC-Certificate", &certificate_header)],
+            b"tampered",
+        ));
+        let agent = replica.agent();
+        agent.set_root_key(root_key);
+        let update_call_policy = update_fallback_policy();
+
+        let response = process_with_update_fallback(
+            &agent,
+            &ResponseVerificationOptions::default(),
+            &update_call_policy,
+        )
+        .await;
+
+        assert_eq!(
+            response.canister_response.status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert!(!response.metadata.upgraded_to_update_call);
+        assert!(matches!(
+            response.metadata.internal_error,
+            Some(HttpGatewayError::ResponseVerificationError(
+                ResponseVerificationError::InvalidResponseBody
+            ))
+        ));
+        assert!(response.metadata.update_fallback_reason.is_none());
+        assert_eq!(update_call_policy.update_fallbacks(), 0);
+        assert_eq!(replica.request_count("call"), 0);
+    }
 }
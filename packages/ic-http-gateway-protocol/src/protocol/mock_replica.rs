@@ -0,0 +1,124 @@
+use bytes::Bytes;
+use candid::CandidType;
+use ic_agent::{agent::HttpService, Agent, AgentError};
+use serde_cbor::Value;
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+/// The reply of a canister's `http_request` method.
+#[derive(CandidType)]
+struct MockHttpResponse<'a> {
+    status_code: u16,
+    headers: Vec<(&'a str, &'a str)>,
+    body: &'a [u8],
+}
+
+/// An in-process replica that the agent talks to instead of the network.
+#[derive(Debug, Default)]
+pub(crate) struct MockReplica {
+    /// The root key that the replica reports in its status, if any.
+    pub root_key: Option<Vec<u8>>,
+
+    /// The candid-encoded reply to every query call, if any.
+    pub query_reply: Option<Vec<u8>>,
+
+    /// The paths of the requests that the replica received.
+    pub requests: Mutex<Vec<String>>,
+}
+
+impl MockReplica {
+    /// A replica on which every query call is answered with the given HTTP response.
+    pub fn serving(status_code: u16, headers: &[(&str, &str)], body: &[u8]) -> Self {
+        Self {
+            query_reply: Some(
+                candid::encode_one(MockHttpResponse {
+                    status_code,
+                    headers: headers.to_vec(),
+                    body,
+                })
+                .unwrap(),
+            ),
+            ..Default::default()
+        }
+    }
+
+    /// Builds an agent that sends its requests to the replica.
+    pub fn agent(self: &Arc<Self>) -> Agent {
+        Agent::builder()
+            .with_url("http://mock-replica")
+            .with_arc_http_middleware(self.clone())
+            .with_verify_query_signatures(false)
+            .build()
+            .unwrap()
+    }
+
+    /// The number of requests that the replica received for the endpoint, e.g. `call`.
+    pub fn request_count(&self, endpoint: &str) -> usize {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|path| path.rsplit('/').next() == Some(endpoint))
+            .count()
+    }
+
+    fn status(&self) -> Value {
+        let mut status = BTreeMap::new();
+        if let Some(root_key) = &self.root_key {
+            status.insert(
+                Value::Text("root_key".to_string()),
+                Value::Bytes(root_key.clone()),
+            );
+        }
+
+        Value::Map(status)
+    }
+
+    fn query(query_reply: &[u8]) -> Value {
+        Value::Map(BTreeMap::from([
+            (
+                Value::Text("status".to_string()),
+                Value::Text("replied".to_string()),
+            ),
+            (
+                Value::Text("reply".to_string()),
+                Value::Map(BTreeMap::from([(
+                    Value::Text("arg".to_string()),
+                    Value::Bytes(query_reply.to_vec()),
+                )])),
+            ),
+        ]))
+    }
+}
+
+#[async_trait::async_trait]
+impl HttpService for MockReplica {
+    async fn call<'a>(
+        &'a self,
+        request: &'a (dyn Fn() -> Result<http::Request<Bytes>, AgentError> + Send + Sync),
+        _max_retries: usize,
+        _size_limit: Option<usize>,
+    ) -> Result<http::Response<Bytes>, AgentError> {
+        let request = request()?;
+        let path = request.uri().path();
+        self.requests.lock().unwrap().push(path.to_string());
+
+        let body = match (path, &self.query_reply) {
+            ("/api/v2/status", _) => self.status(),
+            (path, Some(query_reply)) if path.ends_with("/query") => Self::query(query_reply),
+            (path, _) => {
+                return Ok(http::Response::builder()
+                    .status(404)
+                    .body(Bytes::from(format!("{path} is not mocked")))
+                    .unwrap())
+            }
+        };
+
+        Ok(http::Response::builder()
+            .header(http::header::CONTENT_TYPE, "application/cbor")
+            .body(Bytes::from(serde_cbor::to_vec(&body).unwrap()))
+            .unwrap())
+    }
+}
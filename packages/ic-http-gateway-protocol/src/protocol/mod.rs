@@ -1,5 +1,17 @@
+mod canister_caller;
+pub(crate) use canister_caller::*;
+
+mod certificate;
+pub(crate) use certificate::*;
+
 mod handler;
 pub(crate) use handler::*;
 
+#[cfg(test)]
+pub(crate) mod mock_replica;
+
 mod validate;
 pub(crate) use validate::*;
+
+mod verifiable;
+pub(crate) use verifiable::*;
@@ -1,20 +1,64 @@
-use crate::HttpGatewayResult;
+use super::{get_certificate_header, ResponseCertificate, VerifiableRequest, VerifiableResponse};
+use crate::{HttpGatewayError, HttpGatewayResult, ResponseVerificationOptions, VerificationKey};
 use candid::Principal;
 use ic_agent::Agent;
 use ic_http_certification::{HttpRequest, HttpResponse};
-use ic_response_verification::{
-    types::VerificationInfo, verify_request_response_pair, MIN_VERIFICATION_VERSION,
-};
-use std::time::{SystemTime, UNIX_EPOCH};
+use ic_response_verification::{types::VerificationInfo, verify_request_response_pair};
+use std::time::UNIX_EPOCH;
 
-const MAX_CERT_TIME_OFFSET_NS: u128 = 300_000_000_000;
+/// Verifies the response on the verification pool, if there is one, and on the current thread otherwise.
+/// The request and response bodies are borrowed by the verification, not copied.
+pub async fn validate_on_pool(
+    agent: &Agent,
+    canister_id: &Principal,
+    request: &VerifiableRequest,
+    response: &VerifiableResponse,
+    skip_verification: bool,
+    options: &ResponseVerificationOptions,
+) -> HttpGatewayResult<Option<VerificationInfo>> {
+    let Some(verification_pool) = options
+        .verification_pool
+        .as_ref()
+        .filter(|_| !skip_verification)
+    else {
+        return validate(
+            agent,
+            canister_id,
+            request.as_http_request(),
+            response.as_http_response(),
+            skip_verification,
+            options,
+        );
+    };
+
+    let agent = agent.clone();
+    let canister_id = *canister_id;
+    let request = request.clone();
+    let response = response.clone();
+    let options = options.clone();
+    verification_pool
+        .run(move || {
+            validate(
+                &agent,
+                &canister_id,
+                request.as_http_request(),
+                response.as_http_response(),
+                skip_verification,
+                &options,
+            )
+        })
+        .await?
+}
 
+/// Verifies the response against its certificate.
+/// Responses that have already been verified with the same certificate are taken from the verification cache.
 pub fn validate(
     agent: &Agent,
     canister_id: &Principal,
     request: HttpRequest,
     response: HttpResponse,
     skip_verification: bool,
+    options: &ResponseVerificationOptions,
 ) -> HttpGatewayResult<Option<VerificationInfo>> {
     if skip_verification {
         // TODO: Remove this (FOLLOW-483)
@@ -23,24 +67,311 @@ pub fn validate(
         return Ok(None);
     }
 
+    let current_time_ns = get_current_time_in_ns(options);
+    // the certificate time has already been checked against the asymmetric offsets,
+    // so the symmetric check of the response verification library must allow the larger one
+    let max_cert_time_offset_ns = options
+        .max_cert_time_offset_past
+        .max(options.max_cert_time_offset_future)
+        .as_nanos();
     let ic_public_key = agent.read_root_key();
+
+    // Malformed or missing certificates are left to the response verification library to reject.
+    let certificate_header = get_certificate_header(
+        response
+            .headers()
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str())),
+    );
+    let certificate = certificate_header.and_then(ResponseCertificate::parse);
+
+    if let Some(certificate) = &certificate {
+        options
+            .verification_version_policy
+            .check(canister_id, certificate.version)?;
+        validate_certificate_time(certificate, current_time_ns, options)?;
+    }
+
+    let min_verification_version = options
+        .verification_version_policy
+        .verification_versions(canister_id)
+        .min;
+    // the time of cached certificates has just been checked against the stricter asymmetric offsets
+    let verification_key =
+        certificate_header
+            .filter(|_| certificate.is_some())
+            .map(|certificate_header| {
+                VerificationKey::new(
+                    certificate_header,
+                    &ic_public_key,
+                    canister_id.as_slice(),
+                    min_verification_version,
+                    &request,
+                    &response,
+                )
+            });
+    let verification_cache = options.verification_cache.as_ref().zip(verification_key);
+    if let Some((verification_cache, verification_key)) = &verification_cache {
+        if let Some(verification_info) = verification_cache.get(verification_key, response.body()) {
+            return Ok(Some(verification_info));
+        }
+    }
+
     let verification_info = verify_request_response_pair(
         request,
         response,
         canister_id.as_slice(),
-        get_current_time_in_ns(),
-        MAX_CERT_TIME_OFFSET_NS,
+        current_time_ns,
+        max_cert_time_offset_ns,
         ic_public_key.as_slice(),
-        MIN_VERIFICATION_VERSION,
+        u8::try_from(min_verification_version).unwrap_or(u8::MAX),
     )?;
+    if let Some((verification_cache, verification_key)) = verification_cache {
+        verification_cache.insert(verification_key, &verification_info);
+    }
     Ok(Some(verification_info))
 }
 
-fn get_current_time_in_ns() -> u128 {
-    let start = SystemTime::now();
+/// Checks the time of the response's certificate against the current time.
+fn validate_certificate_time(
+    certificate: &ResponseCertificate,
+    current_time_ns: u128,
+    options: &ResponseVerificationOptions,
+) -> HttpGatewayResult {
+    let Some(certificate_time_ns) = certificate.time_ns() else {
+        return Ok(());
+    };
+
+    if certificate_time_ns
+        < current_time_ns.saturating_sub(options.max_cert_time_offset_past.as_nanos())
+    {
+        return Err(HttpGatewayError::StaleCertificate {
+            certificate_time_ns,
+            current_time_ns,
+        });
+    }
+
+    if certificate_time_ns
+        > current_time_ns.saturating_add(options.max_cert_time_offset_future.as_nanos())
+    {
+        return Err(HttpGatewayError::FutureCertificate {
+            certificate_time_ns,
+            current_time_ns,
+        });
+    }
+
+    Ok(())
+}
 
-    start
+fn get_current_time_in_ns(options: &ResponseVerificationOptions) -> u128 {
+    // a clock set before the UNIX epoch will fail the certificate freshness check
+    options
+        .clock
+        .now()
         .duration_since(UNIX_EPOCH)
-        .expect("Time went backwards")
-        .as_nanos()
+        .map(|duration| duration.as_nanos())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::certificate::tests::{
+        create_certificate_header, create_v1_certificate_header,
+    };
+    use crate::{Clock, VerificationCache, VerificationVersionPolicy, VerificationVersionRange};
+    use assert_matches::assert_matches;
+    use ic_http_certification::StatusCode;
+    use std::{
+        collections::HashMap,
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    const CERTIFICATE_TIME: Duration = Duration::from_secs(1_700_000_000);
+
+    #[derive(Debug)]
+    struct FixedClock(SystemTime);
+
+    impl Clock for FixedClock {
+        fn now(&self) -> SystemTime {
+            self.0
+        }
+    }
+
+    fn options_at(now: Duration) -> ResponseVerificationOptions {
+        ResponseVerificationOptions {
+            clock: Arc::new(FixedClock(UNIX_EPOCH + now)),
+            max_cert_time_offset_past: Duration::from_secs(60),
+            max_cert_time_offset_future: Duration::from_secs(10),
+            verification_pool: None,
+            verification_cache: None,
+            verification_version_policy: Arc::default(),
+            v1_header_policy: Arc::default(),
+            strip_certificate_headers: false,
+        }
+    }
+
+    fn validate_at(now: Duration) -> HttpGatewayResult<Option<VerificationInfo>> {
+        validate_with_options(&options_at(now))
+    }
+
+    fn validate_with_options(
+        options: &ResponseVerificationOptions,
+    ) -> HttpGatewayResult<Option<VerificationInfo>> {
+        let agent = Agent::builder()
+            .with_url("http://localhost:4943")
+            .build()
+            .unwrap();
+        let response = HttpResponse::builder()
+            .with_status_code(StatusCode::OK)
+            .with_headers(vec![(
+                "IC-Certificate".to_string(),
+                create_certificate_header(CERTIFICATE_TIME.as_nanos()),
+            )])
+            .build();
+
+        validate(
+            &agent,
+            &Principal::from_slice(&[1, 2, 3, 4]),
+            HttpRequest::get("/").build(),
+            response,
+            false,
+            options,
+        )
+    }
+
+    #[test]
+    fn should_reject_stale_certificate() {
+        let now = CERTIFICATE_TIME + Duration::from_secs(61);
+
+        assert_matches!(
+            validate_at(now),
+            Err(HttpGatewayError::StaleCertificate {
+                certificate_time_ns,
+                current_time_ns,
+            }) if certificate_time_ns == CERTIFICATE_TIME.as_nanos() && current_time_ns == now.as_nanos()
+        );
+    }
+
+    #[test]
+    fn should_reject_future_certificate() {
+        let now = CERTIFICATE_TIME - Duration::from_secs(11);
+
+        assert_matches!(
+            validate_at(now),
+            Err(HttpGatewayError::FutureCertificate {
+                certificate_time_ns,
+                current_time_ns,
+            }) if certificate_time_ns == CERTIFICATE_TIME.as_nanos() && current_time_ns == now.as_nanos()
+        );
+    }
+
+    #[test]
+    fn should_accept_certificate_time_within_offsets() {
+        for now in [
+            CERTIFICATE_TIME + Duration::from_secs(59),
+            CERTIFICATE_TIME - Duration::from_secs(9),
+        ] {
+            // the certificate is fresh, but its signature is invalid
+            assert_matches!(
+                validate_at(now),
+                Err(HttpGatewayError::ResponseVerificationError(_))
+            );
+        }
+    }
+
+    #[test]
+    fn should_reject_disallowed_verification_versions_before_verification() {
+        let options = ResponseVerificationOptions {
+            verification_version_policy: Arc::new(VerificationVersionPolicy::new(
+                VerificationVersionRange::new(1, 1),
+                HashMap::new(),
+            )),
+            ..options_at(CERTIFICATE_TIME)
+        };
+
+        assert_matches!(
+            validate_with_options(&options),
+            Err(HttpGatewayError::VerificationVersionNotAllowed {
+                version: 2,
+                min: 1,
+                max: 1,
+                ..
+            })
+        );
+        assert_eq!(options.verification_version_policy.rejected_responses(), 1);
+    }
+
+    #[test]
+    fn should_skip_the_verification_of_cached_responses() {
+        let canister_id = Principal::from_slice(&[1, 2, 3, 4]);
+        let (root_key, certificate_header) =
+            create_v1_certificate_header(&canister_id, "/", b"body", CERTIFICATE_TIME.as_nanos());
+        let agent = Agent::builder()
+            .with_url("http://localhost:4943")
+            .build()
+            .unwrap();
+        agent.set_root_key(root_key);
+        let verification_cache = Arc::new(VerificationCache::new(10));
+        let options = ResponseVerificationOptions {
+            verification_cache: Some(verification_cache.clone()),
+            ..options_at(CERTIFICATE_TIME)
+        };
+        let validate_body = |body: &[u8]| {
+            validate(
+                &agent,
+                &canister_id,
+                HttpRequest::get("/").build(),
+                HttpResponse::builder()
+                    .with_status_code(StatusCode::OK)
+                    .with_headers(vec![(
+                        "IC-Certificate".to_string(),
+                        certificate_header.clone(),
+                    )])
+                    .with_body(body.to_vec())
+                    .build(),
+                false,
+                &options,
+            )
+        };
+
+        for _ in 0..2 {
+            assert_matches!(
+                validate_body(b"body"),
+                Ok(Some(VerificationInfo {
+                    verification_version: 1,
+                    ..
+                }))
+            );
+        }
+        assert_matches!(
+            validate_body(b"tampered body"),
+            Err(HttpGatewayError::ResponseVerificationError(_))
+        );
+        assert_eq!(verification_cache.hits(), 1);
+        assert_eq!(verification_cache.misses(), 2);
+        assert_eq!(verification_cache.len(), 1);
+
+        // the time of cached certificates is still checked
+        let options = ResponseVerificationOptions {
+            verification_cache: Some(verification_cache.clone()),
+            ..options_at(CERTIFICATE_TIME + Duration::from_secs(61))
+        };
+        assert_matches!(
+            validate(
+                &agent,
+                &canister_id,
+                HttpRequest::get("/").build(),
+                HttpResponse::builder()
+                    .with_status_code(StatusCode::OK)
+                    .with_headers(vec![("IC-Certificate".to_string(), certificate_header)])
+                    .with_body(b"body".to_vec())
+                    .build(),
+                false,
+                &options,
+            ),
+            Err(HttpGatewayError::StaleCertificate { .. })
+        );
+    }
 }
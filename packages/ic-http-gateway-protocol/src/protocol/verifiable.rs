@@ -0,0 +1,92 @@
+use bytes::Bytes;
+use http::{Method, StatusCode};
+use ic_http_certification::{HttpRequest, HttpResponse};
+use ic_utils::interfaces::http_request::HeaderField;
+
+/// A request to a canister, whose body is shared between the canister call
+/// and the verification of every response to it, rather than copied.
+#[derive(Debug, Clone)]
+pub struct VerifiableRequest {
+    pub method: Method,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Bytes,
+}
+
+impl VerifiableRequest {
+    /// Borrows the request in the shape expected by the response verification library.
+    pub fn as_http_request(&self) -> HttpRequest<'_> {
+        HttpRequest::builder()
+            .with_method(self.method.clone())
+            .with_url(self.url.clone())
+            .with_headers(self.headers.clone())
+            .with_body(self.body.as_ref())
+            .build()
+    }
+
+    /// The request headers in the shape expected by the `http_request` canister methods.
+    pub fn header_fields(&self) -> impl ExactSizeIterator<Item = HeaderField<'_>> + Clone {
+        self.headers
+            .iter()
+            .map(|(name, value)| HeaderField(name.into(), value.into()))
+    }
+}
+
+/// A response from a canister, whose body is shared between its verification
+/// and the response body that is sent to the client, rather than copied.
+#[derive(Debug, Clone)]
+pub struct VerifiableResponse {
+    pub status_code: StatusCode,
+    pub headers: Vec<(String, String)>,
+    pub body: Bytes,
+}
+
+impl VerifiableResponse {
+    pub fn new(status_code: StatusCode, headers: &[HeaderField<'_>], body: Bytes) -> Self {
+        Self {
+            status_code,
+            headers: headers
+                .iter()
+                .map(|HeaderField(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            body,
+        }
+    }
+
+    /// Borrows the response in the shape expected by the response verification library.
+    pub fn as_http_response(&self) -> HttpResponse<'_> {
+        HttpResponse::builder()
+            .with_status_code(self.status_code)
+            .with_headers(self.headers.clone())
+            .with_body(self.body.as_ref())
+            .build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_borrow_bodies_for_verification() {
+        let body = Bytes::from(vec![1; 1024]);
+        let request = VerifiableRequest {
+            method: Method::POST,
+            url: "/upload".to_string(),
+            headers: vec![("content-type".to_string(), "text/plain".to_string())],
+            body: body.clone(),
+        };
+        let response = VerifiableResponse::new(StatusCode::OK, &[], body.clone());
+
+        assert_eq!(request.as_http_request().body().as_ptr(), body.as_ptr());
+        assert_eq!(response.as_http_response().body().as_ptr(), body.as_ptr());
+        assert_eq!(request.as_http_request().url(), "/upload");
+        assert_eq!(
+            request
+                .header_fields()
+                .map(|HeaderField(name, value)| (name.to_string(), value.to_string()))
+                .collect::<Vec<_>>(),
+            request.headers
+        );
+    }
+}
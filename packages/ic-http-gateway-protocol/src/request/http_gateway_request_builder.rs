@@ -1,8 +1,15 @@
-use crate::{protocol::process_request, HttpGatewayResponse};
+use crate::{
+    create_invalid_delegation_response, create_rate_limited_response,
+    protocol::{process_request, CallOverrides, CanisterCaller},
+    DelegationAuthenticator, Denylist, HttpGatewayResponse, HttpInterfaceResolver,
+    RateLimitContext, RateLimitedCall, RateLimiter, RequestCoalescer, ResponseCache,
+    ResponseStreamingOptions, ResponseVerificationOptions, UpdateCallPolicy, UpgradeGuard,
+};
 use bytes::Bytes;
 use candid::Principal;
 use http::Request;
-use ic_agent::Agent;
+use ic_agent::{Agent, Identity};
+use std::{net::IpAddr, sync::Arc, time::Duration};
 
 pub struct HttpGatewayRequestArgs {
     /// The request to make to the canister.
@@ -17,11 +24,25 @@ pub type CanisterRequest = Request<Bytes>;
 pub struct HttpGatewayRequestBuilderArgs<'a> {
     pub request_args: HttpGatewayRequestArgs,
     pub agent: &'a Agent,
+    pub response_verification_options: &'a ResponseVerificationOptions,
+    pub response_streaming_options: &'a ResponseStreamingOptions,
+    pub request_coalescer: Option<&'a RequestCoalescer>,
+    pub response_cache: Option<&'a Arc<ResponseCache>>,
+    pub denylist: Option<&'a Denylist>,
+    pub rate_limiter: Option<&'a RateLimiter>,
+    pub update_call_policy: Option<&'a Arc<UpdateCallPolicy>>,
+    pub delegation_authenticator: Option<&'a DelegationAuthenticator>,
+    pub http_interface_resolver: Option<&'a Arc<HttpInterfaceResolver>>,
 }
 
 pub struct HttpGatewayRequestBuilder<'a> {
     args: HttpGatewayRequestBuilderArgs<'a>,
     skip_verification: bool,
+    client_ip: Option<IpAddr>,
+    agent: Option<Agent>,
+    identity: Option<Arc<dyn Identity>>,
+    ingress_expiry: Option<Duration>,
+    effective_canister_id: Option<Principal>,
 }
 
 impl<'a> HttpGatewayRequestBuilder<'a> {
@@ -29,6 +50,11 @@ impl<'a> HttpGatewayRequestBuilder<'a> {
         Self {
             args,
             skip_verification: false,
+            client_ip: None,
+            agent: None,
+            identity: None,
+            ingress_expiry: None,
+            effective_canister_id: None,
         }
     }
 
@@ -38,13 +64,190 @@ impl<'a> HttpGatewayRequestBuilder<'a> {
         self
     }
 
+    /// The IP address of the client making the request, used to apply per-client rate limits.
+    pub fn set_client_ip(&mut self, client_ip: IpAddr) -> &mut Self {
+        self.client_ip = Some(client_ip);
+
+        self
+    }
+
+    /// The agent to make the request with, instead of the agent of the client.
+    /// Use [set_identity](Self::set_identity) to keep sharing the connections of the client's agent.
+    pub fn set_agent(&mut self, agent: Agent) -> &mut Self {
+        self.agent = Some(agent);
+
+        self
+    }
+
+    /// The identity to make the request with, instead of the identity of the agent.
+    pub fn set_identity(&mut self, identity: Arc<dyn Identity>) -> &mut Self {
+        self.identity = Some(identity);
+
+        self
+    }
+
+    /// How long the query and update calls of the request are valid for,
+    /// instead of the ingress expiry of the agent.
+    pub fn set_ingress_expiry(&mut self, ingress_expiry: Duration) -> &mut Self {
+        self.ingress_expiry = Some(ingress_expiry);
+
+        self
+    }
+
+    /// The canister id used to route the query and update calls of the request,
+    /// instead of the id of the canister that the request is made to.
+    pub fn set_effective_canister_id(&mut self, effective_canister_id: Principal) -> &mut Self {
+        self.effective_canister_id = Some(effective_canister_id);
+
+        self
+    }
+
     pub async fn send(self) -> HttpGatewayResponse {
-        process_request(
-            self.args.agent,
-            self.args.request_args.canister_request,
-            self.args.request_args.canister_id,
-            self.skip_verification,
-        )
-        .await
+        let canister_id = self.args.request_args.canister_id;
+        let mut canister_request = self.args.request_args.canister_request;
+
+        if let Some(denylist_response) = self
+            .args
+            .denylist
+            .and_then(|denylist| denylist.check(&canister_id, &canister_request))
+        {
+            return denylist_response;
+        }
+
+        let rate_limit = self.args.rate_limiter.map(|rate_limiter| RateLimitContext {
+            rate_limiter,
+            client_ip: self.client_ip,
+        });
+        let query_rate_limit = match rate_limit
+            .map(|rate_limit| rate_limit.check(RateLimitedCall::Query, canister_id))
+            .transpose()
+        {
+            Ok(status) => status.flatten(),
+            Err(status) => return create_rate_limited_response(status),
+        };
+
+        let mut agent = self.agent;
+        if let Some(identity) = self.identity {
+            agent
+                .get_or_insert_with(|| self.args.agent.clone())
+                .set_arc_identity(identity);
+        }
+
+        let mut delegated_principal = None;
+        match self
+            .args
+            .delegation_authenticator
+            .map(|delegation_authenticator| {
+                delegation_authenticator.authenticate(
+                    canister_id,
+                    &mut canister_request,
+                    &self.args.response_verification_options.clock,
+                )
+            })
+            .transpose()
+        {
+            Ok(delegated_identity) => {
+                if let Some(delegated_identity) = delegated_identity.flatten() {
+                    delegated_principal = delegated_identity.sender().ok();
+                    agent
+                        .get_or_insert_with(|| self.args.agent.clone())
+                        .set_identity(delegated_identity);
+                }
+            }
+            Err(e) => return create_invalid_delegation_response(e),
+        }
+
+        // responses to calls with another identity may be specific to the caller, so they are neither shared nor cached
+        let shared = agent.is_none();
+        let canister_caller = CanisterCaller {
+            agent: agent.as_ref().unwrap_or(self.args.agent),
+            canister_id,
+            overrides: CallOverrides {
+                ingress_expiry: self.ingress_expiry,
+                effective_canister_id: self.effective_canister_id,
+            },
+            http_interface_resolver: self.args.http_interface_resolver.map(Arc::as_ref),
+        };
+
+        let process = |canister_request| {
+            process_request(
+                canister_caller,
+                canister_request,
+                self.skip_verification,
+                self.args.response_verification_options,
+                self.args.response_streaming_options,
+                UpgradeGuard {
+                    update_call_policy: self.args.update_call_policy.map(Arc::as_ref),
+                    rate_limit,
+                },
+            )
+        };
+
+        let coalesce = |canister_request| async move {
+            match self.args.request_coalescer {
+                Some(request_coalescer) => {
+                    request_coalescer
+                        .coalesce(
+                            canister_id,
+                            canister_request,
+                            self.skip_verification,
+                            process,
+                        )
+                        .await
+                }
+                None => process(canister_request).await,
+            }
+        };
+
+        let mut response = match self.args.response_cache {
+            _ if !shared => process(canister_request).await,
+            Some(response_cache) => {
+                // stale responses are revalidated in the background, after this request has completed
+                let agent = self.args.agent.clone();
+                let response_verification_options = self.args.response_verification_options.clone();
+                let response_streaming_options = self.args.response_streaming_options.clone();
+                let skip_verification = self.skip_verification;
+                let update_call_policy = self.args.update_call_policy.cloned();
+                let http_interface_resolver = self.args.http_interface_resolver.cloned();
+                let overrides = canister_caller.overrides;
+                let revalidate = move |canister_request| async move {
+                    process_request(
+                        CanisterCaller {
+                            overrides,
+                            http_interface_resolver: http_interface_resolver.as_deref(),
+                            ..CanisterCaller::new(&agent, canister_id)
+                        },
+                        canister_request,
+                        skip_verification,
+                        &response_verification_options,
+                        &response_streaming_options,
+                        // background revalidations do not count towards the rate limits of the client
+                        UpgradeGuard {
+                            update_call_policy: update_call_policy.as_deref(),
+                            rate_limit: None,
+                        },
+                    )
+                    .await
+                };
+
+                response_cache
+                    .serve(
+                        canister_id,
+                        canister_request,
+                        self.skip_verification,
+                        &self.args.response_verification_options.clock,
+                        coalesce,
+                        revalidate,
+                    )
+                    .await
+            }
+            None => coalesce(canister_request).await,
+        };
+        if response.metadata.rate_limit.is_none() {
+            response.metadata.rate_limit = query_rate_limit;
+        }
+        response.metadata.delegated_principal = delegated_principal;
+
+        response
     }
 }
@@ -1,19 +1,24 @@
 use bytes::Bytes;
+use candid::Principal;
 use futures::stream::BoxStream;
 use http::Response;
 use http_body::Frame;
 use http_body_util::{Either, Full, StreamBody};
 use ic_agent::AgentError;
-use std::fmt::Debug;
+use std::{
+    fmt::Debug,
+    time::{Duration, SystemTime},
+};
 
-use crate::HttpGatewayError;
+use crate::{HttpGatewayError, RateLimitStatus};
 
 pub type CanisterResponse = Response<HttpGatewayResponseBody>;
 
 /// A response from the HTTP gateway.
 pub struct HttpGatewayResponse {
     /// The certified response, excluding uncertified headers.
-    /// If response verification v1 is used, the original, uncertified headers are returned.
+    /// If response verification v1 is used, the uncertified headers allowed by the
+    /// [V1HeaderPolicy](crate::V1HeaderPolicy) are returned.
     pub canister_response: CanisterResponse,
 
     /// Additional metadata regarding the response.
@@ -21,9 +26,10 @@ pub struct HttpGatewayResponse {
 }
 
 /// Additional metadata regarding the response.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct HttpGatewayResponseMetadata {
-    /// Whether the original query call was upgraded to an update call.
+    /// Whether the original query call was upgraded to an update call,
+    /// either by the canister or because its response was not certified.
     pub upgraded_to_update_call: bool,
 
     /// The version of response verification that was used to verify the response.
@@ -33,6 +39,132 @@ pub struct HttpGatewayResponseMetadata {
 
     /// The internal error that resulted in the HTTP response being an error response.
     pub internal_error: Option<HttpGatewayError>,
+
+    /// Whether the response was served from the response cache,
+    /// or `None` if it was fetched from the canister.
+    pub cache_status: Option<CacheStatus>,
+
+    /// Whether the request was refused because it matched the [Denylist](crate::Denylist).
+    pub denylisted: bool,
+
+    /// The state of the rate limit budget that the request was checked against,
+    /// the update budget if the request was upgraded to an update call.
+    /// `None` if the request is not rate limited.
+    pub rate_limit: Option<RateLimitStatus>,
+
+    /// Whether the canister asked for the request to be upgraded to an update call,
+    /// but the upgrade was refused because of the [ReadOnlyMode](crate::ReadOnlyMode).
+    pub upgrade_refused: bool,
+
+    /// Why the query response is considered not certified, if the request was re-issued as an update call
+    /// because of an [UpdateCallPolicy](crate::UpdateCallPolicy) fallback.
+    pub update_fallback_reason: Option<HttpGatewayError>,
+
+    /// The principal of the user that the canister was called on behalf of,
+    /// if the request carried a delegation chain for the [DelegationAuthenticator](crate::DelegationAuthenticator).
+    pub delegated_principal: Option<Principal>,
+
+    /// The decoded certificate headers of the response, if it was verified.
+    pub certificate: Option<ResponseCertificateMetadata>,
+
+    /// What the verification of the response certified, and which headers were dropped,
+    /// if the response was verified.
+    pub verification_report: Option<VerificationReport>,
+}
+
+/// What the verification of a response certified, for debugging missing headers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerificationReport {
+    /// The response verification version that the response was verified with.
+    pub version: u16,
+
+    /// The time at which the certificate was created.
+    pub certificate_time: Option<SystemTime>,
+
+    /// The age of the certificate when the response was verified.
+    pub certificate_age: Option<Duration>,
+
+    /// The id of the subnet that signed the certificate, if a delegation was used.
+    pub subnet_id: Option<Principal>,
+
+    /// Whether the certificate was signed by a subnet with a delegation from the root key.
+    pub delegated: bool,
+
+    /// The path of the certified expression in the certified tree of the canister,
+    /// `None` for response verification v1.
+    pub expr_path: Option<Vec<String>>,
+
+    /// The parts of the response that were certified, `None` for response verification v1
+    /// and for responses that the canister certifiably excluded from certification.
+    pub certified_response: Option<CertifiedResponse>,
+
+    /// The names of the canister's response headers that were not returned to the client,
+    /// because they were not certified or not allowed by the [V1HeaderPolicy](crate::V1HeaderPolicy).
+    pub dropped_headers: Vec<String>,
+
+    /// The names of the canister's response headers that the gateway removed on purpose,
+    /// i.e. the certificate headers if they are stripped, and the `Content-Range` and `Content-Length`
+    /// of a partial response that is streamed to the client in full.
+    pub stripped_headers: Vec<String>,
+}
+
+/// The parts of a response that were certified.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertifiedResponse {
+    /// The certified status code, `None` if the status code was not certified.
+    pub status_code: Option<u16>,
+
+    /// The certified headers.
+    pub headers: Vec<(String, String)>,
+
+    /// The length of the certified body.
+    pub body_length: usize,
+}
+
+/// The decoded `IC-Certificate` and `IC-CertificateExpression` headers of a verified response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResponseCertificateMetadata {
+    /// The time at which the certificate was created.
+    pub time: Option<SystemTime>,
+
+    /// The delegation from the root key to the subnet that signed the certificate,
+    /// or `None` if the certificate was signed with the root key.
+    pub delegation: Option<CertificateDelegation>,
+
+    /// The path of the certified expression in the certified tree of the canister,
+    /// `None` for response verification v1.
+    pub expr_path: Option<Vec<String>>,
+
+    /// The certified expression that specifies which parts of the response are certified,
+    /// `None` for response verification v1.
+    pub certificate_expression: Option<String>,
+}
+
+/// The delegation of a certificate to the subnet that signed it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertificateDelegation {
+    /// The id of the subnet that signed the certificate.
+    pub subnet_id: Principal,
+
+    /// The CBOR-encoded certificate of the delegation, signed with the root key.
+    pub certificate: Vec<u8>,
+}
+
+/// How a response was served from the response cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheStatus {
+    /// The response is fresh according to its certified `Cache-Control` header.
+    Fresh,
+
+    /// The response is stale and is being refreshed in the background,
+    /// as allowed by the `stale-while-revalidate` directive.
+    /// Equivalent to a `Warning: 110 - "Response is Stale"` header.
+    StaleWhileRevalidate,
+
+    /// The response is stale and was served because the canister could not be reached,
+    /// as allowed by the `stale-if-error` directive.
+    /// Equivalent to a `Warning: 111 - "Revalidation Failed"` header.
+    StaleIfError,
 }
 
 pub type HttpGatewayResponseBody = Either<ResponseBodyStream, Full<Bytes>>;
@@ -41,3 +173,29 @@ pub type ResponseBodyStream = StreamBody<BoxStream<'static, ResponseBodyStreamIt
 
 /// An item in a response body stream.
 pub type ResponseBodyStreamItem = Result<Frame<Bytes>, AgentError>;
+
+/// The trailer reporting whether every chunk of a [ResponseBodyStream] was verified,
+/// either [VERIFIED_STATUS] or [UNVERIFIED_STATUS].
+pub const VERIFICATION_STATUS_TRAILER_NAME: &str = "x-ic-verification-status";
+
+/// The trailer reporting the number of chunks of a [ResponseBodyStream].
+pub const CHUNK_COUNT_TRAILER_NAME: &str = "x-ic-chunk-count";
+
+/// The trailer reporting the number of verified chunks of a [ResponseBodyStream].
+pub const VERIFIED_CHUNK_COUNT_TRAILER_NAME: &str = "x-ic-verified-chunk-count";
+
+/// The trailer carrying the SHA-256 digest of a [ResponseBodyStream], as `sha-256=:<base64>:`.
+pub const BODY_DIGEST_TRAILER_NAME: &str = "repr-digest";
+
+pub const VERIFIED_STATUS: &str = "verified";
+
+pub const UNVERIFIED_STATUS: &str = "unverified";
+
+/// The trailers sent at the end of every [ResponseBodyStream] that completes successfully,
+/// to be advertised up front in the `Trailer` header.
+pub const RESPONSE_BODY_STREAM_TRAILER_NAMES: [&str; 4] = [
+    VERIFICATION_STATUS_TRAILER_NAME,
+    CHUNK_COUNT_TRAILER_NAME,
+    VERIFIED_CHUNK_COUNT_TRAILER_NAME,
+    BODY_DIGEST_TRAILER_NAME,
+];
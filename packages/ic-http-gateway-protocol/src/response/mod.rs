@@ -3,3 +3,6 @@ pub use http_gateway_response::*;
 
 mod response_handler;
 pub use response_handler::*;
+
+mod streaming_token_predictor;
+pub use streaming_token_predictor::*;
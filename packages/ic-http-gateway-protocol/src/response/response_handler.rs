@@ -1,21 +1,34 @@
-use crate::protocol::validate;
-use crate::{HttpGatewayResponseBody, ResponseBodyStream};
-use bytes::Bytes;
+use crate::protocol::{
+    validate_on_pool, CallOverrides, CanisterCaller, VerifiableRequest, VerifiableResponse,
+};
+use crate::{
+    HttpGatewayResponseBody, ResponseBodyStream, ResponseBodyStreamItem, ResponseStreamingOptions,
+    ResponseVerificationOptions, StreamingTokenPredictor, BODY_DIGEST_TRAILER_NAME,
+    CHUNK_COUNT_TRAILER_NAME, CONTENT_DIGEST_HEADER_NAME, REPR_DIGEST_HEADER_NAME,
+    UNVERIFIED_STATUS, VERIFICATION_STATUS_TRAILER_NAME, VERIFIED_CHUNK_COUNT_TRAILER_NAME,
+    VERIFIED_STATUS,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use bytes::{Bytes, BytesMut};
 use candid::Principal;
-use futures::{stream, Stream, StreamExt, TryStreamExt};
+use futures::{
+    future::BoxFuture,
+    stream::{self, BoxStream, FuturesOrdered},
+    FutureExt, Stream, StreamExt, TryStreamExt,
+};
+use http::{HeaderMap, HeaderValue};
 use http_body::Frame;
 use http_body_util::{BodyExt, Full};
 use ic_agent::{Agent, AgentError};
-use ic_http_certification::{HttpRequest, HttpResponse, StatusCode};
-use ic_response_verification::MAX_VERIFICATION_VERSION;
+use ic_http_certification::StatusCode;
+use ic_response_verification::types::VerificationInfo;
 use ic_utils::interfaces::http_request::HeaderField;
-use ic_utils::{
-    call::SyncCall,
-    interfaces::http_request::{
-        HttpRequestCanister, HttpRequestStreamingCallbackAny, HttpResponse as AgentResponse,
-        StreamingCallbackHttpResponse, StreamingStrategy, Token,
-    },
+use ic_utils::interfaces::http_request::{
+    HttpRequestStreamingCallbackAny, HttpResponse as AgentResponse, StreamingCallbackHttpResponse,
+    StreamingStrategy, Token,
 };
+use sha2::{Digest, Sha256};
+use std::{collections::VecDeque, sync::Arc};
 
 // Limit the total number of calls to an HTTP Request loop to 1000 for now.
 static MAX_HTTP_REQUEST_STREAM_CALLBACK_CALL_COUNT: usize = 1000;
@@ -28,48 +41,56 @@ static STREAM_CALLBACK_BUFFER: usize = 2;
 
 pub type AgentResponseAny = AgentResponse<Token, HttpRequestStreamingCallbackAny>;
 
-pub async fn get_body_and_streaming_body(
-    agent: &Agent,
-    response: &AgentResponseAny,
+pub(crate) async fn get_body_and_streaming_body(
+    canister_caller: CanisterCaller<'_>,
+    response_body: Vec<u8>,
+    response_headers: &[HeaderField<'static>],
+    streaming_strategy: Option<StreamingStrategy<Token, HttpRequestStreamingCallbackAny>>,
+    response_streaming_options: &ResponseStreamingOptions,
 ) -> Result<HttpGatewayResponseBody, AgentError> {
     // if we already have the full body, we can return it early
-    let Some(StreamingStrategy::Callback(callback_strategy)) = response.streaming_strategy.clone()
-    else {
-        return Ok(HttpGatewayResponseBody::Right(Full::from(
-            response.body.clone(),
-        )));
+    let Some(StreamingStrategy::Callback(callback_strategy)) = streaming_strategy else {
+        return Ok(HttpGatewayResponseBody::Right(Full::new(Bytes::from(
+            response_body,
+        ))));
     };
 
+    // chunks are appended to the initial body in place
     let (streamed_body, token) = create_stream(
-        agent.clone(),
+        canister_caller.agent.clone(),
+        canister_caller.overrides,
         callback_strategy.callback.clone(),
         Some(callback_strategy.token),
+        response_streaming_options,
+        MAX_VERIFIED_HTTP_REQUEST_STREAM_CALLBACK_CALL_COUNT,
     )
     .take(MAX_VERIFIED_HTTP_REQUEST_STREAM_CALLBACK_CALL_COUNT)
     .map(|x| async move { x })
     .buffered(STREAM_CALLBACK_BUFFER)
     .try_fold(
-        (vec![], None::<Token>),
-        |mut accum, (mut body, token)| async move {
-            accum.0.append(&mut body);
+        (response_body, None::<Token>),
+        |mut accum, (body, token)| async move {
+            accum.0.extend_from_slice(&body);
             accum.1 = token;
 
             Ok(accum)
         },
     )
     .await?;
-
-    let streamed_body = [response.body.clone(), streamed_body].concat();
+    let streamed_body = Bytes::from(streamed_body);
 
     // if we still have a token at this point,
     // we were unable to collect the response within the allowed certified callback limit,
     // fallback to uncertified streaming using what we've streamed so far as the initial body
     if token.is_some() {
         let body_stream = create_body_stream(
-            agent.clone(),
+            canister_caller.agent.clone(),
+            canister_caller.overrides,
             callback_strategy.callback,
             token,
             streamed_body,
+            response_streaming_options,
+            get_callback_body_integrity(response_headers),
         );
 
         return Ok(HttpGatewayResponseBody::Left(body_stream));
@@ -78,70 +99,365 @@ pub async fn get_body_and_streaming_body(
     // if we no longer have a token at this point,
     // we were able to collect the response within the allow certified callback limit,
     // return this collected response as a standard response body so it will be verified
-    Ok(HttpGatewayResponseBody::Right(Full::from(streamed_body)))
+    Ok(HttpGatewayResponseBody::Right(Full::new(streamed_body)))
 }
 
 fn create_body_stream(
     agent: Agent,
+    overrides: CallOverrides,
     callback: HttpRequestStreamingCallbackAny,
     token: Option<Token>,
-    initial_body: Vec<u8>,
+    initial_body: Bytes,
+    response_streaming_options: &ResponseStreamingOptions,
+    body_integrity: BodyIntegrity,
 ) -> ResponseBodyStream {
-    let chunks_stream = create_stream(agent, callback, token)
-        .map(|chunk| chunk.map(|(body, _)| Frame::data(Bytes::from(body))));
+    // callback streams are never verified
+    let chunks_stream = create_stream(
+        agent,
+        overrides,
+        callback,
+        token,
+        response_streaming_options,
+        MAX_HTTP_REQUEST_STREAM_CALLBACK_CALL_COUNT,
+    )
+    .map(|chunk| chunk.map(|(body, _)| StreamedChunk::unverified(Bytes::from(body))));
 
-    let body_stream = stream::once(async move { Ok(Frame::data(Bytes::from(initial_body))) })
+    let body_stream = stream::once(async move { Ok(StreamedChunk::unverified(initial_body)) })
         .chain(chunks_stream)
         .take(MAX_HTTP_REQUEST_STREAM_CALLBACK_CALL_COUNT)
         .map(|x| async move { x })
         .buffered(STREAM_CALLBACK_BUFFER);
 
-    ResponseBodyStream::new(Box::pin(body_stream))
+    ResponseBodyStream::new(Box::pin(finish_body_stream(body_stream, body_integrity)))
+}
+
+/// A chunk of a streamed body.
+struct StreamedChunk {
+    data: Bytes,
+    verified: bool,
+}
+
+impl StreamedChunk {
+    fn unverified(data: Bytes) -> Self {
+        Self {
+            data,
+            verified: false,
+        }
+    }
+}
+
+/// The length and digest that a streamed body is expected to have, as advertised by the canister.
+///
+/// The check is advisory: it catches truncated and corrupted streams, but the headers
+/// that the length and digest are taken from are not necessarily certified,
+/// e.g. for callback streams and response verification v1.
+#[derive(Debug, Clone, Copy, Default)]
+struct BodyIntegrity {
+    length: Option<usize>,
+    sha256: Option<[u8; 32]>,
+}
+
+/// Callback streams are not verified, so the advertised length and digest
+/// are taken from the headers of the initial response as they are.
+fn get_callback_body_integrity(response_headers: &[HeaderField<'static>]) -> BodyIntegrity {
+    let get_header_value = |header_name: &str| {
+        response_headers
+            .iter()
+            .find(|HeaderField(name, _)| name.eq_ignore_ascii_case(header_name))
+            .map(|HeaderField(_, value)| value.as_ref())
+    };
+
+    BodyIntegrity {
+        length: get_header_value(http::header::CONTENT_LENGTH.as_ref())
+            .and_then(|length| length.trim().parse().ok()),
+        sha256: get_header_value(REPR_DIGEST_HEADER_NAME)
+            .or_else(|| get_header_value(CONTENT_DIGEST_HEADER_NAME))
+            .and_then(parse_sha256_digest),
+    }
+}
+
+/// What has been streamed of a body so far.
+#[derive(Default)]
+struct StreamedBodySummary {
+    hasher: Sha256,
+    length: usize,
+    chunk_count: usize,
+    verified_chunk_count: usize,
+}
+
+impl StreamedBodySummary {
+    fn add_chunk(&mut self, chunk: &StreamedChunk) {
+        self.hasher.update(&chunk.data);
+        self.length += chunk.data.len();
+        self.chunk_count += 1;
+        if chunk.verified {
+            self.verified_chunk_count += 1;
+        }
+    }
+
+    fn into_trailers(self, sha256: [u8; 32]) -> HeaderMap {
+        let verification_status = if self.verified_chunk_count == self.chunk_count {
+            VERIFIED_STATUS
+        } else {
+            UNVERIFIED_STATUS
+        };
+
+        let mut trailers = HeaderMap::new();
+        trailers.insert(
+            VERIFICATION_STATUS_TRAILER_NAME,
+            HeaderValue::from_static(verification_status),
+        );
+        trailers.insert(
+            CHUNK_COUNT_TRAILER_NAME,
+            HeaderValue::from(self.chunk_count),
+        );
+        trailers.insert(
+            VERIFIED_CHUNK_COUNT_TRAILER_NAME,
+            HeaderValue::from(self.verified_chunk_count),
+        );
+        // base64 is always a valid header value
+        if let Ok(digest) = HeaderValue::try_from(format!("sha-256=:{}:", BASE64.encode(sha256))) {
+            trailers.insert(BODY_DIGEST_TRAILER_NAME, digest);
+        }
+
+        trailers
+    }
+}
+
+/// Turns the chunks of a streamed body into frames, ending with trailers that report
+/// how many chunks were verified and the SHA-256 digest of the body.
+///
+/// If the length or digest of the body do not match the expected length and digest,
+/// an error is yielded instead of the trailers. Failures abort the stream rather than
+/// completing it, so that clients that ignore trailers never mistake a partial body for a complete one.
+fn finish_body_stream(
+    chunks_stream: impl Stream<Item = Result<StreamedChunk, AgentError>> + Send + 'static,
+    expected: BodyIntegrity,
+) -> impl Stream<Item = ResponseBodyStreamItem> {
+    stream::unfold(
+        Some((chunks_stream.boxed(), StreamedBodySummary::default())),
+        move |state| async move {
+            let (mut chunks_stream, mut summary) = state?;
+
+            match chunks_stream.next().await {
+                Some(Ok(chunk)) => {
+                    summary.add_chunk(&chunk);
+                    if let Some(expected_length) = expected.length.filter(|l| summary.length > *l) {
+                        return Some((
+                            Err(AgentError::InvalidHttpResponse(format!(
+                                "streamed body exceeds the advertised length of {} bytes",
+                                expected_length
+                            ))),
+                            None,
+                        ));
+                    }
+
+                    Some((Ok(Frame::data(chunk.data)), Some((chunks_stream, summary))))
+                }
+                Some(Err(e)) => Some((Err(e), None)),
+                None => {
+                    if let Some(expected_length) = expected.length.filter(|l| summary.length != *l)
+                    {
+                        return Some((
+                            Err(AgentError::InvalidHttpResponse(format!(
+                                "streamed body length of {} bytes does not match the advertised length of {} bytes",
+                                summary.length, expected_length
+                            ))),
+                            None,
+                        ));
+                    }
+                    let sha256: [u8; 32] = summary.hasher.clone().finalize().into();
+                    if expected
+                        .sha256
+                        .is_some_and(|expected_sha256| expected_sha256 != sha256)
+                    {
+                        return Some((
+                            Err(AgentError::InvalidHttpResponse(
+                                "streamed body does not match the advertised SHA-256 digest"
+                                    .to_string(),
+                            )),
+                            None,
+                        ));
+                    }
+
+                    Some((Ok(Frame::trailers(summary.into_trailers(sha256))), None))
+                }
+            }
+        },
+    )
 }
 
+type CallbackChunk = Result<(Vec<u8>, Option<Token>), AgentError>;
+
+type FetchCallbackChunk = Arc<dyn Fn(Token) -> BoxFuture<'static, CallbackChunk> + Send + Sync>;
+
+/// Creates a stream of at most `max_chunk_count` chunks.
+/// If the tokens of the canister can be predicted, upcoming chunks are prefetched concurrently,
+/// otherwise chunks are fetched one after another.
 fn create_stream(
     agent: Agent,
+    overrides: CallOverrides,
+    callback: HttpRequestStreamingCallbackAny,
+    token: Option<Token>,
+    response_streaming_options: &ResponseStreamingOptions,
+    max_chunk_count: usize,
+) -> BoxStream<'static, CallbackChunk> {
+    match &response_streaming_options.streaming_token_predictor {
+        Some(streaming_token_predictor)
+            if response_streaming_options.max_concurrent_chunk_requests > 1 =>
+        {
+            create_predicted_stream(PredictedStreamState {
+                fetch_chunk: Arc::new(move |token| {
+                    fetch_callback_chunk(agent.clone(), overrides, callback.clone(), token).boxed()
+                }),
+                streaming_token_predictor: Arc::clone(streaming_token_predictor),
+                max_concurrent_chunk_requests: response_streaming_options
+                    .max_concurrent_chunk_requests,
+                remaining_chunk_count: max_chunk_count,
+                next_token: token,
+                requested_tokens: VecDeque::new(),
+                pending_chunks: FuturesOrdered::new(),
+            })
+            .boxed()
+        }
+        _ => create_sequential_stream(agent, overrides, callback, token)
+            .take(max_chunk_count)
+            .boxed(),
+    }
+}
+
+fn create_sequential_stream(
+    agent: Agent,
+    overrides: CallOverrides,
     callback: HttpRequestStreamingCallbackAny,
     token: Option<Token>,
-) -> impl Stream<Item = Result<(Vec<u8>, Option<Token>), AgentError>> {
+) -> impl Stream<Item = CallbackChunk> {
     futures::stream::try_unfold(
         (agent, callback, token),
-        |(agent, callback, token)| async move {
+        move |(agent, callback, token)| async move {
             let Some(token) = token else {
                 return Ok(None);
             };
 
-            let canister = HttpRequestCanister::create(&agent, callback.0.principal);
-            match canister
-                .http_request_stream_callback(&callback.0.method, token)
-                .call()
-                .await
-            {
-                Ok((StreamingCallbackHttpResponse { body, token },)) => {
-                    Ok(Some(((body, token.clone()), (agent, callback, token))))
-                }
-                Err(e) => Err(e),
-            }
+            let (body, token) =
+                fetch_callback_chunk(agent.clone(), overrides, callback.clone(), token).await?;
+            Ok(Some(((body, token.clone()), (agent, callback, token))))
         },
     )
 }
 
+struct PredictedStreamState {
+    fetch_chunk: FetchCallbackChunk,
+    streaming_token_predictor: Arc<dyn StreamingTokenPredictor>,
+    max_concurrent_chunk_requests: usize,
+    remaining_chunk_count: usize,
+    /// The token returned with the most recently delivered chunk.
+    next_token: Option<Token>,
+    /// The tokens of the pending chunks, in order.
+    requested_tokens: VecDeque<Token>,
+    pending_chunks: FuturesOrdered<BoxFuture<'static, CallbackChunk>>,
+}
+
+impl PredictedStreamState {
+    /// Requests chunks until `max_concurrent_chunk_requests` are pending
+    /// or the token of the next chunk cannot be predicted.
+    fn request_chunks(&mut self) {
+        while self.pending_chunks.len() < self.max_concurrent_chunk_requests
+            && self.remaining_chunk_count > 0
+        {
+            let token = match self.requested_tokens.back() {
+                Some(last_token) => self
+                    .streaming_token_predictor
+                    .predict_next_token(last_token),
+                None => self.next_token.take(),
+            };
+            let Some(token) = token else {
+                break;
+            };
+
+            self.requested_tokens.push_back(token.clone());
+            self.pending_chunks.push_back((self.fetch_chunk)(token));
+            self.remaining_chunk_count -= 1;
+        }
+    }
+
+    /// Discards all pending chunks, they do not count towards the chunk limit.
+    fn discard_pending_chunks(&mut self) {
+        self.remaining_chunk_count += self.pending_chunks.len();
+        self.requested_tokens.clear();
+        self.pending_chunks = FuturesOrdered::new();
+    }
+}
+
+fn create_predicted_stream(state: PredictedStreamState) -> impl Stream<Item = CallbackChunk> {
+    futures::stream::unfold(state, |mut state| async move {
+        state.request_chunks();
+        let chunk = state.pending_chunks.next().await?;
+        state.requested_tokens.pop_front();
+
+        match chunk {
+            Ok((body, token)) => {
+                // the chunks that were prefetched with a wrong prediction,
+                // or past the end of the stream, are never delivered
+                if state.requested_tokens.front().map(|t| &t.0) != token.as_ref().map(|t| &t.0) {
+                    state.discard_pending_chunks();
+                }
+                state.next_token = token.clone();
+
+                Some((Ok((body, token)), state))
+            }
+            Err(e) => {
+                // end the stream after the first error, like a sequential stream
+                state.discard_pending_chunks();
+                state.next_token = None;
+
+                Some((Err(e), state))
+            }
+        }
+    })
+}
+
+async fn fetch_callback_chunk(
+    agent: Agent,
+    overrides: CallOverrides,
+    callback: HttpRequestStreamingCallbackAny,
+    token: Token,
+) -> CallbackChunk {
+    let StreamingCallbackHttpResponse { body, token } = CanisterCaller {
+        overrides,
+        ..CanisterCaller::new(&agent, callback.0.principal)
+    }
+    .http_request_stream_callback(&callback.0.method, token)
+    .await?;
+
+    Ok((body, token))
+}
+
 #[derive(Clone, Debug)]
-struct StreamState<'a> {
-    pub http_request: HttpRequest<'a>,
+struct StreamState {
+    pub http_request: VerifiableRequest,
     pub canister_id: Principal,
+    pub overrides: CallOverrides,
+    pub http_request_method: String,
     pub total_length: usize,
     pub fetched_length: usize,
+    pub etag: Option<String>,
+    pub sha256: Option<[u8; 32]>,
     pub skip_verification: bool,
+    pub response_verification_options: ResponseVerificationOptions,
 }
 
-pub async fn get_206_stream_response_body_and_total_length(
-    agent: &Agent,
-    http_request: HttpRequest<'static>,
-    canister_id: Principal,
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn get_206_stream_response_body_and_total_length(
+    canister_caller: CanisterCaller<'_>,
+    http_request: VerifiableRequest,
+    http_request_method: String,
     response_headers: &Vec<HeaderField<'static>>,
+    verification_info: Option<&VerificationInfo>,
     response_206_body: HttpGatewayResponseBody,
     skip_verification: bool,
+    response_verification_options: ResponseVerificationOptions,
+    response_streaming_options: &ResponseStreamingOptions,
 ) -> Result<(HttpGatewayResponseBody, usize), AgentError> {
     let HttpGatewayResponseBody::Right(body) = response_206_body else {
         return Err(AgentError::InvalidHttpResponse(
@@ -153,17 +469,26 @@ pub async fn get_206_stream_response_body_and_total_length(
         .collect()
         .await
         .expect("missing streamed chunk body")
-        .to_bytes()
-        .to_vec();
+        .to_bytes();
     let stream_state = get_initial_stream_state(
         http_request,
-        canister_id,
+        canister_caller.canister_id,
+        canister_caller.overrides,
+        http_request_method,
         response_headers,
+        verification_info,
         skip_verification,
+        response_verification_options,
     )?;
     let content_length = stream_state.total_length;
 
-    let body_stream = create_206_body_stream(agent.clone(), stream_state, streamed_body);
+    let body_stream = create_206_body_stream(
+        canister_caller.agent.clone(),
+        stream_state,
+        streamed_body,
+        verification_info.is_some(),
+        response_streaming_options.max_concurrent_chunk_requests,
+    );
     Ok((HttpGatewayResponseBody::Left(body_stream), content_length))
 }
 
@@ -274,152 +599,417 @@ fn get_content_range_values(
     Ok(range_values)
 }
 
-fn get_initial_stream_state<'a>(
-    http_request: HttpRequest<'a>,
+/// Returns the value of a response header that a stream relies on, such as the `ETag`.
+///
+/// If the canister certified the response headers, only a certified header is used.
+/// Otherwise, i.e. for response verification v1 or if verification is skipped,
+/// the header is taken from the response headers as they are.
+fn get_header_value(
+    response_headers: &[HeaderField<'static>],
+    verification_info: Option<&VerificationInfo>,
+    header_name: &str,
+) -> Option<String> {
+    match verification_info.and_then(|info| info.response.as_ref()) {
+        Some(certified_response) => certified_response
+            .headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(header_name))
+            .map(|(_, value)| value.clone()),
+        None => response_headers
+            .iter()
+            .find(|HeaderField(name, _)| name.eq_ignore_ascii_case(header_name))
+            .map(|HeaderField(_, value)| value.to_string()),
+    }
+}
+
+fn get_etag(
+    response_headers: &[HeaderField<'static>],
+    verification_info: Option<&VerificationInfo>,
+) -> Option<String> {
+    get_header_value(
+        response_headers,
+        verification_info,
+        http::header::ETAG.as_ref(),
+    )
+}
+
+/// Parses the SHA-256 digest from a `Repr-Digest` or `Content-Digest` header value,
+/// e.g. `sha-256=:RK/0qy18MlBSVnWgjwz6lZEWjP/lF5HF9bvEF8FabDg=:`.
+fn parse_sha256_digest(header_value: &str) -> Option<[u8; 32]> {
+    header_value
+        .split(',')
+        .filter_map(|digest| digest.trim().split_once('='))
+        .find(|(algorithm, _)| algorithm.trim().eq_ignore_ascii_case("sha-256"))
+        .and_then(|(_, digest)| BASE64.decode(digest.trim().trim_matches(':')).ok())
+        .and_then(|digest| digest.try_into().ok())
+}
+
+/// Ensures that a chunk belongs to the same version of the asset as the first chunk of the stream,
+/// so that a body is never spliced together from two versions of an asset that changed mid-stream.
+fn check_chunk_consistency(
+    stream_state: &StreamState,
+    chunk_begin: usize,
+    range_values: &ContentRangeValues,
+    etag: Option<&str>,
+) -> Result<(), AgentError> {
+    if range_values.total_length != stream_state.total_length {
+        return Err(AgentError::InvalidHttpResponse(format!(
+            "asset changed mid-stream: total_length={} of the chunk starting at {} differs from the initial total_length={}",
+            range_values.total_length, chunk_begin, stream_state.total_length
+        )));
+    }
+    if etag != stream_state.etag.as_deref() {
+        return Err(AgentError::InvalidHttpResponse(format!(
+            "asset changed mid-stream: ETag {:?} of the chunk starting at {} differs from the initial ETag {:?}",
+            etag, chunk_begin, stream_state.etag
+        )));
+    }
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn get_initial_stream_state(
+    http_request: VerifiableRequest,
     canister_id: Principal,
+    overrides: CallOverrides,
+    http_request_method: String,
     response_headers: &Vec<HeaderField<'static>>,
+    verification_info: Option<&VerificationInfo>,
     skip_verification: bool,
-) -> Result<StreamState<'a>, AgentError> {
+    response_verification_options: ResponseVerificationOptions,
+) -> Result<StreamState, AgentError> {
     let range_values = get_content_range_values(response_headers, 0)?;
 
     Ok(StreamState {
         http_request,
         canister_id,
+        overrides,
+        http_request_method,
         total_length: range_values.total_length,
         fetched_length: range_values
             .range_end
             .saturating_sub(range_values.range_begin)
             + 1,
+        etag: get_etag(response_headers, verification_info),
+        // a `Content-Digest` would only cover the first chunk
+        sha256: get_header_value(response_headers, verification_info, REPR_DIGEST_HEADER_NAME)
+            .and_then(|digest| parse_sha256_digest(&digest)),
         skip_verification,
+        response_verification_options,
     })
 }
 
 fn create_206_body_stream(
     agent: Agent,
-    stream_state: StreamState<'static>,
-    initial_body: Vec<u8>,
+    stream_state: StreamState,
+    initial_body: Bytes,
+    is_initial_body_verified: bool,
+    max_concurrent_chunk_requests: usize,
 ) -> ResponseBodyStream {
-    let chunks_stream = create_206_stream(agent, Some(stream_state))
-        .map(|chunk| chunk.map(|(body, _)| Frame::data(Bytes::from(body))));
+    let body_integrity = BodyIntegrity {
+        length: Some(stream_state.total_length),
+        sha256: stream_state.sha256,
+    };
+    let are_chunks_verified = !stream_state.skip_verification;
+    let chunks_stream =
+        create_206_stream(agent, stream_state, max_concurrent_chunk_requests).map(move |chunk| {
+            chunk.map(|data| StreamedChunk {
+                data,
+                verified: are_chunks_verified,
+            })
+        });
 
-    let body_stream = stream::once(async move { Ok(Frame::data(Bytes::from(initial_body))) })
+    let initial_chunk = StreamedChunk {
+        data: initial_body,
+        verified: is_initial_body_verified,
+    };
+    let body_stream = stream::once(async move { Ok(initial_chunk) })
         .chain(chunks_stream)
-        .take(MAX_HTTP_REQUEST_STREAM_CALLBACK_CALL_COUNT)
-        .map(|x| async move { x })
-        .buffered(STREAM_CALLBACK_BUFFER);
+        .take(MAX_HTTP_REQUEST_STREAM_CALLBACK_CALL_COUNT);
 
-    ResponseBodyStream::new(Box::pin(body_stream))
+    ResponseBodyStream::new(Box::pin(finish_body_stream(body_stream, body_integrity)))
 }
 
+/// Fetches the remaining chunks of a 206 stream, with up to `max_concurrent_chunk_requests` chunks
+/// being fetched and verified at a time. Chunks are delivered in order.
 fn create_206_stream(
     agent: Agent,
-    maybe_stream_state: Option<StreamState>,
-) -> impl Stream<Item = Result<(Vec<u8>, Option<StreamState>), AgentError>> {
-    futures::stream::try_unfold(
-        (agent, maybe_stream_state),
-        |(agent, maybe_stream_state)| async move {
-            let Some(stream_state) = maybe_stream_state else {
-                return Ok(None);
-            };
-            let canister = HttpRequestCanister::create(&agent, stream_state.canister_id);
-            let next_chunk_begin = stream_state.fetched_length;
+    stream_state: StreamState,
+    max_concurrent_chunk_requests: usize,
+) -> impl Stream<Item = Result<Bytes, AgentError>> {
+    let chunk_ranges = get_chunk_ranges(stream_state.fetched_length, stream_state.total_length);
 
-            let range_header = ("Range".to_string(), format!("bytes={}-", next_chunk_begin));
-            let mut updated_headers = stream_state.http_request.headers().to_vec();
-            updated_headers.push(range_header.clone());
-            let headers = updated_headers
-                .iter()
-                .map(|(name, value)| HeaderField(name.into(), value.into()))
-                .collect::<Vec<HeaderField>>()
-                .into_iter();
-            let query_result = canister
-                .http_request(
-                    &stream_state.http_request.method(),
-                    &stream_state.http_request.url(),
-                    headers,
-                    &stream_state.http_request.body(),
-                    Some(&u16::from(MAX_VERIFICATION_VERSION)),
-                )
-                .call()
-                .await;
-            let agent_response = match query_result {
-                Ok((response,)) => response,
-                Err(e) => return Err(e),
-            };
-            let range_values =
-                get_content_range_values(&agent_response.headers, stream_state.fetched_length)?;
-            let new_bytes_begin = stream_state
-                .fetched_length
-                .saturating_sub(range_values.range_begin);
-            let chunk_length = range_values
-                .range_end
-                .saturating_sub(stream_state.fetched_length)
-                + 1;
-            let current_fetched_length = stream_state.fetched_length + chunk_length;
-            // Verify the chunk from the range response.
-            if agent_response.streaming_strategy.is_some() {
-                return Err(AgentError::InvalidHttpResponse(
-                    "unexpected StreamingStrategy".to_string(),
-                ));
-            }
+    stream::iter(chunk_ranges)
+        .map(move |(chunk_begin, chunk_end)| {
+            fetch_chunk_range(agent.clone(), stream_state.clone(), chunk_begin, chunk_end)
+        })
+        .buffered(max_concurrent_chunk_requests.max(1))
+}
 
-            let Ok(status_code) = StatusCode::from_u16(agent_response.status_code) else {
-                return Err(AgentError::InvalidHttpResponse(format!(
-                    "Invalid canister response status code: {}",
-                    agent_response.status_code
-                )));
-            };
-            let response = HttpResponse::builder()
-                .with_status_code(status_code)
-                .with_headers(
-                    agent_response
-                        .headers
-                        .iter()
-                        .map(|HeaderField(k, v)| (k.to_string(), v.to_string()))
-                        .collect(),
-                )
-                .with_body(agent_response.body.clone())
-                .build();
-            let mut http_request = stream_state.http_request.clone();
-            http_request.headers_mut().push(range_header);
-            let validation_result = validate(
-                &agent,
-                &stream_state.canister_id,
-                http_request,
-                response,
-                stream_state.skip_verification,
-            );
-
-            if let Err(e) = validation_result {
-                return Err(AgentError::InvalidHttpResponse(format!(
-                    "CertificateVerificationFailed for a chunk starting at {}, error: {}",
-                    stream_state.fetched_length, e
-                )));
-            }
-            let maybe_new_state = if current_fetched_length < stream_state.total_length {
-                Some(StreamState {
-                    fetched_length: current_fetched_length,
-                    ..stream_state
-                })
-            } else {
-                None
-            };
-            Ok(Some((
-                (
-                    agent_response.body[new_bytes_begin..].to_vec(),
-                    maybe_new_state.clone(),
-                ),
-                (agent, maybe_new_state),
-            )))
-        },
+/// Splits the rest of an asset into inclusive byte ranges the size of the first chunk,
+/// so that the offsets of all chunks are known upfront.
+fn get_chunk_ranges(
+    first_chunk_length: usize,
+    total_length: usize,
+) -> impl Iterator<Item = (usize, usize)> {
+    (first_chunk_length..total_length)
+        .step_by(first_chunk_length.max(1))
+        .map(move |chunk_begin| {
+            (
+                chunk_begin,
+                (chunk_begin + first_chunk_length).min(total_length) - 1,
+            )
+        })
+}
+
+/// Fetches the inclusive byte range `chunk_begin..=chunk_end`.
+/// If the canister returns the whole range at once, the range shares the buffer of the response,
+/// otherwise the rest is fetched sequentially and copied together.
+async fn fetch_chunk_range(
+    agent: Agent,
+    stream_state: StreamState,
+    chunk_begin: usize,
+    chunk_end: usize,
+) -> Result<Bytes, AgentError> {
+    let mut body = BytesMut::new();
+    let mut next_begin = chunk_begin;
+
+    while next_begin <= chunk_end {
+        let (range_values, chunk_body) = fetch_chunk(&agent, &stream_state, next_begin).await?;
+        let range_end = range_values.range_end.min(chunk_end);
+        let chunk_body = chunk_body
+            .slice(next_begin - range_values.range_begin..=range_end - range_values.range_begin);
+
+        if next_begin == chunk_begin && range_end == chunk_end {
+            return Ok(chunk_body);
+        }
+        if body.is_empty() {
+            body.reserve(chunk_end - chunk_begin + 1);
+        }
+        body.extend_from_slice(&chunk_body);
+        next_begin = range_end + 1;
+    }
+
+    Ok(body.freeze())
+}
+
+/// Fetches and verifies the chunk that contains the byte at `chunk_begin`.
+async fn fetch_chunk(
+    agent: &Agent,
+    stream_state: &StreamState,
+    chunk_begin: usize,
+) -> Result<(ContentRangeValues, Bytes), AgentError> {
+    let mut http_request = stream_state.http_request.clone();
+    http_request
+        .headers
+        .push(("Range".to_string(), format!("bytes={}-", chunk_begin)));
+    if let Some(etag) = &stream_state.etag {
+        http_request
+            .headers
+            .push(("If-Match".to_string(), etag.clone()));
+    }
+    let agent_response = CanisterCaller {
+        overrides: stream_state.overrides,
+        ..CanisterCaller::new(agent, stream_state.canister_id)
+    }
+    .http_request(
+        &stream_state.http_request_method,
+        http_request.method.as_str(),
+        &http_request.url,
+        &http_request.header_fields().collect::<Vec<_>>(),
+        &http_request.body,
+        stream_state
+            .response_verification_options
+            .verification_version_policy
+            .requested_version(&stream_state.canister_id),
+    )
+    .await?;
+    // canisters that support `If-Match` reject the chunk request if the asset changed
+    if agent_response.status_code == StatusCode::PRECONDITION_FAILED.as_u16() {
+        return Err(AgentError::InvalidHttpResponse(format!(
+            "asset changed mid-stream: the chunk starting at {} no longer matches ETag {:?}",
+            chunk_begin, stream_state.etag
+        )));
+    }
+    let range_values = get_content_range_values(&agent_response.headers, chunk_begin)?;
+    if agent_response.body.len() != range_values.range_end - range_values.range_begin + 1 {
+        return Err(AgentError::InvalidHttpResponse(format!(
+            "chunk length {} does not match Content-Range {:?}",
+            agent_response.body.len(),
+            range_values
+        )));
+    }
+    // Verify the chunk from the range response.
+    if agent_response.streaming_strategy.is_some() {
+        return Err(AgentError::InvalidHttpResponse(
+            "unexpected StreamingStrategy".to_string(),
+        ));
+    }
+
+    let Ok(status_code) = StatusCode::from_u16(agent_response.status_code) else {
+        return Err(AgentError::InvalidHttpResponse(format!(
+            "Invalid canister response status code: {}",
+            agent_response.status_code
+        )));
+    };
+    let response = VerifiableResponse::new(
+        status_code,
+        &agent_response.headers,
+        Bytes::from(agent_response.body),
+    );
+    let validation_result = validate_on_pool(
+        agent,
+        &stream_state.canister_id,
+        &http_request,
+        &response,
+        stream_state.skip_verification,
+        &stream_state.response_verification_options,
     )
+    .await;
+
+    let verification_info = match validation_result {
+        Ok(verification_info) => verification_info,
+        Err(e) => {
+            return Err(AgentError::InvalidHttpResponse(format!(
+                "CertificateVerificationFailed for a chunk starting at {}, error: {}",
+                chunk_begin, e
+            )));
+        }
+    };
+    check_chunk_consistency(
+        stream_state,
+        chunk_begin,
+        &range_values,
+        get_etag(&agent_response.headers, verification_info.as_ref()).as_deref(),
+    )?;
+
+    Ok((range_values, response.body))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use assert_matches::assert_matches;
-    use std::borrow::Cow;
+    use candid::types::value::IDLValue;
+    use ic_response_verification::types::VerifiedResponse;
+    use std::{
+        borrow::Cow,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    fn some_file_request() -> VerifiableRequest {
+        VerifiableRequest {
+            method: http::Method::GET,
+            url: "http://example.com/some_file".to_string(),
+            headers: vec![("Xyz".to_string(), "some value".to_string())],
+            body: Bytes::from_static(&[42]),
+        }
+    }
+
+    #[derive(Debug)]
+    struct IncrementingTokenPredictor;
+
+    impl StreamingTokenPredictor for IncrementingTokenPredictor {
+        fn predict_next_token(&self, token: &Token) -> Option<Token> {
+            match token.0 {
+                IDLValue::Nat64(index) => Some(Token(IDLValue::Nat64(index + 1))),
+                _ => None,
+            }
+        }
+    }
+
+    /// Creates a predicted stream over `chunk_count` chunks, where chunk `i` contains the byte `i`
+    /// and the token of chunk `next_index(i)`, unless that is the end of the stream.
+    /// Returns the stream and the number of requested chunks.
+    fn create_test_predicted_stream(
+        chunk_count: u64,
+        next_index: fn(u64) -> u64,
+        max_chunk_count: usize,
+    ) -> (impl Stream<Item = CallbackChunk>, Arc<AtomicUsize>) {
+        let request_count = Arc::new(AtomicUsize::new(0));
+        let fetch_request_count = Arc::clone(&request_count);
+
+        let stream = create_predicted_stream(PredictedStreamState {
+            fetch_chunk: Arc::new(move |token: Token| {
+                fetch_request_count.fetch_add(1, Ordering::SeqCst);
+                let IDLValue::Nat64(index) = token.0 else {
+                    unreachable!()
+                };
+
+                async move {
+                    if index >= chunk_count {
+                        return Err(AgentError::InvalidHttpResponse(
+                            "chunk index out of bounds".to_string(),
+                        ));
+                    }
+
+                    let next_index = next_index(index);
+                    Ok((
+                        vec![index as u8],
+                        (next_index != chunk_count).then_some(Token(IDLValue::Nat64(next_index))),
+                    ))
+                }
+                .boxed()
+            }),
+            streaming_token_predictor: Arc::new(IncrementingTokenPredictor),
+            max_concurrent_chunk_requests: 4,
+            remaining_chunk_count: max_chunk_count,
+            next_token: Some(Token(IDLValue::Nat64(0))),
+            requested_tokens: VecDeque::new(),
+            pending_chunks: FuturesOrdered::new(),
+        });
+
+        (stream, request_count)
+    }
+
+    async fn collect_chunks(stream: impl Stream<Item = CallbackChunk>) -> Vec<Result<u8, String>> {
+        stream
+            .map(|chunk| chunk.map(|(body, _)| body[0]).map_err(|e| e.to_string()))
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn should_deliver_predicted_chunks_in_order() {
+        let (stream, request_count) = create_test_predicted_stream(10, |i| i + 1, 1000);
+
+        assert_eq!(
+            collect_chunks(stream).await,
+            (0..10).map(Ok).collect::<Vec<_>>()
+        );
+        // chunks past the end of the stream may have been prefetched, but are never delivered
+        assert!(request_count.load(Ordering::SeqCst) < 10 + 4);
+    }
+
+    #[tokio::test]
+    async fn should_fall_back_to_returned_tokens_on_misprediction() {
+        let (stream, _) = create_test_predicted_stream(10, |i| i + 2, 1000);
+
+        assert_eq!(
+            collect_chunks(stream).await,
+            vec![Ok(0), Ok(2), Ok(4), Ok(6), Ok(8)]
+        );
+    }
+
+    #[tokio::test]
+    async fn should_limit_predicted_chunk_count() {
+        let (stream, request_count) = create_test_predicted_stream(10, |i| i + 1, 3);
+
+        assert_eq!(collect_chunks(stream).await, vec![Ok(0), Ok(1), Ok(2)]);
+        assert_eq!(request_count.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn should_end_predicted_stream_after_error() {
+        let (stream, _) =
+            create_test_predicted_stream(10, |i| if i == 2 { 20 } else { i + 1 }, 1000);
+        let chunks = collect_chunks(stream).await;
+
+        assert_eq!(chunks.len(), 4);
+        assert_eq!(chunks[..3], [Ok(0), Ok(1), Ok(2)]);
+        assert_matches!(&chunks[3], Err(e) if e.contains("chunk index out of bounds"));
+    }
 
     #[test]
     fn should_parse_content_range_header_str() {
@@ -477,10 +1067,7 @@ mod tests {
 
     #[test]
     fn should_get_initial_stream_state() {
-        let http_request = HttpRequest::get("http://example.com/some_file")
-            .with_headers(vec![("Xyz".to_string(), "some value".to_string())])
-            .with_body(vec![42])
-            .build();
+        let http_request = some_file_request();
         let canister_id = Principal::from_slice(&[1, 2, 3, 4]);
         let response_headers = vec![HeaderField(
             Cow::from("Content-Range"),
@@ -490,59 +1077,299 @@ mod tests {
         let state = get_initial_stream_state(
             http_request.clone(),
             canister_id,
+            CallOverrides::default(),
+            "http_request".to_string(),
             &response_headers,
+            None,
             skip_verification,
+            ResponseVerificationOptions::default(),
         )
         .expect("failed constructing StreamState");
-        assert_eq!(state.http_request, http_request);
+        assert_eq!(
+            state.http_request.as_http_request(),
+            http_request.as_http_request()
+        );
         assert_eq!(state.canister_id, canister_id);
         assert_eq!(state.fetched_length, 3);
         assert_eq!(state.total_length, 10);
         assert_eq!(state.skip_verification, skip_verification);
     }
 
+    #[test]
+    fn should_pin_stream_state_to_certified_etag() {
+        let http_request = some_file_request();
+        let canister_id = Principal::from_slice(&[1, 2, 3, 4]);
+        let response_headers = vec![
+            HeaderField(Cow::from("Content-Range"), Cow::from("bytes 0-2/10")),
+            HeaderField(Cow::from("ETag"), Cow::from("\"uncertified\"")),
+        ];
+        let verification_info = VerificationInfo {
+            response: Some(VerifiedResponse {
+                status_code: Some(206),
+                headers: vec![("etag".to_string(), "\"certified\"".to_string())],
+                body: vec![],
+            }),
+            verification_version: 2,
+        };
+
+        let state = get_initial_stream_state(
+            http_request.clone(),
+            canister_id,
+            CallOverrides::default(),
+            "http_request".to_string(),
+            &response_headers,
+            Some(&verification_info),
+            false,
+            ResponseVerificationOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(state.etag.as_deref(), Some("\"certified\""));
+
+        let state = get_initial_stream_state(
+            http_request,
+            canister_id,
+            CallOverrides::default(),
+            "http_request".to_string(),
+            &response_headers,
+            None,
+            true,
+            ResponseVerificationOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(state.etag.as_deref(), Some("\"uncertified\""));
+    }
+
+    async fn collect_checked_body(
+        chunks: &[&'static [u8]],
+        expected: BodyIntegrity,
+    ) -> Vec<Result<Frame<Bytes>, String>> {
+        let body_stream = stream::iter(
+            chunks
+                .iter()
+                .map(|chunk| {
+                    Ok(StreamedChunk {
+                        data: Bytes::from_static(chunk),
+                        verified: true,
+                    })
+                })
+                .collect::<Vec<_>>(),
+        );
+
+        finish_body_stream(body_stream, expected)
+            .map(|frame| frame.map_err(|e| e.to_string()))
+            .collect()
+            .await
+    }
+
+    fn data_frames(body: &[Result<Frame<Bytes>, String>]) -> Vec<Bytes> {
+        body.iter()
+            .filter_map(|frame| frame.as_ref().ok()?.data_ref().cloned())
+            .collect()
+    }
+
+    #[test]
+    fn should_parse_sha256_digest() {
+        let sha256: [u8; 32] = Sha256::digest(b"hello world").into();
+        let header_value = format!("sha-512=:AAAA:, sha-256=:{}:", BASE64.encode(sha256));
+
+        assert_eq!(parse_sha256_digest(&header_value), Some(sha256));
+        assert_eq!(parse_sha256_digest("sha-256=:AAAA:"), None);
+        assert_eq!(parse_sha256_digest("sha-512=:AAAA:"), None);
+    }
+
+    #[tokio::test]
+    async fn should_pass_body_with_advertised_length_and_digest() {
+        let expected = BodyIntegrity {
+            length: Some(11),
+            sha256: Some(Sha256::digest(b"hello world").into()),
+        };
+
+        let body = collect_checked_body(&[b"hello", b" ", b"world"], expected).await;
+        assert_eq!(body.len(), 4);
+        assert_eq!(
+            data_frames(&body),
+            vec![
+                Bytes::from_static(b"hello"),
+                Bytes::from_static(b" "),
+                Bytes::from_static(b"world"),
+            ]
+        );
+        assert_matches!(&body[3], Ok(frame) if frame.is_trailers());
+    }
+
+    #[tokio::test]
+    async fn should_report_verification_status_in_trailers() {
+        let sha256: [u8; 32] = Sha256::digest(b"hello world").into();
+        let body_stream = stream::iter(vec![
+            Ok(StreamedChunk {
+                data: Bytes::from_static(b"hello"),
+                verified: true,
+            }),
+            Ok(StreamedChunk::unverified(Bytes::from_static(b" world"))),
+        ]);
+
+        let body: Vec<_> = finish_body_stream(body_stream, BodyIntegrity::default())
+            .collect()
+            .await;
+        let trailers = body
+            .into_iter()
+            .last()
+            .unwrap()
+            .unwrap()
+            .into_trailers()
+            .unwrap();
+
+        assert_eq!(
+            trailers[VERIFICATION_STATUS_TRAILER_NAME],
+            UNVERIFIED_STATUS
+        );
+        assert_eq!(trailers[CHUNK_COUNT_TRAILER_NAME], "2");
+        assert_eq!(trailers[VERIFIED_CHUNK_COUNT_TRAILER_NAME], "1");
+        assert_eq!(
+            trailers[BODY_DIGEST_TRAILER_NAME],
+            format!("sha-256=:{}:", BASE64.encode(sha256))
+        );
+    }
+
+    #[tokio::test]
+    async fn should_fail_body_with_wrong_length() {
+        let expected = BodyIntegrity {
+            length: Some(11),
+            sha256: None,
+        };
+
+        let truncated_body = collect_checked_body(&[b"hello", b" "], expected).await;
+        assert_eq!(truncated_body.len(), 3);
+        assert_matches!(&truncated_body[2], Err(e) if e.contains("length of 6 bytes does not match"));
+
+        let oversized_body = collect_checked_body(&[b"hello", b" world!", b"!"], expected).await;
+        assert_eq!(oversized_body.len(), 2);
+        assert_matches!(&oversized_body[1], Err(e) if e.contains("exceeds the advertised length"));
+    }
+
+    #[tokio::test]
+    async fn should_fail_body_with_wrong_digest() {
+        let expected = BodyIntegrity {
+            length: None,
+            sha256: Some(Sha256::digest(b"hello world").into()),
+        };
+
+        let body = collect_checked_body(&[b"hello", b" ", b"there"], expected).await;
+        assert_eq!(body.len(), 4);
+        assert_matches!(&body[3], Err(e) if e.contains("SHA-256 digest"));
+    }
+
+    #[test]
+    fn should_split_remaining_asset_into_chunk_ranges() {
+        assert_eq!(
+            get_chunk_ranges(3, 10).collect::<Vec<_>>(),
+            vec![(3, 5), (6, 8), (9, 9)]
+        );
+        assert_eq!(
+            get_chunk_ranges(5, 15).collect::<Vec<_>>(),
+            vec![(5, 9), (10, 14)]
+        );
+        assert_eq!(get_chunk_ranges(10, 10).count(), 0);
+    }
+
+    #[test]
+    fn should_reject_chunks_of_a_changed_asset() {
+        let state = StreamState {
+            http_request: some_file_request(),
+            canister_id: Principal::from_slice(&[1, 2, 3, 4]),
+            overrides: CallOverrides::default(),
+            http_request_method: "http_request".to_string(),
+            total_length: 10,
+            fetched_length: 3,
+            etag: Some("\"v1\"".to_string()),
+            sha256: None,
+            skip_verification: false,
+            response_verification_options: ResponseVerificationOptions::default(),
+        };
+        let range_values = |total_length| ContentRangeValues {
+            range_begin: 3,
+            range_end: 5,
+            total_length,
+        };
+
+        assert_matches!(
+            check_chunk_consistency(&state, 3, &range_values(10), Some("\"v1\"")),
+            Ok(())
+        );
+        assert_matches!(
+            check_chunk_consistency(&state, 3, &range_values(12), Some("\"v1\"")),
+            Err(e) if format!("{}", e).contains("total_length=12")
+        );
+        assert_matches!(
+            check_chunk_consistency(&state, 3, &range_values(10), Some("\"v2\"")),
+            Err(e) if format!("{}", e).contains("asset changed mid-stream")
+        );
+        assert_matches!(
+            check_chunk_consistency(&state, 3, &range_values(10), None),
+            Err(e) if format!("{}", e).contains("asset changed mid-stream")
+        );
+    }
+
     #[test]
     fn should_fail_get_initial_stream_state_without_content_range_header() {
-        let http_request = HttpRequest::get("http://example.com/some_file")
-            .with_headers(vec![("Xyz".to_string(), "some value".to_string())])
-            .with_body(vec![42])
-            .build();
+        let http_request = some_file_request();
         let canister_id = Principal::from_slice(&[1, 2, 3, 4]);
         let response_headers = vec![HeaderField(
             Cow::from("other header"),
             Cow::from("other value"),
         )];
-        let result = get_initial_stream_state(http_request, canister_id, &response_headers, false);
+        let result = get_initial_stream_state(
+            http_request,
+            canister_id,
+            CallOverrides::default(),
+            "http_request".to_string(),
+            &response_headers,
+            None,
+            false,
+            ResponseVerificationOptions::default(),
+        );
         assert_matches!(result, Err(e) if format!("{}", e).contains("missing Content-Range header"));
     }
 
     #[test]
     fn should_fail_get_initial_stream_state_with_malformed_content_range_header() {
-        let http_request = HttpRequest::get("http://example.com/some_file")
-            .with_headers(vec![("Xyz".to_string(), "some value".to_string())])
-            .with_body(vec![42])
-            .build();
+        let http_request = some_file_request();
         let canister_id = Principal::from_slice(&[1, 2, 3, 4]);
         let response_headers = vec![HeaderField(
             Cow::from("Content-Range"),
             Cow::from("bytes 42/10"),
         )];
-        let result = get_initial_stream_state(http_request, canister_id, &response_headers, false);
+        let result = get_initial_stream_state(
+            http_request,
+            canister_id,
+            CallOverrides::default(),
+            "http_request".to_string(),
+            &response_headers,
+            None,
+            false,
+            ResponseVerificationOptions::default(),
+        );
         assert_matches!(result, Err(e) if format!("{}", e).contains("Invalid bytes spec in Content-Range header"));
     }
 
     #[test]
     fn should_fail_get_initial_stream_state_with_inconsistent_content_range_header() {
-        let http_request = HttpRequest::get("http://example.com/some_file")
-            .with_headers(vec![("Xyz".to_string(), "some value".to_string())])
-            .with_body(vec![42])
-            .build();
+        let http_request = some_file_request();
         let canister_id = Principal::from_slice(&[1, 2, 3, 4]);
         let response_headers = vec![HeaderField(
             Cow::from("Content-Range"),
             Cow::from("bytes 40-100/90"),
         )];
-        let result = get_initial_stream_state(http_request, canister_id, &response_headers, false);
+        let result = get_initial_stream_state(
+            http_request,
+            canister_id,
+            CallOverrides::default(),
+            "http_request".to_string(),
+            &response_headers,
+            None,
+            false,
+            ResponseVerificationOptions::default(),
+        );
         assert_matches!(result, Err(e) if format!("{}", e).contains("inconsistent Content-Range header"));
     }
 }
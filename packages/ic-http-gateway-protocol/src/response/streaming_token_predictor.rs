@@ -0,0 +1,125 @@
+use candid::{
+    types::{
+        value::{IDLField, IDLValue},
+        Label,
+    },
+    Nat,
+};
+use ic_utils::interfaces::http_request::Token;
+use std::fmt::Debug;
+
+/// Predicts the tokens of upcoming chunks of a callback-strategy stream,
+/// so that several chunks can be fetched concurrently.
+pub trait StreamingTokenPredictor: Debug + Send + Sync {
+    /// Returns the token that the canister is expected to return along with the chunk for `token`,
+    /// or `None` if the shape of the token is unknown.
+    fn predict_next_token(&self, token: &Token) -> Option<Token>;
+}
+
+/// Predicts the tokens of asset canisters built with `ic-certified-assets`,
+/// which encode the index of the next chunk in a token of the shape
+/// `record { key : text; content_encoding : text; index : nat; sha256 : opt blob }`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CertifiedAssetsTokenPredictor;
+
+impl StreamingTokenPredictor for CertifiedAssetsTokenPredictor {
+    fn predict_next_token(&self, token: &Token) -> Option<Token> {
+        let IDLValue::Record(fields) = &token.0 else {
+            return None;
+        };
+
+        let has_field = |name: &str, is_expected_type: fn(&IDLValue) -> bool| {
+            fields.iter().any(|field| {
+                field.id == Label::Named(name.to_string()) && is_expected_type(&field.val)
+            })
+        };
+        let is_known_field = |field: &IDLField| {
+            ["key", "content_encoding", "index", "sha256"]
+                .iter()
+                .any(|name| field.id == Label::Named(name.to_string()))
+        };
+        if !has_field("key", |val| matches!(val, IDLValue::Text(_)))
+            || !has_field("content_encoding", |val| matches!(val, IDLValue::Text(_)))
+            || !has_field("index", |val| matches!(val, IDLValue::Nat(_)))
+            || !fields.iter().all(is_known_field)
+        {
+            return None;
+        }
+
+        let next_fields = fields
+            .iter()
+            .map(|field| match &field.val {
+                IDLValue::Nat(index) if field.id == Label::Named("index".to_string()) => IDLField {
+                    id: field.id.clone(),
+                    val: IDLValue::Nat(index.clone() + Nat::from(1u8)),
+                },
+                _ => field.clone(),
+            })
+            .collect();
+
+        Some(Token(IDLValue::Record(next_fields)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::idl_hash;
+
+    fn certified_assets_token(index: u64) -> Token {
+        // field names are hashed on the wire
+        Token(IDLValue::Record(vec![
+            IDLField {
+                id: Label::Id(idl_hash("key")),
+                val: IDLValue::Text("/index.js".to_string()),
+            },
+            IDLField {
+                id: Label::Id(idl_hash("content_encoding")),
+                val: IDLValue::Text("gzip".to_string()),
+            },
+            IDLField {
+                id: Label::Id(idl_hash("index")),
+                val: IDLValue::Nat(Nat::from(index)),
+            },
+            IDLField {
+                id: Label::Id(idl_hash("sha256")),
+                val: IDLValue::Opt(Box::new(IDLValue::Blob(vec![0; 32]))),
+            },
+        ]))
+    }
+
+    #[test]
+    fn should_predict_certified_assets_tokens() {
+        let predicted_token = CertifiedAssetsTokenPredictor
+            .predict_next_token(&certified_assets_token(1))
+            .unwrap();
+
+        assert_eq!(predicted_token.0, certified_assets_token(2).0);
+    }
+
+    #[test]
+    fn should_not_predict_unknown_tokens() {
+        let IDLValue::Record(mut fields_with_unknown_field) = certified_assets_token(1).0 else {
+            unreachable!()
+        };
+        fields_with_unknown_field.push(IDLField {
+            id: Label::Named("cursor".to_string()),
+            val: IDLValue::Text("abc".to_string()),
+        });
+
+        let unknown_tokens = [
+            Token(IDLValue::Nat(Nat::from(1u8))),
+            Token(IDLValue::Record(vec![IDLField {
+                id: Label::Named("index".to_string()),
+                val: IDLValue::Nat(Nat::from(1u8)),
+            }])),
+            Token(IDLValue::Record(fields_with_unknown_field)),
+        ];
+
+        for token in unknown_tokens {
+            assert!(CertifiedAssetsTokenPredictor
+                .predict_next_token(&token)
+                .is_none());
+        }
+    }
+}
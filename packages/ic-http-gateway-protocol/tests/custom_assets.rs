@@ -1,9 +1,8 @@
 use bytes::Bytes;
 use http::Request;
 use http_body_util::BodyExt;
-use ic_agent::Agent;
 use ic_http_gateway_protocol::{
-    HttpGatewayClient, HttpGatewayRequestArgs, HttpGatewayResponseMetadata,
+    HttpGatewayClient, HttpGatewayRequestArgs, HttpGatewayResponseMetadata, RootKey,
 };
 use pocket_ic::PocketIcBuilder;
 
@@ -25,16 +24,15 @@ fn test_custom_assets_index_html() {
 
     let url = pic.auto_progress();
 
-    let agent = Agent::builder().with_url(url).build().unwrap();
-    rt.block_on(async {
-        agent.fetch_root_key().await.unwrap();
+    let http_gateway = rt.block_on(async {
+        HttpGatewayClient::builder()
+            .with_url(url)
+            .with_root_key(RootKey::FetchForLocalDev)
+            .build_and_check_root_key()
+            .await
+            .unwrap()
     });
 
-    let http_gateway = HttpGatewayClient::builder()
-        .with_agent(agent)
-        .build()
-        .unwrap();
-
     let response = rt.block_on(async {
         http_gateway
             .request(HttpGatewayRequestArgs {
@@ -106,13 +104,82 @@ fn test_custom_assets_index_html() {
     assert_response_metadata(
         response.metadata,
         HttpGatewayResponseMetadata {
-            upgraded_to_update_call: false,
             response_verification_version: Some(2),
-            internal_error: None,
+            ..Default::default()
         },
     );
 }
 
+#[test]
+fn test_custom_assets_without_certificate_headers() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let wasm_bytes = rt.block_on(async { utils::load_custom_assets_wasm().await });
+
+    let pic = PocketIcBuilder::new()
+        .with_nns_subnet()
+        .with_application_subnet()
+        .build();
+
+    let canister_id = pic.create_canister();
+    pic.add_cycles(canister_id, 2_000_000_000_000_000);
+    pic.install_canister(canister_id, wasm_bytes, vec![], None);
+
+    let url = pic.auto_progress();
+
+    let http_gateway = rt.block_on(async {
+        HttpGatewayClient::builder()
+            .with_url(url)
+            .with_root_key(RootKey::FetchForLocalDev)
+            .with_strip_certificate_headers(true)
+            .build_and_check_root_key()
+            .await
+            .unwrap()
+    });
+
+    let response = rt.block_on(async {
+        http_gateway
+            .request(HttpGatewayRequestArgs {
+                canister_id,
+                canister_request: Request::builder().uri("/").body(Bytes::new()).unwrap(),
+            })
+            .send()
+            .await
+    });
+
+    assert_eq!(response.canister_response.status(), 200);
+    let response_headers = response
+        .canister_response
+        .headers()
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.to_str().unwrap()))
+        .collect::<Vec<(&str, &str)>>();
+    assert!(!contains_header("ic-certificate", response_headers.clone()));
+    assert!(!contains_header(
+        "ic-certificateexpression",
+        response_headers.clone()
+    ));
+    assert!(contains_header("content-type", response_headers));
+
+    // the certificate headers are still available in decoded form
+    let certificate = response.metadata.certificate.unwrap();
+    assert!(certificate.time.is_some());
+    assert!(certificate.expr_path.is_some());
+    assert!(certificate
+        .certificate_expression
+        .is_some_and(|expression| expression.starts_with("default_certification")));
+    let verification_report = response.metadata.verification_report.unwrap();
+    assert_eq!(verification_report.version, 2);
+    assert!(verification_report.certified_response.is_some());
+    assert!(verification_report
+        .stripped_headers
+        .iter()
+        .any(|name| name.eq_ignore_ascii_case("ic-certificate")));
+    assert!(!verification_report
+        .dropped_headers
+        .iter()
+        .any(|name| name.eq_ignore_ascii_case("ic-certificate")));
+}
+
 fn assert_response_metadata(
     response_metadata: HttpGatewayResponseMetadata,
     expected_response_metadata: HttpGatewayResponseMetadata,
@@ -0,0 +1,128 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bytes::Bytes;
+use http::{header, Request, StatusCode};
+use http_body_util::BodyExt;
+use ic_agent::{
+    identity::{BasicIdentity, Delegation},
+    Identity,
+};
+use ic_http_gateway_protocol::{
+    DelegationAuthenticator, HttpGatewayClient, HttpGatewayError, HttpGatewayRequestArgs, RootKey,
+};
+use pocket_ic::PocketIcBuilder;
+use serde_json::json;
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+mod utils;
+
+fn create_delegation_chain(user: &BasicIdentity, session_public_key: Vec<u8>) -> String {
+    let expiration =
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap() + Duration::from_secs(300);
+    let delegation = Delegation {
+        pubkey: session_public_key,
+        expiration: expiration.as_nanos() as u64,
+        targets: None,
+    };
+    let signature = user.sign_delegation(&delegation).unwrap();
+    let chain = json!({
+        "delegations": [{
+            "delegation": {
+                "pubkey": hex::encode(&delegation.pubkey),
+                "expiration": format!("{:x}", delegation.expiration),
+            },
+            "signature": hex::encode(signature.signature.unwrap()),
+        }],
+        "publicKey": hex::encode(user.public_key().unwrap()),
+    });
+
+    URL_SAFE_NO_PAD.encode(chain.to_string())
+}
+
+#[test]
+fn test_update_calls_on_behalf_of_delegating_users() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let wasm_bytes = rt.block_on(async { utils::load_custom_assets_wasm().await });
+
+    let pic = PocketIcBuilder::new()
+        .with_nns_subnet()
+        .with_application_subnet()
+        .build();
+
+    let canister_id = pic.create_canister();
+    pic.add_cycles(canister_id, 2_000_000_000_000_000);
+    pic.install_canister(canister_id, wasm_bytes, vec![], None);
+
+    let url = pic.auto_progress();
+
+    let user = BasicIdentity::from_raw_key(&[7; 32]);
+    let delegation_authenticator = Arc::new(DelegationAuthenticator::new(Arc::new(
+        BasicIdentity::from_raw_key(&[8; 32]),
+    )));
+    let delegation_chain = create_delegation_chain(
+        &user,
+        delegation_authenticator.session_public_key().unwrap(),
+    );
+
+    let http_gateway = rt.block_on(async {
+        HttpGatewayClient::builder()
+            .with_url(url)
+            .with_root_key(RootKey::FetchForLocalDev)
+            .with_delegation_authenticator(delegation_authenticator)
+            .build_and_check_root_key()
+            .await
+            .unwrap()
+    });
+
+    let whoami = |authorization: Option<String>| {
+        let mut request = Request::builder().uri("/whoami");
+        if let Some(authorization) = authorization {
+            request = request.header(header::AUTHORIZATION, authorization);
+        }
+
+        rt.block_on(async {
+            let response = http_gateway
+                .request(HttpGatewayRequestArgs {
+                    canister_id,
+                    canister_request: request.body(Bytes::new()).unwrap(),
+                })
+                .send()
+                .await;
+            let status = response.canister_response.status();
+            let body = response
+                .canister_response
+                .into_body()
+                .collect()
+                .await
+                .unwrap()
+                .to_bytes();
+
+            (status, body, response.metadata)
+        })
+    };
+
+    let (status, body, metadata) = whoami(Some(format!("Delegation {delegation_chain}")));
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, user.sender().unwrap().to_text());
+    assert!(metadata.upgraded_to_update_call);
+    assert_eq!(metadata.delegated_principal, Some(user.sender().unwrap()));
+
+    // requests without a delegation chain are sent with the anonymous identity of the agent
+    let (status, body, metadata) = whoami(None);
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, ic_agent::export::Principal::anonymous().to_text());
+    assert_eq!(metadata.delegated_principal, None);
+
+    let (status, _, metadata) = whoami(Some("Delegation invalid".to_string()));
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(matches!(
+        metadata.internal_error,
+        Some(HttpGatewayError::InvalidDelegation { .. })
+    ));
+
+    let metrics = http_gateway.metrics();
+    assert_eq!(metrics.delegated_requests, 1);
+    assert_eq!(metrics.rejected_delegations, 1);
+}
@@ -0,0 +1,73 @@
+use bytes::Bytes;
+use http::{Request, StatusCode};
+use ic_http_gateway_protocol::{
+    HttpGatewayClient, HttpGatewayError, HttpGatewayRequestArgs, HttpInterface, RootKey,
+};
+use pocket_ic::PocketIcBuilder;
+
+mod utils;
+
+#[test]
+fn test_http_interface_discovery() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let wasm_bytes = rt.block_on(async { utils::load_custom_assets_wasm().await });
+
+    let pic = PocketIcBuilder::new()
+        .with_nns_subnet()
+        .with_application_subnet()
+        .build();
+
+    let canister_id = pic.create_canister();
+    pic.add_cycles(canister_id, 2_000_000_000_000_000);
+    pic.install_canister(canister_id, wasm_bytes.clone(), vec![], None);
+
+    let misconfigured_canister_id = pic.create_canister();
+    pic.add_cycles(misconfigured_canister_id, 2_000_000_000_000_000);
+    pic.install_canister(misconfigured_canister_id, wasm_bytes, vec![], None);
+
+    let url = pic.auto_progress();
+
+    let http_gateway = rt.block_on(async {
+        HttpGatewayClient::builder()
+            .with_url(url)
+            .with_root_key(RootKey::FetchForLocalDev)
+            .with_http_interface_discovery(true)
+            .with_canister_http_interface(
+                misconfigured_canister_id,
+                HttpInterface::new("http_request_v2", None),
+            )
+            .build_and_check_root_key()
+            .await
+            .unwrap()
+    });
+
+    let send = |canister_id, path| {
+        rt.block_on(async {
+            http_gateway
+                .request(HttpGatewayRequestArgs {
+                    canister_id,
+                    canister_request: Request::builder().uri(path).body(Bytes::new()).unwrap(),
+                })
+                .send()
+                .await
+        })
+    };
+
+    // the query and update methods are discovered from the candid interface of the canister
+    let response = send(canister_id, "/");
+    assert_eq!(response.canister_response.status(), StatusCode::OK);
+    assert!(response.metadata.internal_error.is_none());
+
+    let response = send(canister_id, "/whoami");
+    assert_eq!(response.canister_response.status(), StatusCode::OK);
+    assert!(response.metadata.upgraded_to_update_call);
+
+    // configured methods are validated against the candid interface of the canister
+    let response = send(misconfigured_canister_id, "/");
+    assert_eq!(response.canister_response.status(), StatusCode::BAD_GATEWAY);
+    assert!(matches!(
+        response.metadata.internal_error,
+        Some(HttpGatewayError::CanisterMethodNotDeclared { method, query: true, .. })
+            if method == "http_request_v2"
+    ));
+}
@@ -0,0 +1,237 @@
+use bytes::Bytes;
+use http::{Request, StatusCode};
+use http_body_util::BodyExt;
+use ic_agent::{
+    agent::HttpService, export::Principal, identity::BasicIdentity, Agent, AgentError, Identity,
+};
+use ic_http_gateway_protocol::{
+    HttpGatewayClient, HttpGatewayRequestArgs, HttpGatewayRequestBuilder, RootKey,
+};
+use pocket_ic::PocketIcBuilder;
+use serde_cbor::Value;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+mod utils;
+
+const SIX_CHUNKS_ASSET_NAME: &str = "long_asset_six_chunks";
+const SIX_CHUNKS_ASSET_LEN: usize = 5 * 2_000_000 + 12;
+
+/// Forwards the requests of an agent to the replica and records the query calls.
+#[derive(Debug, Default)]
+struct QueryRecorder {
+    client: reqwest::Client,
+    /// The path and ingress expiry of every query call.
+    queries: Mutex<Vec<(String, Duration)>>,
+}
+
+impl QueryRecorder {
+    fn get_ingress_expiry(envelope: &[u8]) -> Duration {
+        let Ok(Value::Map(envelope)) = serde_cbor::from_slice(envelope) else {
+            panic!("malformed query envelope");
+        };
+        let Some(Value::Map(content)) = envelope.get(&Value::Text("content".to_string())) else {
+            panic!("query envelope without content");
+        };
+        let Some(Value::Integer(ingress_expiry)) =
+            content.get(&Value::Text("ingress_expiry".to_string()))
+        else {
+            panic!("query without ingress expiry");
+        };
+
+        Duration::from_nanos(u64::try_from(*ingress_expiry).unwrap())
+    }
+}
+
+#[async_trait::async_trait]
+impl HttpService for QueryRecorder {
+    async fn call<'a>(
+        &'a self,
+        request: &'a (dyn Fn() -> Result<http::Request<Bytes>, AgentError> + Send + Sync),
+        max_retries: usize,
+        size_limit: Option<usize>,
+    ) -> Result<http::Response<Bytes>, AgentError> {
+        let recorded_request = request()?;
+        if recorded_request.uri().path().ends_with("/query") {
+            self.queries.lock().unwrap().push((
+                recorded_request.uri().path().to_string(),
+                Self::get_ingress_expiry(recorded_request.body()),
+            ));
+        }
+
+        HttpService::call(&self.client, request, max_retries, size_limit).await
+    }
+}
+
+#[test]
+fn test_per_request_identity_overrides() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let wasm_bytes = rt.block_on(async { utils::load_custom_assets_wasm().await });
+
+    let pic = PocketIcBuilder::new()
+        .with_nns_subnet()
+        .with_application_subnet()
+        .build();
+
+    let canister_id = pic.create_canister();
+    pic.add_cycles(canister_id, 2_000_000_000_000_000);
+    pic.install_canister(canister_id, wasm_bytes, vec![], None);
+
+    let url = pic.auto_progress();
+
+    let http_gateway = rt.block_on(async {
+        HttpGatewayClient::builder()
+            .with_url(url.clone())
+            .with_root_key(RootKey::FetchForLocalDev)
+            .build_and_check_root_key()
+            .await
+            .unwrap()
+    });
+
+    let first_tenant: Arc<dyn Identity> = Arc::new(BasicIdentity::from_raw_key(&[1; 32]));
+    let second_tenant: Arc<dyn Identity> = Arc::new(BasicIdentity::from_raw_key(&[2; 32]));
+    let backend_service = BasicIdentity::from_raw_key(&[3; 32]);
+    let backend_service_principal = backend_service.sender().unwrap();
+    let backend_service_agent = rt.block_on(async {
+        let agent = Agent::builder()
+            .with_url(url)
+            .with_identity(backend_service)
+            .build()
+            .unwrap();
+        agent.fetch_root_key().await.unwrap();
+
+        agent
+    });
+
+    let whoami = |configure: &dyn Fn(&mut HttpGatewayRequestBuilder)| {
+        rt.block_on(async {
+            let mut request = http_gateway.request(HttpGatewayRequestArgs {
+                canister_id,
+                canister_request: Request::builder()
+                    .uri("/whoami")
+                    .body(Bytes::new())
+                    .unwrap(),
+            });
+            configure(&mut request);
+            let response = request.send().await;
+            assert_eq!(response.canister_response.status(), StatusCode::OK);
+            assert!(response.metadata.upgraded_to_update_call);
+
+            let body = response
+                .canister_response
+                .into_body()
+                .collect()
+                .await
+                .unwrap()
+                .to_bytes();
+            Principal::from_text(String::from_utf8(body.to_vec()).unwrap()).unwrap()
+        })
+    };
+
+    assert_eq!(whoami(&|_| {}), Principal::anonymous());
+    assert_eq!(
+        whoami(&|request| {
+            request.set_identity(first_tenant.clone());
+        }),
+        first_tenant.sender().unwrap()
+    );
+    assert_eq!(
+        whoami(&|request| {
+            request
+                .set_identity(second_tenant.clone())
+                .set_ingress_expiry(Duration::from_secs(60))
+                .set_effective_canister_id(canister_id);
+        }),
+        second_tenant.sender().unwrap()
+    );
+    assert_eq!(
+        whoami(&|request| {
+            request.set_agent(backend_service_agent.clone());
+        }),
+        backend_service_principal
+    );
+
+    // the identity of the client's agent is not changed by the overrides
+    assert_eq!(whoami(&|_| {}), Principal::anonymous());
+}
+
+#[test]
+fn test_per_request_overrides_apply_to_all_chunks() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let wasm_bytes = rt.block_on(async { utils::load_custom_assets_wasm().await });
+
+    let pic = PocketIcBuilder::new()
+        .with_nns_subnet()
+        .with_application_subnet()
+        .build();
+
+    let canister_id = pic.create_canister();
+    pic.add_cycles(canister_id, 2_000_000_000_000_000);
+    pic.install_canister(canister_id, wasm_bytes, vec![], None);
+
+    let url = pic.auto_progress();
+
+    let http_gateway = rt.block_on(async {
+        HttpGatewayClient::builder()
+            .with_url(url.clone())
+            .with_root_key(RootKey::FetchForLocalDev)
+            .build_and_check_root_key()
+            .await
+            .unwrap()
+    });
+
+    let query_recorder = Arc::new(QueryRecorder::default());
+    let recording_agent = rt.block_on(async {
+        let agent = Agent::builder()
+            .with_url(url)
+            .with_arc_http_middleware(query_recorder.clone())
+            .build()
+            .unwrap();
+        agent.fetch_root_key().await.unwrap();
+
+        agent
+    });
+
+    // the agent's default ingress expiry is 3 minutes
+    let ingress_expiry = Duration::from_secs(30);
+    let body = rt.block_on(async {
+        let mut request = http_gateway.request(HttpGatewayRequestArgs {
+            canister_id,
+            canister_request: Request::builder()
+                .uri(format!("/{SIX_CHUNKS_ASSET_NAME}"))
+                .body(Bytes::new())
+                .unwrap(),
+        });
+        request
+            .set_agent(recording_agent)
+            .set_ingress_expiry(ingress_expiry)
+            .set_effective_canister_id(canister_id);
+        let response = request.send().await;
+        assert_eq!(response.canister_response.status(), StatusCode::OK);
+
+        response
+            .canister_response
+            .into_body()
+            .collect()
+            .await
+            .unwrap()
+            .to_bytes()
+    });
+    let latest_ingress_expiry =
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap() + ingress_expiry;
+
+    assert_eq!(body.len(), SIX_CHUNKS_ASSET_LEN);
+
+    // the first chunk and the five remaining chunks of the asset
+    let queries = query_recorder.queries.lock().unwrap();
+    assert!(queries.len() >= 6, "only {} query calls", queries.len());
+    for (path, query_ingress_expiry) in queries.iter() {
+        assert!(path.contains(&canister_id.to_text()), "{path}");
+        assert!(
+            *query_ingress_expiry <= latest_ingress_expiry,
+            "{path} expires after {query_ingress_expiry:?}"
+        );
+    }
+}
@@ -3,9 +3,8 @@ use bytes::Bytes;
 use http::Request;
 use http_body_util::BodyExt;
 use ic_agent::hash_tree::Hash;
-use ic_agent::Agent;
 use ic_http_gateway_protocol::{
-    HttpGatewayClient, HttpGatewayRequestArgs, HttpGatewayResponseMetadata,
+    HttpGatewayClient, HttpGatewayRequestArgs, HttpGatewayResponseMetadata, RootKey,
 };
 use pocket_ic::PocketIcBuilder;
 use rand_chacha::rand_core::{RngCore, SeedableRng};
@@ -70,16 +69,15 @@ fn test_long_asset_request_yields_entire_asset(#[case] asset_name: &str) {
 
     let url = pic.auto_progress();
 
-    let agent = Agent::builder().with_url(url).build().unwrap();
-    rt.block_on(async {
-        agent.fetch_root_key().await.unwrap();
+    let http_gateway = rt.block_on(async {
+        HttpGatewayClient::builder()
+            .with_url(url)
+            .with_root_key(RootKey::FetchForLocalDev)
+            .build_and_check_root_key()
+            .await
+            .unwrap()
     });
 
-    let http_gateway = HttpGatewayClient::builder()
-        .with_agent(agent)
-        .build()
-        .unwrap();
-
     let response = rt.block_on(async {
         http_gateway
             .request(HttpGatewayRequestArgs {
@@ -155,9 +153,8 @@ fn test_long_asset_request_yields_entire_asset(#[case] asset_name: &str) {
     assert_response_metadata(
         response.metadata,
         HttpGatewayResponseMetadata {
-            upgraded_to_update_call: false,
             response_verification_version: Some(2),
-            internal_error: None,
+            ..Default::default()
         },
     );
 }
@@ -185,16 +182,15 @@ fn test_corrupted_long_asset_request_fails(
 
     let url = pic.auto_progress();
 
-    let agent = Agent::builder().with_url(url).build().unwrap();
-    rt.block_on(async {
-        agent.fetch_root_key().await.unwrap();
+    let http_gateway = rt.block_on(async {
+        HttpGatewayClient::builder()
+            .with_url(url)
+            .with_root_key(RootKey::FetchForLocalDev)
+            .build_and_check_root_key()
+            .await
+            .unwrap()
     });
 
-    let http_gateway = HttpGatewayClient::builder()
-        .with_agent(agent)
-        .build()
-        .unwrap();
-
     let response = rt.block_on(async {
         http_gateway
             .request(HttpGatewayRequestArgs {
@@ -260,16 +256,15 @@ fn test_long_asset_with_chunks_out_of_order_fails(
 
     let url = pic.auto_progress();
 
-    let agent = Agent::builder().with_url(url).build().unwrap();
-    rt.block_on(async {
-        agent.fetch_root_key().await.unwrap();
+    let http_gateway = rt.block_on(async {
+        HttpGatewayClient::builder()
+            .with_url(url)
+            .with_root_key(RootKey::FetchForLocalDev)
+            .build_and_check_root_key()
+            .await
+            .unwrap()
     });
 
-    let http_gateway = HttpGatewayClient::builder()
-        .with_agent(agent)
-        .build()
-        .unwrap();
-
     let response = rt.block_on(async {
         http_gateway
             .request(HttpGatewayRequestArgs {
@@ -336,16 +331,15 @@ fn test_corrupted_chunk_certificate_for_long_asset_request_fails(
 
     let url = pic.auto_progress();
 
-    let agent = Agent::builder().with_url(url).build().unwrap();
-    rt.block_on(async {
-        agent.fetch_root_key().await.unwrap();
+    let http_gateway = rt.block_on(async {
+        HttpGatewayClient::builder()
+            .with_url(url)
+            .with_root_key(RootKey::FetchForLocalDev)
+            .build_and_check_root_key()
+            .await
+            .unwrap()
     });
 
-    let http_gateway = HttpGatewayClient::builder()
-        .with_agent(agent)
-        .build()
-        .unwrap();
-
     let response = rt.block_on(async {
         http_gateway
             .request(HttpGatewayRequestArgs {
@@ -404,16 +398,15 @@ fn test_range_request_yields_range_response(#[case] asset_name: &str) {
 
     let url = pic.auto_progress();
 
-    let agent = Agent::builder().with_url(url).build().unwrap();
-    rt.block_on(async {
-        agent.fetch_root_key().await.unwrap();
+    let http_gateway = rt.block_on(async {
+        HttpGatewayClient::builder()
+            .with_url(url)
+            .with_root_key(RootKey::FetchForLocalDev)
+            .build_and_check_root_key()
+            .await
+            .unwrap()
     });
 
-    let http_gateway = HttpGatewayClient::builder()
-        .with_agent(agent)
-        .build()
-        .unwrap();
-
     let response = rt.block_on(async {
         http_gateway
             .request(HttpGatewayRequestArgs {
@@ -497,9 +490,8 @@ fn test_range_request_yields_range_response(#[case] asset_name: &str) {
     assert_response_metadata(
         response.metadata,
         HttpGatewayResponseMetadata {
-            upgraded_to_update_call: false,
             response_verification_version: Some(2),
-            internal_error: None,
+            ..Default::default()
         },
     );
 }